@@ -7,20 +7,28 @@
 //! - `target/<target>/release/.fingerprint/<crate>-<hash>/...`
 //! - `target/<target>/release/deps/<crate>-<hash>.{rlib,rmeta,so,d}`
 //! - `target/<target>/release/build/<pkg>-<hash>/...`  (build script run + COMPILE)
-//! - `target/<target>/release/<bin_name>` — *no hash*, link to the bin.
 //!
 //! We use the per-unit directory paths cargo's `CompilationFiles` exposes
 //! (`fingerprint_dir`, `build_script_run_dir`, `build_script_dir`) plus a
-//! deps-dir filter on `c_extra_filename`. The naked binary in the build dir
-//! root is attributed to the bin unit by name (cargo's `Compilation::binaries`
-//! / `cdylibs` give the post-build paths if needed).
+//! deps-dir filter on `c_extra_filename`.
+//!
+//! The unhashed copies cargo uplifts into the target root
+//! (`target/release/<bin_name>`, cdylibs, `.dwp`) are not part of a bundle:
+//! whether a unit is uplifted depends on which units the invocation asked
+//! for, not on the unit itself. [`uplift_unit`] recreates them after a
+//! restore from the paths cargo's `CompilationFiles::outputs` plans, along
+//! with any `--artifact-dir` copies.
 
+use std::collections::HashSet;
 use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{Scope, ScopedJoinHandle};
 
 use anyhow::{Context, Result};
 use cargo::core::compiler::{BuildRunner, CompileMode, Unit};
 
 use crate::cache::CacheBackend;
+use crate::remap::{self, PathRoots};
 
 /// Files belonging to one unit that we want to cache + restore.
 #[derive(Debug, Default, Clone)]
@@ -90,13 +98,66 @@ fn walk_dir_files(dir: &Path, out: &mut Vec<PathBuf>) {
     }
 }
 
+/// Put a restored unit's outputs where cargo would after building it, as
+/// cargo's `link_targets` does for a fresh unit: each uplifted or exported
+/// path becomes a hard link to (or, failing that, a copy of) the file in
+/// `deps/`, replacing whatever stale file was there.
+pub fn uplift_unit(runner: &BuildRunner<'_, '_>, unit: &Unit) -> Result<usize> {
+    let mut linked = 0;
+    for output in runner.outputs(unit)?.iter() {
+        let Some(dst) = &output.hardlink else { continue };
+        if !output.path.exists() {
+            continue;
+        }
+        cargo_util::paths::link_or_copy(&output.path, dst)?;
+        linked += 1;
+        if let Some(export) = &output.export_path {
+            if let Some(dir) = export.parent() {
+                cargo_util::paths::create_dir_all(dir)?;
+            }
+            cargo_util::paths::link_or_copy(&output.path, export)?;
+        }
+    }
+    Ok(linked)
+}
+
+/// Replace every file under `target_dir` that is a `--hardlink` restore with
+/// a writable copy, before cargo runs over it. cargo would otherwise see the
+/// blob's old mtime as a stale output and rewrite `.fingerprint/` files in
+/// place, through the link into the cache. Those are the read-only files:
+/// the fs cache stores blobs without write bits, and cargo writes none.
+pub fn unshare_restored(target_dir: &Path) -> Result<usize> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut copied = 0;
+    for entry in walkdir::WalkDir::new(target_dir).into_iter().filter_map(|e| e.ok()) {
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let path = entry.path();
+        let mode = entry.metadata()?.permissions().mode();
+        if mode & 0o222 != 0 {
+            continue;
+        }
+        let dir = path.parent().expect("file under the target dir");
+        let tmp = tempfile::Builder::new().prefix(".tmp").tempfile_in(dir)?.into_temp_path();
+        std::fs::copy(path, &tmp).with_context(|| format!("copying {}", path.display()))?;
+        std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(mode | 0o200))?;
+        tmp.persist(path).with_context(|| format!("replacing {}", path.display()))?;
+        copied += 1;
+    }
+    Ok(copied)
+}
+
 /// Store a unit's artifacts under `unit_key`. Each file's path is stored as
-/// relative to `target_dir` (so restore can reconstruct under any target dir).
+/// relative to `target_dir` (so restore can reconstruct under any target dir),
+/// and path-bearing files are stored normalized against `roots`.
 pub fn store_unit(
     cache: &dyn CacheBackend,
     unit_key: &[u8; 32],
     artifacts: &UnitArtifacts,
     target_dir: &Path,
+    roots: &PathRoots,
 ) -> Result<usize> {
     let mut manifest: Vec<String> = Vec::new();
     for path in &artifacts.files {
@@ -106,41 +167,306 @@ pub fn store_unit(
         }
         let rel = path.strip_prefix(target_dir).unwrap_or(path);
         let rel_str = rel.to_string_lossy().to_string();
-        cache
-            .store_artifact_from_file(unit_key, &rel_str, path)
-            .with_context(|| format!("storing {}", path.display()))?;
+        let text = remap::is_path_bearing(&rel_str)
+            .then(|| std::fs::read_to_string(path).ok())
+            .flatten();
+        match text {
+            Some(text) => cache.put_artifact(unit_key, &rel_str, roots.normalize_str(&text).as_bytes()),
+            None => cache.store_artifact_from_file(unit_key, &rel_str, path),
+        }
+        .with_context(|| format!("storing {}", path.display()))?;
         manifest.push(rel_str);
     }
     cache.finalize_unit(unit_key, &manifest)?;
     Ok(manifest.len())
 }
 
-/// Restore a unit's artifacts from cache into `target_dir`.
-pub fn restore_unit(
-    cache: &dyn CacheBackend,
-    unit_key: &[u8; 32],
-    target_dir: &Path,
-) -> Result<usize> {
-    let manifest = cache.list_artifacts(unit_key)?;
-    if manifest.is_empty() {
-        return Ok(0);
+/// Files per restore job. Large units fan out across workers; a batch is
+/// also the unit of submission for backends with batched I/O (io_uring).
+const RESTORE_BATCH: usize = 64;
+
+struct RestoreJob {
+    id: usize,
+    unit_key: [u8; 32],
+    files: Vec<(String, PathBuf)>,
+}
+
+struct JobDone {
+    id: usize,
+    restored: usize,
+    complete: bool,
+}
+
+/// Outcome of a [`Restorer`] run.
+#[derive(Debug, Default)]
+pub struct RestoreReport {
+    /// Ids (from [`Restorer::submit`]) whose bundle vanished mid-restore — a
+    /// concurrent `cargo zb gc` evicted it. The caller treats these as misses.
+    pub failed: Vec<usize>,
+    pub files: usize,
+}
+
+/// Restores unit bundles on a pool of `--io-threads` workers.
+///
+/// `submit` runs on the lookup thread: it reads the unit's manifest, creates
+/// the destination dirs and queues the copies, so the next unit's lookup
+/// overlaps with this unit's `copy_file_range` work. Workers rewrite restored
+/// path-bearing files for `roots`. `finish` joins the pool and records an
+/// access time for every fully restored unit.
+pub struct Restorer<'scope> {
+    cache: &'scope dyn CacheBackend,
+    target_dir: &'scope Path,
+    tx: Option<mpsc::Sender<RestoreJob>>,
+    workers: Vec<ScopedJoinHandle<'scope, Result<Vec<JobDone>>>>,
+    units: Vec<[u8; 32]>,
+    dirs_seen: HashSet<PathBuf>,
+}
+
+impl<'scope> Restorer<'scope> {
+    pub fn start<'env>(
+        scope: &'scope Scope<'scope, 'env>,
+        cache: &'scope dyn CacheBackend,
+        target_dir: &'scope Path,
+        roots: &'scope PathRoots,
+        io_threads: usize,
+    ) -> Self {
+        let (tx, rx) = mpsc::channel::<RestoreJob>();
+        let rx = Arc::new(Mutex::new(rx));
+        let workers = (0..io_threads.max(1))
+            .map(|_| {
+                let rx = Arc::clone(&rx);
+                scope.spawn(move || {
+                    let mut done = Vec::new();
+                    loop {
+                        let job = match rx.lock().unwrap().recv() {
+                            Ok(job) => job,
+                            Err(_) => break, // sender dropped: no more work
+                        };
+                        let found = cache.restore_artifacts(&job.unit_key, &job.files)?;
+                        for ((rel, dest), ok) in job.files.iter().zip(&found) {
+                            if !ok {
+                                tracing::warn!("missing cached file: {}", rel);
+                            } else if remap::is_path_bearing(rel) {
+                                roots.resolve_file(dest)?;
+                            }
+                        }
+                        let restored = found.iter().filter(|f| **f).count();
+                        done.push(JobDone {
+                            id: job.id,
+                            restored,
+                            complete: restored == job.files.len(),
+                        });
+                    }
+                    Ok(done)
+                })
+            })
+            .collect();
+        Self {
+            cache,
+            target_dir,
+            tx: Some(tx),
+            workers,
+            units: Vec::new(),
+            dirs_seen: HashSet::new(),
+        }
     }
-    let mut dirs_seen = std::collections::HashSet::new();
-    for rel_str in &manifest {
-        let dest = target_dir.join(rel_str);
-        if let Some(parent) = dest.parent() {
-            if dirs_seen.insert(parent.to_path_buf()) {
+
+    /// Queue a unit bundle for restore. Returns its id, or `None` if there is
+    /// no such bundle (never stored, or evicted since the lookup).
+    pub fn submit(&mut self, unit_key: &[u8; 32]) -> Result<Option<usize>> {
+        let manifest = self.cache.list_artifacts(unit_key)?;
+        if manifest.is_empty() {
+            return Ok(None);
+        }
+        let id = self.units.len();
+        self.units.push(*unit_key);
+
+        let mut files = Vec::with_capacity(manifest.len());
+        for rel_str in manifest {
+            let dest = self.target_dir.join(&rel_str);
+            if let Some(parent) = dest.parent()
+                && self.dirs_seen.insert(parent.to_path_buf())
+            {
                 std::fs::create_dir_all(parent)?;
             }
+            files.push((rel_str, dest));
         }
+        let tx = self.tx.as_ref().expect("submit after finish");
+        let mut files = files.into_iter().peekable();
+        while files.peek().is_some() {
+            let chunk: Vec<_> = files.by_ref().take(RESTORE_BATCH).collect();
+            // A send only fails if every worker already died on an error;
+            // `finish` surfaces that error.
+            let _ = tx.send(RestoreJob { id, unit_key: *unit_key, files: chunk });
+        }
+        Ok(Some(id))
     }
-    let mut restored = 0;
-    for rel_str in &manifest {
-        let dest = target_dir.join(rel_str);
-        match cache.restore_artifact(unit_key, rel_str, &dest)? {
-            true => restored += 1,
-            false => tracing::warn!("missing cached file: {}", rel_str),
+
+    /// Wait for all queued restores.
+    pub fn finish(mut self) -> Result<RestoreReport> {
+        drop(self.tx.take());
+        let mut complete = vec![true; self.units.len()];
+        let mut report = RestoreReport::default();
+        for worker in self.workers.drain(..) {
+            let done = worker
+                .join()
+                .map_err(|_| anyhow::anyhow!("restore worker panicked"))??;
+            for d in done {
+                report.files += d.restored;
+                complete[d.id] &= d.complete;
+            }
+        }
+        let mut restored = Vec::with_capacity(self.units.len());
+        for (id, ok) in complete.into_iter().enumerate() {
+            if ok {
+                restored.push(self.units[id]);
+            } else {
+                report.failed.push(id);
+            }
         }
+        self.cache.touch_units(&restored)?;
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cargo::GlobalContext;
+    use cargo::core::Workspace;
+    use cargo::core::compiler::{UnitInterner, UserIntent};
+    use cargo::core::shell::Shell;
+    use cargo::ops::CompileOptions;
+
+    use super::*;
+
+    fn write(path: &Path, contents: &str) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn uplift_recreates_what_cargo_links_and_exports() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        write(&root.join("Cargo.toml"), "[package]\nname = \"up\"\nversion = \"0.1.0\"\nedition = \"2021\"\n");
+        write(&root.join("src/main.rs"), "fn main() {}\n");
+        let gctx = GlobalContext::new(
+            Shell::from_write(Box::new(std::io::sink())),
+            root.into(),
+            root.join("home"),
+        );
+        let ws = Workspace::new(&root.join("Cargo.toml"), &gctx).unwrap();
+        let mut opts = CompileOptions::new(&gctx, UserIntent::Build).unwrap();
+        opts.build_config.export_dir = Some(root.join("dist"));
+        let (uplifted, exported) = (root.join("target/debug/up"), root.join("dist/up"));
+
+        // Miss: cargo builds, links and exports.
+        crate::cargo_interop::execute_build(&ws, &opts, &Default::default()).unwrap();
+        assert!(uplifted.is_file() && exported.is_file());
+
+        // Hit: only `deps/` comes out of the cache.
+        std::fs::remove_file(&uplifted).unwrap();
+        std::fs::remove_dir_all(root.join("dist")).unwrap();
+        let interner = UnitInterner::new();
+        let opts = [opts];
+        let plan = crate::cargo_interop::Plan::new(&ws, &interner, &opts).unwrap();
+        let mut runners = plan.runners().unwrap();
+        for unit in &plan.roots {
+            assert_eq!(uplift_unit(runners.get(unit), unit).unwrap(), 1);
+        }
+        let built = std::fs::read(&uplifted).unwrap();
+        assert!(!built.is_empty());
+        assert_eq!(std::fs::read(&exported).unwrap(), built);
+    }
+
+    #[test]
+    fn restorer_fans_units_out_and_reports_vanished_ones() {
+        use std::os::unix::fs::PermissionsExt;
+
+        use crate::cache::CacheBackend;
+        use crate::cache::codec::Codec;
+        use crate::cache::fs::FsCache;
+
+        let dir = tempfile::tempdir().unwrap();
+        let target = tempfile::tempdir().unwrap();
+        let cache = FsCache::new(dir.path()).unwrap();
+        let roots = PathRoots::from_roots(Path::new("/ws"), Path::new("/home"), target.path());
+
+        // More files than one batch, so the unit spans several workers.
+        let big = *blake3::hash(b"big").as_bytes();
+        let mut names: Vec<String> = (0..RESTORE_BATCH * 2 + 3)
+            .map(|i| format!("debug/deps/f{i}.o"))
+            .collect();
+        for (i, name) in names.iter().enumerate() {
+            cache.put_artifact(&big, name, format!("file {i}").as_bytes()).unwrap();
+        }
+        let exe = dir.path().join("build-script-build");
+        std::fs::write(&exe, b"#!/bin/sh").unwrap();
+        std::fs::set_permissions(&exe, std::fs::Permissions::from_mode(0o755)).unwrap();
+        cache.store_artifact_from_file(&big, "debug/build/foo/build-script-build", &exe).unwrap();
+        names.push("debug/build/foo/build-script-build".into());
+        cache.finalize_unit(&big, &names).unwrap();
+
+        // A unit whose blob goes away between lookup and restore.
+        let gone = *blake3::hash(b"gone").as_bytes();
+        cache.put_artifact(&gone, "debug/libgone.rlib", b"gone").unwrap();
+        cache.finalize_unit(&gone, &["debug/libgone.rlib".into()]).unwrap();
+        std::fs::remove_file(cache.blob_path(&crate::cache::cas::blob_name(
+            &crate::cache::cas::hash_bytes(b"gone"),
+            Codec::None,
+        )))
+        .unwrap();
+
+        let report = std::thread::scope(|scope| {
+            let mut restorer = Restorer::start(scope, &cache, target.path(), &roots, 4);
+            assert_eq!(restorer.submit(&big).unwrap(), Some(0));
+            assert_eq!(restorer.submit(&[9; 32]).unwrap(), None);
+            assert_eq!(restorer.submit(&gone).unwrap(), Some(1));
+            restorer.finish().unwrap()
+        });
+        assert_eq!(report.failed, [1]);
+        assert_eq!(report.files, names.len());
+        for (i, name) in names[..names.len() - 1].iter().enumerate() {
+            assert_eq!(std::fs::read_to_string(target.path().join(name)).unwrap(), format!("file {i}"));
+        }
+        let script = target.path().join("debug/build/foo/build-script-build");
+        assert_eq!(std::fs::metadata(&script).unwrap().permissions().mode() & 0o777, 0o755);
+        assert!(!target.path().join("debug/libgone.rlib").exists());
+    }
+
+    #[test]
+    fn unsharing_copies_hardlinked_restores_and_leaves_cargos_links() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        use crate::cache::CacheBackend;
+        use crate::cache::fs::FsCache;
+
+        let dir = tempfile::tempdir().unwrap();
+        let target = tempfile::tempdir().unwrap();
+        let cache = FsCache::new(dir.path()).unwrap().with_hardlinks(true);
+        let key = *blake3::hash(b"unit").as_bytes();
+        let fingerprint = "debug/.fingerprint/foo-1/lib-foo";
+        cache.put_artifact(&key, fingerprint, b"fp").unwrap();
+        cache.finalize_unit(&key, &[fingerprint.into()]).unwrap();
+        let blob = cache.blob_path(&cache.list_blobs().unwrap()[0].name);
+        let old = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+        std::fs::File::open(&blob).unwrap().set_modified(old).unwrap();
+        let restored = target.path().join(fingerprint);
+        std::fs::create_dir_all(restored.parent().unwrap()).unwrap();
+        assert!(cache.restore_artifact(&key, fingerprint, &restored).unwrap());
+        assert_eq!(std::fs::metadata(&restored).unwrap().ino(), std::fs::metadata(&blob).unwrap().ino());
+        // An uplift cargo linked itself.
+        let built = target.path().join("debug/deps/foo");
+        write(&built, "bin");
+        std::fs::hard_link(&built, target.path().join("debug/foo")).unwrap();
+
+        assert_eq!(unshare_restored(target.path()).unwrap(), 1);
+        let meta = std::fs::metadata(&restored).unwrap();
+        assert_ne!(meta.ino(), std::fs::metadata(&blob).unwrap().ino());
+        assert!(meta.modified().unwrap() > old);
+        std::fs::write(&restored, b"rewritten").unwrap();
+        assert_eq!(std::fs::read(&blob).unwrap(), b"fp");
+        assert_eq!(std::fs::metadata(&blob).unwrap().permissions().mode() & 0o222, 0);
+        assert_eq!(std::fs::metadata(&built).unwrap().nlink(), 2);
     }
-    Ok(restored)
 }
@@ -3,6 +3,8 @@ use std::time::Instant;
 
 use anyhow::{Context, Result};
 
+use crate::cache::codec::Compression;
+
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct RunMetrics {
     pub label: String,
@@ -21,6 +23,14 @@ pub struct BenchResult {
     pub artifact_count: u64,
     pub artifact_total_bytes: u64,
     pub runs: Vec<RunMetrics>,
+    /// Cache size after the uncompressed store.
+    #[serde(default)]
+    pub cache_bytes: u64,
+    /// `--compress` spec of the compressed pass and the cache size it left.
+    #[serde(default)]
+    pub compression: Option<String>,
+    #[serde(default)]
+    pub compressed_cache_bytes: Option<u64>,
 }
 
 fn bench_db_path() -> Result<PathBuf> {
@@ -150,11 +160,11 @@ fn detect_project_name(manifest_path: &Path) -> String {
     if let Ok(contents) = std::fs::read_to_string(manifest_path) {
         for line in contents.lines() {
             let line = line.trim();
-            if line.starts_with("name") {
-                if let Some(val) = line.split('=').nth(1) {
-                    let val = val.trim().trim_matches('"');
-                    return val.to_string();
-                }
+            if line.starts_with("name")
+                && let Some(val) = line.split('=').nth(1)
+            {
+                let val = val.trim().trim_matches('"');
+                return val.to_string();
             }
         }
     }
@@ -174,6 +184,7 @@ pub fn run_bench(
     release: bool,
     cache_backend: &str,
     with_sccache: bool,
+    compression: Option<Compression>,
 ) -> Result<()> {
     let manifest_path = manifest_path.canonicalize()
         .with_context(|| format!("canonicalizing {}", manifest_path.display()))?;
@@ -236,6 +247,7 @@ pub fn run_bench(
 
     let store = timed_run("cargo-zb first build", &zb_exe_str, &zb_args, project_dir, &[])?;
     runs.push(store);
+    let (_, cache_bytes) = count_dir(&cache_dir);
 
     eprintln!("\n=== Step 4: Cold cache restore ===");
     let _ = timed_run("cargo clean", "cargo", &["clean"], project_dir, &[]);
@@ -250,6 +262,25 @@ pub fn run_bench(
     let warm_restore = timed_run("cargo-zb restore (warm)", &zb_exe_str, &zb_args, project_dir, &[])?;
     runs.push(warm_restore);
 
+    let mut compressed_cache_bytes = None;
+    if let Some(compression) = compression {
+        let spec = compression.to_string();
+        eprintln!("\n=== Step 6: Compressed store + warm restore ({spec}) ===");
+        let mut args = zb_args.clone();
+        args.extend(["--compress", spec.as_str()]);
+
+        let _ = timed_run("cargo clean", "cargo", &["clean"], project_dir, &[]);
+        let _ = std::fs::remove_dir_all(&cache_dir);
+        std::fs::create_dir_all(&cache_dir)?;
+        let label = format!("cargo-zb first build ({spec})");
+        runs.push(timed_run(&label, &zb_exe_str, &args, project_dir, &[])?);
+        compressed_cache_bytes = Some(count_dir(&cache_dir).1);
+
+        let _ = timed_run("cargo clean", "cargo", &["clean"], project_dir, &[]);
+        let label = format!("cargo-zb restore ({spec})");
+        runs.push(timed_run(&label, &zb_exe_str, &args, project_dir, &[])?);
+    }
+
     if with_sccache {
         if std::process::Command::new("sccache").arg("--version").output().is_err() {
             eprintln!("\n  warning: sccache not found in PATH. Install it for comparison benchmarks.");
@@ -261,7 +292,7 @@ pub fn run_bench(
                 .arg("--stop-server")
                 .output();
 
-            eprintln!("\n=== Step 6: sccache cold build ===");
+            eprintln!("\n=== Step 7: sccache cold build ===");
             let _ = timed_run("cargo clean", "cargo", &["clean"], project_dir, &[]);
             let _ = std::fs::remove_dir_all(&sccache_dir);
 
@@ -275,7 +306,7 @@ pub fn run_bench(
                 Err(e) => eprintln!("  sccache cold build failed: {e}"),
             }
 
-            eprintln!("\n=== Step 7: sccache warm build ===");
+            eprintln!("\n=== Step 8: sccache warm build ===");
             let _ = timed_run("cargo clean", "cargo", &["clean"], project_dir, &[]);
             match timed_run("sccache warm (hit)", "cargo", &cargo_build_args, project_dir, &sccache_env) {
                 Ok(m) => runs.push(m),
@@ -302,6 +333,9 @@ pub fn run_bench(
         artifact_count,
         artifact_total_bytes,
         runs,
+        cache_bytes,
+        compression: compression.map(|c| c.to_string()),
+        compressed_cache_bytes,
     };
 
     save_result(&result)?;
@@ -329,6 +363,18 @@ fn print_one_result(r: &BenchResult) {
         println!("  {:<30} {:>7.2}s {:>7.2}s {:>7.2}s {:>10}",
             run.label, run.wall_secs, run.user_secs, run.sys_secs, rss);
     }
+    if let (Some(spec), Some(compressed)) = (&r.compression, r.compressed_cache_bytes) {
+        let ratio = r.cache_bytes as f64 / compressed.max(1) as f64;
+        println!();
+        println!("  {spec}: cache {} -> {} ({ratio:.2}x smaller)",
+            format_size(r.cache_bytes), format_size(compressed));
+        let plain = r.runs.iter().rfind(|m| m.label == "cargo-zb restore (warm)");
+        let packed = r.runs.iter().rfind(|m| m.label == format!("cargo-zb restore ({spec})"));
+        if let (Some(plain), Some(packed)) = (plain, packed) {
+            println!("  {spec}: warm restore {:.2}s -> {:.2}s ({:+.2}s)",
+                plain.wall_secs, packed.wall_secs, packed.wall_secs - plain.wall_secs);
+        }
+    }
     println!();
 }
 
@@ -387,11 +433,11 @@ pub fn list_benched() -> Result<()> {
 
         let sccache_warm = r.runs.iter().find(|m| m.label.contains("sccache") && m.label.contains("warm"));
         let zb_warm = r.runs.iter().rfind(|m| m.label.contains("restore") && m.label.contains("warm") && !m.label.contains("*"));
-        if let (Some(sc), Some(zb)) = (sccache_warm, zb_warm) {
-            if zb.wall_secs > 0.0 {
-                let speedup = sc.wall_secs / zb.wall_secs;
-                println!("  cargo-zb is {speedup:.1}x faster than sccache on warm restore");
-            }
+        if let (Some(sc), Some(zb)) = (sccache_warm, zb_warm)
+            && zb.wall_secs > 0.0
+        {
+            let speedup = sc.wall_secs / zb.wall_secs;
+            println!("  cargo-zb is {speedup:.1}x faster than sccache on warm restore");
         }
     }
 
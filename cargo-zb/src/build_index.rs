@@ -0,0 +1,260 @@
+//! Whole-build index: one lookup for a build where nothing changed.
+//!
+//! The per-unit lookup lists and hashes the dynamic-inputs manifests of every
+//! unit in turn. Once a build has succeeded, cargo-zb also records the build
+//! as a whole:
+//!
+//! - the **build key** hashes the static keys of every unit in the graph;
+//! - under it, a dynamic-inputs manifest aggregates every unit's manifest
+//!   (the union of their paths and env vars);
+//! - the build key combined with that manifest's content hash names a small
+//!   bundle holding `zb-build-index`: each unit's static key and full key.
+//!
+//! A rebuild with no changes then costs one manifest lookup, one pass over
+//! the aggregated inputs and a bulk restore. Anything else (an edited file, a
+//! new env value, an evicted bundle) falls back to the per-unit walk, which
+//! stores a fresh index once the build completes. The index is an ordinary
+//! bundle with its manifest's `unit_keys` pointing at it, so every backend,
+//! the tiered upload and `gc` handle it unchanged.
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use cargo::core::compiler::Unit;
+use tracing::debug;
+
+use crate::artifacts;
+use crate::cache::{self, CacheBackend, DynamicInputs};
+use crate::hash::{self, CacheKey};
+use crate::remap::PathRoots;
+
+const FILE_NAME: &str = "zb-build-index";
+
+/// Key of the whole unit graph: its units' static keys, in no particular order.
+pub fn build_key(units: &[Unit], static_keys: &HashMap<Unit, CacheKey>) -> CacheKey {
+    let mut keys: Vec<&CacheKey> = units.iter().map(|u| &static_keys[u]).collect();
+    keys.sort_by_key(|k| k.0);
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(b"zb-build-v1\0");
+    for key in keys {
+        hasher.update(key.as_bytes());
+    }
+    CacheKey(*hasher.finalize().as_bytes())
+}
+
+/// Full keys of every unit, if the index recorded for `build_key` matches
+/// the current inputs. Its env vars are all inherited ones (see [`store`]).
+pub fn lookup(
+    cache: &dyn CacheBackend,
+    roots: &PathRoots,
+    build_key: &CacheKey,
+    units: &[Unit],
+    static_keys: &HashMap<Unit, CacheKey>,
+) -> Result<Option<HashMap<Unit, CacheKey>>> {
+    for inputs in cache.list_dynamic_inputs(build_key.as_bytes())? {
+        let content = match inputs.content_hash(roots, |n| std::env::var(n).ok()) {
+            Ok(c) => c,
+            Err(e) => {
+                debug!("build index content hash failed: {e}");
+                continue;
+            }
+        };
+        let index_key = hash::combine_full_key(build_key, &content, &[]);
+        let Some(data) = cache.get_artifact(index_key.as_bytes(), FILE_NAME)? else {
+            continue;
+        };
+        cache.touch_units(&[*index_key.as_bytes()])?;
+        let entries = parse(&data)?;
+        return Ok(units
+            .iter()
+            .map(|u| Some((u.clone(), *entries.get(&static_keys[u])?)))
+            .collect());
+    }
+    Ok(None)
+}
+
+/// Restore every unit in `full_keys`. Returns false, leaving the caller to
+/// fall back to the per-unit lookup, if any bundle is gone.
+pub fn restore(
+    cache: &dyn CacheBackend,
+    target_dir: &Path,
+    roots: &PathRoots,
+    io_threads: usize,
+    full_keys: &HashMap<Unit, CacheKey>,
+) -> Result<bool> {
+    std::thread::scope(|scope| {
+        let mut restorer = artifacts::Restorer::start(scope, cache, target_dir, roots, io_threads);
+        let mut complete = true;
+        for key in full_keys.values() {
+            if restorer.submit(key.as_bytes())?.is_none() {
+                complete = false;
+                break;
+            }
+        }
+        let report = restorer.finish()?;
+        debug!("restored {} files from the build index", report.files);
+        Ok(complete && report.failed.is_empty())
+    })
+}
+
+/// Record the index for a build in which every unit has a stored bundle,
+/// found by (or stored from) the manifest in `inputs`. Units sharing a static
+/// key must share a full key, or the index couldn't tell them apart.
+///
+/// `unit_env` resolves a var as the per-unit lookup does for `unit`. Vars a
+/// unit takes from its build script's `rustc-env` are left out of the
+/// index: [`lookup`] runs before any script output is at hand, and the
+/// values follow from the script run's own inputs, which the index covers.
+#[allow(clippy::too_many_arguments)]
+pub fn store(
+    cache: &dyn CacheBackend,
+    roots: &PathRoots,
+    build_key: &CacheKey,
+    units: &[Unit],
+    static_keys: &HashMap<Unit, CacheKey>,
+    full_keys: &HashMap<Unit, CacheKey>,
+    inputs: &HashMap<Unit, DynamicInputs>,
+    unit_env: impl Fn(&Unit, &str) -> Option<String>,
+) -> Result<()> {
+    let mut entries: HashMap<CacheKey, CacheKey> = HashMap::new();
+    for unit in units {
+        let (Some(full), true) = (full_keys.get(unit), inputs.contains_key(unit)) else {
+            debug!(
+                "not indexing build: {} ({}) has no bundle",
+                unit.pkg.name(),
+                unit.target.name()
+            );
+            return Ok(());
+        };
+        if *entries.entry(static_keys[unit]).or_insert(*full) != *full {
+            debug!("not indexing build: units share a static key");
+            return Ok(());
+        }
+    }
+    let inherited: Vec<DynamicInputs> = units
+        .iter()
+        .map(|unit| {
+            let mut own = inputs[unit].clone();
+            own.envs.retain(|e| unit_env(unit, &e.name) == std::env::var(&e.name).ok());
+            own
+        })
+        .collect();
+    let mut aggregated = aggregate(inherited.iter());
+    let content = aggregated.content_hash(roots, |n| std::env::var(n).ok())?;
+    let index_key = hash::combine_full_key(build_key, &content, &[]);
+    if !cache.contains_unit(index_key.as_bytes())? {
+        cache.put_artifact(index_key.as_bytes(), FILE_NAME, &serialize(&entries))?;
+        cache.finalize_unit(index_key.as_bytes(), &[FILE_NAME.to_string()])?;
+    }
+    aggregated.unit_keys.push(*index_key.as_bytes());
+    cache.put_dynamic_inputs(build_key.as_bytes(), &aggregated)?;
+    debug!(
+        "indexed build {} ({} units)",
+        index_key.to_hex(),
+        entries.len()
+    );
+    Ok(())
+}
+
+/// One manifest declaring every path and env var of `manifests`, keeping the
+/// first snapshot of each.
+fn aggregate<'a>(manifests: impl Iterator<Item = &'a DynamicInputs>) -> DynamicInputs {
+    let mut out = DynamicInputs::default();
+    let mut paths = HashSet::new();
+    let mut envs = HashSet::new();
+    for inputs in manifests {
+        for p in &inputs.paths {
+            if paths.insert(&p.path) {
+                out.paths.push(p.clone());
+            }
+        }
+        for e in &inputs.envs {
+            if envs.insert(&e.name) {
+                out.envs.push(e.clone());
+            }
+        }
+    }
+    out
+}
+
+/// One `<static key> <full key>` line per entry, sorted.
+fn serialize(entries: &HashMap<CacheKey, CacheKey>) -> Vec<u8> {
+    let mut lines: Vec<String> = entries
+        .iter()
+        .map(|(s, f)| {
+            format!(
+                "{} {}\n",
+                cache::hex(s.as_bytes()),
+                cache::hex(f.as_bytes())
+            )
+        })
+        .collect();
+    lines.sort();
+    lines.concat().into_bytes()
+}
+
+fn parse(data: &[u8]) -> Result<HashMap<CacheKey, CacheKey>> {
+    let text = std::str::from_utf8(data).context("build index is not UTF-8")?;
+    text.lines()
+        .map(|line| {
+            let (s, f) = line
+                .split_once(' ')
+                .and_then(|(s, f)| Some((cache::unhex(s)?, cache::unhex(f)?)))
+                .with_context(|| format!("malformed build index line {line:?}"))?;
+            Ok((CacheKey(s), CacheKey(f)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{DynEnv, DynPath};
+
+    #[test]
+    fn aggregate_keeps_each_input_once() {
+        let path = |p: &str, h| DynPath {
+            path: p.into(),
+            stored_hash: [h; 32],
+        };
+        let env = |n: &str| DynEnv {
+            name: n.into(),
+            stored_value: None,
+        };
+        let a = DynamicInputs {
+            paths: vec![path("/src/a.rs", 1), path("/src/shared.rs", 2)],
+            envs: vec![env("CC")],
+            unit_keys: vec![[9; 32]],
+            ..Default::default()
+        };
+        let b = DynamicInputs {
+            paths: vec![path("/src/shared.rs", 3), path("/src/b.rs", 4)],
+            envs: vec![env("CC"), env("PROFILE")],
+            ..Default::default()
+        };
+        let merged = aggregate([&a, &b].into_iter());
+        let paths: Vec<_> = merged
+            .paths
+            .iter()
+            .map(|p| (p.path.to_str().unwrap(), p.stored_hash[0]))
+            .collect();
+        assert_eq!(
+            paths,
+            [("/src/a.rs", 1), ("/src/shared.rs", 2), ("/src/b.rs", 4)]
+        );
+        let envs: Vec<_> = merged.envs.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(envs, ["CC", "PROFILE"]);
+        assert!(merged.unit_keys.is_empty());
+    }
+
+    #[test]
+    fn index_round_trips() {
+        let entries = HashMap::from([
+            (CacheKey([1; 32]), CacheKey([2; 32])),
+            (CacheKey([3; 32]), CacheKey([4; 32])),
+        ]);
+        assert_eq!(parse(&serialize(&entries)).unwrap(), entries);
+        assert!(parse(b"zz 00\n").is_err());
+    }
+}
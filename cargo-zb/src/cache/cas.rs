@@ -0,0 +1,160 @@
+//! Content-addressed artifact storage shared by the backends.
+//!
+//! A unit manifest lists `(path, blake3, size, mode)` for each artifact; the
+//! bytes live once per content under `blobs/`. Identical `OUT_DIR` files,
+//! rlibs re-keyed by a feature change and unchanged fingerprints are then
+//! stored once however many unit keys refer to them.
+//!
+//! A blob's name is its hash plus the codec suffix (`<hex>` or `<hex>.zst`):
+//! content first stored uncompressed is reused as-is by compressing builds,
+//! and the manifest entry records which encoding it points at.
+//!
+//! Nothing counts references on disk. `gc` derives refcounts from the unit
+//! manifests it lists, and only deletes an unreferenced blob once it is older
+//! than a grace period; stores that reuse a blob refresh its mtime first.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::codec::Codec;
+
+/// One artifact of a unit, pointing at its blob.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlobEntry {
+    pub path: String,
+    /// blake3 of the uncompressed content, hex.
+    pub hash: String,
+    pub size: u64,
+    pub mode: u32,
+    #[serde(default, skip_serializing_if = "Codec::is_none")]
+    pub codec: Codec,
+}
+
+impl BlobEntry {
+    pub fn blob_name(&self) -> String {
+        blob_name(&self.hash, self.codec)
+    }
+}
+
+pub fn blob_name(hash: &str, codec: Codec) -> String {
+    match codec {
+        Codec::None => hash.to_string(),
+        Codec::Zstd => format!("{hash}.zst"),
+    }
+}
+
+/// A stored blob as seen by `gc`.
+#[derive(Debug, Clone)]
+pub struct BlobInfo {
+    pub name: String,
+    /// Bytes the blob occupies in the backend (after compression).
+    pub size: u64,
+    /// Last store or reuse.
+    pub modified: SystemTime,
+}
+
+/// Hash a file's content, returning `(hex, size, mode)`.
+pub fn hash_file(path: &Path) -> Result<(String, u64, u32)> {
+    let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    let meta = file.metadata()?;
+    let mut hasher = blake3::Hasher::new();
+    hasher
+        .update_reader(file)
+        .with_context(|| format!("hashing {}", path.display()))?;
+    Ok((hasher.finalize().to_hex().to_string(), meta.len(), meta.permissions().mode()))
+}
+
+pub fn hash_bytes(data: &[u8]) -> String {
+    blake3::hash(data).to_hex().to_string()
+}
+
+/// Stored form of a unit manifest. Units written before the blob store keep
+/// their artifacts under the unit itself and stay readable.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum UnitManifest {
+    Blobs { blobs: Vec<BlobEntry> },
+    Encoded { codec: Codec, artifacts: Vec<String> },
+    Plain(Vec<String>),
+}
+
+/// A parsed manifest, indexed for per-file restore.
+#[derive(Debug, Default)]
+pub(crate) struct UnitIndex {
+    pub artifacts: Vec<String>,
+    /// Codec of per-unit artifacts in pre-blob manifests.
+    pub legacy_codec: Codec,
+    pub blobs: HashMap<String, BlobEntry>,
+}
+
+impl UnitManifest {
+    pub(crate) fn into_index(self) -> UnitIndex {
+        match self {
+            UnitManifest::Blobs { blobs } => UnitIndex {
+                artifacts: blobs.iter().map(|b| b.path.clone()).collect(),
+                legacy_codec: Codec::None,
+                blobs: blobs.into_iter().map(|b| (b.path.clone(), b)).collect(),
+            },
+            UnitManifest::Encoded { codec, artifacts } => UnitIndex {
+                artifacts,
+                legacy_codec: codec,
+                blobs: HashMap::new(),
+            },
+            UnitManifest::Plain(artifacts) => UnitIndex { artifacts, ..Default::default() },
+        }
+    }
+}
+
+/// Order `stored` (this unit's blob entries by path) into a manifest for
+/// `artifacts`, failing if one was never stored.
+pub(crate) fn manifest_for(
+    artifacts: &[String],
+    mut stored: HashMap<String, BlobEntry>,
+) -> Result<UnitManifest> {
+    let blobs = artifacts
+        .iter()
+        .map(|p| {
+            stored
+                .remove(p)
+                .with_context(|| format!("finalizing unit: {p} was never stored"))
+        })
+        .collect::<Result<_>>()?;
+    Ok(UnitManifest::Blobs { blobs })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_every_manifest_format() {
+        let plain: UnitManifest = serde_json::from_slice(br#"["a"]"#).unwrap();
+        assert_eq!(plain.into_index().artifacts, ["a"]);
+
+        let encoded: UnitManifest =
+            serde_json::from_slice(br#"{"codec":"zstd","artifacts":["a"]}"#).unwrap();
+        assert_eq!(encoded.into_index().legacy_codec, Codec::Zstd);
+
+        let entry = BlobEntry {
+            path: "debug/a".into(),
+            hash: hash_bytes(b"a"),
+            size: 1,
+            mode: 0o644,
+            codec: Codec::Zstd,
+        };
+        let stored = HashMap::from([(entry.path.clone(), entry.clone())]);
+        let manifest = manifest_for(&["debug/a".into()], stored).unwrap();
+        let data = serde_json::to_vec(&manifest).unwrap();
+        let index = serde_json::from_slice::<UnitManifest>(&data).unwrap().into_index();
+        assert_eq!(index.blobs["debug/a"], entry);
+        assert!(entry.blob_name().ends_with(".zst"));
+
+        assert!(manifest_for(&["missing".into()], HashMap::new()).is_err());
+    }
+}
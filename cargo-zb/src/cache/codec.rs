@@ -0,0 +1,141 @@
+//! Optional per-artifact compression (`--compress zstd[:level]`).
+//!
+//! The codec is recorded in each unit manifest entry, so one cache can hold
+//! both compressed and uncompressed artifacts and any build can restore either.
+//! Uncompressed artifacts keep the `copy_file_range` path; compressed ones are
+//! streamed through the decoder. Cached files keep the source's mode bits
+//! either way, which is what restore copies back onto the output.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    #[default]
+    None,
+    Zstd,
+}
+
+impl Codec {
+    pub fn is_none(&self) -> bool {
+        *self == Codec::None
+    }
+}
+
+/// What to compress newly stored artifacts with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Compression {
+    pub codec: Codec,
+    pub level: i32,
+}
+
+const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, level) = match s.split_once(':') {
+            Some((name, level)) => (name, Some(level)),
+            None => (s, None),
+        };
+        match name {
+            "none" if level.is_none() => Ok(Compression::default()),
+            "zstd" => {
+                let level = match level {
+                    Some(l) => l
+                        .parse()
+                        .with_context(|| format!("invalid zstd level {l:?}"))?,
+                    None => DEFAULT_ZSTD_LEVEL,
+                };
+                let range = zstd::compression_level_range();
+                anyhow::ensure!(
+                    range.contains(&level),
+                    "zstd level {level} out of range ({}..={})",
+                    range.start(),
+                    range.end()
+                );
+                Ok(Compression { codec: Codec::Zstd, level })
+            }
+            _ => anyhow::bail!("unknown compression {s:?} (expected \"none\" or \"zstd[:level]\")"),
+        }
+    }
+}
+
+impl std::fmt::Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.codec {
+            Codec::None => f.write_str("none"),
+            Codec::Zstd => write!(f, "zstd:{}", self.level),
+        }
+    }
+}
+
+/// Write `src` to `dest` encoded with `compression`, copying `src`'s mode.
+/// Only called for compressed stores; `Codec::None` goes through the
+/// backend's own copy path.
+pub fn compress_file(src: &Path, dest: &Path, compression: Compression) -> Result<()> {
+    let input = File::open(src).with_context(|| format!("opening {}", src.display()))?;
+    let mode = input.metadata()?.permissions().mode();
+    let output = File::create(dest).with_context(|| format!("creating {}", dest.display()))?;
+    let mut writer = BufWriter::new(output);
+    zstd::stream::copy_encode(BufReader::new(input), &mut writer, compression.level)
+        .with_context(|| format!("compressing {}", src.display()))?;
+    writer.flush()?;
+    std::fs::set_permissions(dest, std::fs::Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+/// Decode the cached `src` into `dest`, copying `src`'s mode.
+pub fn decompress_file(src: &Path, dest: &Path) -> Result<()> {
+    let input = File::open(src).with_context(|| format!("opening {}", src.display()))?;
+    let mode = input.metadata()?.permissions().mode();
+    decompress_to(BufReader::new(input), dest)?;
+    std::fs::set_permissions(dest, std::fs::Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+/// Decode a compressed stream into a new file at `dest`.
+pub fn decompress_to(input: impl std::io::Read, dest: &Path) -> Result<()> {
+    let output = File::create(dest).with_context(|| format!("creating {}", dest.display()))?;
+    let mut writer = BufWriter::new(output);
+    zstd::stream::copy_decode(input, &mut writer)
+        .with_context(|| format!("decompressing into {}", dest.display()))?;
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn encode(data: &[u8], compression: Compression) -> Result<Vec<u8>> {
+    match compression.codec {
+        Codec::None => Ok(data.to_vec()),
+        Codec::Zstd => Ok(zstd::bulk::compress(data, compression.level)?),
+    }
+}
+
+pub fn decode(data: Vec<u8>, codec: Codec) -> Result<Vec<u8>> {
+    match codec {
+        Codec::None => Ok(data),
+        Codec::Zstd => Ok(zstd::stream::decode_all(&data[..])?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_compression_specs() {
+        assert_eq!("zstd".parse::<Compression>().unwrap(), Compression { codec: Codec::Zstd, level: 3 });
+        assert_eq!("zstd:19".parse::<Compression>().unwrap().level, 19);
+        assert_eq!("none".parse::<Compression>().unwrap(), Compression::default());
+        assert!("zstd:99".parse::<Compression>().is_err());
+        assert!("lz4".parse::<Compression>().is_err());
+    }
+}
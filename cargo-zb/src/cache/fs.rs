@@ -1,13 +1,38 @@
+use std::collections::HashMap;
 use std::fs::File;
+use std::os::unix::fs::PermissionsExt;
 use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
 use anyhow::{Context, Result};
 
-use super::{CacheBackend, DynamicInputs};
+use super::cas::{self, BlobEntry, BlobInfo, UnitIndex, UnitManifest};
+use super::codec::{self, Codec, Compression};
+use super::{CacheBackend, DynamicInputs, UnitInfo};
 
+/// Layout under `root`:
+///
+/// - `units/<key>/manifest.json` — the unit's blob entries (see `cas`)
+/// - `blobs/<xx>/<hash>[.zst]` — artifact contents, read-only
+/// - `dynamic/<static>/<shape>.json` — dynamic-inputs manifests
+///
+/// Units stored before the blob store keep `units/<key>/artifacts/<rel>`.
 pub struct FsCache {
     root: PathBuf,
+    compression: Compression,
+    hardlink: bool,
+    /// Parsed manifests of units read or written by this process, so
+    /// restores don't re-read them per file.
+    indexes: Mutex<HashMap<[u8; 32], Arc<UnitIndex>>>,
+    /// Blob entries stored since each unit's last `finalize_unit`.
+    pending: Mutex<HashMap<[u8; 32], HashMap<String, BlobEntry>>>,
+    /// Set after the first FICLONE the filesystem refuses.
+    no_reflink: AtomicBool,
+    #[cfg(feature = "io-uring")]
+    io_uring: bool,
 }
 
 impl FsCache {
@@ -15,7 +40,40 @@ impl FsCache {
         let root = root.into();
         std::fs::create_dir_all(&root)
             .with_context(|| format!("creating cache dir {}", root.display()))?;
-        Ok(Self { root })
+        Ok(Self {
+            root,
+            compression: Compression::default(),
+            hardlink: false,
+            indexes: Mutex::new(HashMap::new()),
+            pending: Mutex::new(HashMap::new()),
+            no_reflink: AtomicBool::new(false),
+            #[cfg(feature = "io-uring")]
+            io_uring: false,
+        })
+    }
+
+    /// Compress artifacts stored from now on. Existing blobs keep the
+    /// codec they were written with.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Restore uncompressed blobs as hardlinks instead of copies. The
+    /// restored files *are* the cache's read-only blobs, so this is only
+    /// for target dirs that are discarded rather than rebuilt in place;
+    /// before cargo runs over them, `artifacts::unshare_restored` copies them.
+    pub fn with_hardlinks(mut self, enabled: bool) -> Self {
+        self.hardlink = enabled;
+        self
+    }
+
+    /// Restore batches through io_uring instead of one `copy_file_range`
+    /// loop per file. Falls back per file when the ring can't be set up.
+    #[cfg(feature = "io-uring")]
+    pub fn with_io_uring(mut self, enabled: bool) -> Self {
+        self.io_uring = enabled;
+        self
     }
 
     fn unit_dir(&self, unit_key: &[u8; 32]) -> PathBuf {
@@ -30,9 +88,184 @@ impl FsCache {
         self.unit_dir(unit_key).join("artifacts").join(rel_path)
     }
 
+    pub(crate) fn blob_path(&self, name: &str) -> PathBuf {
+        self.root.join("blobs").join(&name[..2]).join(name)
+    }
+
     fn dyn_dir(&self, static_key: &[u8; 32]) -> PathBuf {
         self.root.join("dynamic").join(super::hex(static_key))
     }
+
+    fn read_index(&self, unit_key: &[u8; 32]) -> Result<Option<Arc<UnitIndex>>> {
+        let path = self.manifest_path(unit_key);
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("reading manifest {}", path.display())),
+        };
+        let manifest: UnitManifest = serde_json::from_slice(&data)
+            .with_context(|| format!("parsing manifest {}", path.display()))?;
+        let index = Arc::new(manifest.into_index());
+        self.indexes.lock().unwrap().insert(*unit_key, Arc::clone(&index));
+        Ok(Some(index))
+    }
+
+    fn unit_index(&self, unit_key: &[u8; 32]) -> Result<Arc<UnitIndex>> {
+        if let Some(index) = self.indexes.lock().unwrap().get(unit_key) {
+            return Ok(Arc::clone(index));
+        }
+        Ok(self.read_index(unit_key)?.unwrap_or_default())
+    }
+
+    /// Store content with blake3 `hash` unless a blob for it exists in
+    /// either encoding. `write` fills a temp file with the encoded content.
+    /// Returns the codec of the blob the unit should reference.
+    fn store_blob(&self, hash: &str, write: impl FnOnce(&Path) -> Result<()>) -> Result<Codec> {
+        let preferred = self.compression.codec;
+        let other = if preferred.is_none() { Codec::Zstd } else { Codec::None };
+        for codec in [preferred, other] {
+            match File::open(self.blob_path(&cas::blob_name(hash, codec))) {
+                // Reused: refresh the mtime so a concurrent gc's grace period
+                // covers it until our manifest lands. Best effort: a cache
+                // shared between users may hold blobs we don't own.
+                Ok(f) => {
+                    let _ = f.set_modified(SystemTime::now());
+                    return Ok(codec);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e).context("opening blob"),
+            }
+        }
+
+        let path = self.blob_path(&cas::blob_name(hash, preferred));
+        let dir = path.parent().expect("blob path has a parent");
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("creating {}", dir.display()))?;
+        let tmp = tempfile::Builder::new()
+            .prefix(".tmp")
+            .tempfile_in(dir)?
+            .into_temp_path();
+        write(&tmp)?;
+        // Read-only, so nothing writes through a hardlinked restore.
+        let mode = std::fs::metadata(&tmp)?.permissions().mode() & !0o222;
+        std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(mode))?;
+        tmp.persist(&path)
+            .with_context(|| format!("renaming blob {}", path.display()))?;
+        Ok(preferred)
+    }
+
+    fn add_pending(&self, unit_key: &[u8; 32], entry: BlobEntry) {
+        self.pending
+            .lock()
+            .unwrap()
+            .entry(*unit_key)
+            .or_default()
+            .insert(entry.path.clone(), entry);
+    }
+
+    fn restore_blob(&self, entry: &BlobEntry, dest: &Path) -> Result<bool> {
+        let src = self.blob_path(&entry.blob_name());
+        if self.hardlink && entry.codec.is_none() {
+            match std::fs::hard_link(&src, dest) {
+                Ok(()) => return Ok(true),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+                Err(_) => {} // e.g. cache and target on different filesystems
+            }
+        }
+        let copied = match entry.codec {
+            Codec::None => self.reflink_or_copy(&src, dest),
+            Codec::Zstd => codec::decompress_file(&src, dest),
+        };
+        match copied {
+            Ok(()) => {}
+            Err(e) if is_not_found(&e) => return Ok(false),
+            Err(e) => return Err(e),
+        }
+        std::fs::set_permissions(dest, std::fs::Permissions::from_mode(entry.mode))?;
+        Ok(true)
+    }
+
+    /// FICLONE where the filesystem shares extents (btrfs, xfs), else
+    /// `copy_file_range`.
+    fn reflink_or_copy(&self, src: &Path, dest: &Path) -> Result<()> {
+        if !self.no_reflink.load(Ordering::Relaxed) {
+            let src_file = File::open(src)
+                .with_context(|| format!("opening {}", src.display()))?;
+            let dst_file = File::create(dest)
+                .with_context(|| format!("creating {}", dest.display()))?;
+            let rc = unsafe { libc::ioctl(dst_file.as_raw_fd(), libc::FICLONE, src_file.as_raw_fd()) };
+            if rc == 0 {
+                return Ok(());
+            }
+            let err = std::io::Error::last_os_error();
+            if matches!(err.raw_os_error(), Some(libc::EOPNOTSUPP | libc::EINVAL | libc::ENOTTY)) {
+                self.no_reflink.store(true, Ordering::Relaxed);
+            }
+        }
+        copy_file_range_or_fallback(src, dest)
+    }
+
+    fn read_dynamic_inputs(path: &Path) -> Result<DynamicInputs> {
+        let data = std::fs::read(path)
+            .with_context(|| format!("reading {}", path.display()))?;
+        serde_json::from_slice(&data)
+            .with_context(|| format!("parsing {}", path.display()))
+    }
+
+    fn sweep_incomplete(&self, dir: &Path) -> Result<()> {
+        // No manifest: either a concurrent build is still storing into it, or
+        // one died mid-store. Leave recent ones alone.
+        let age = std::fs::metadata(dir)?
+            .modified()?
+            .elapsed()
+            .unwrap_or_default();
+        if age > INCOMPLETE_GRACE {
+            tracing::debug!("removing incomplete unit dir {}", dir.display());
+            remove_dir_atomically(dir)?;
+        }
+        Ok(())
+    }
+}
+
+/// How long a unit dir without a manifest may sit before `gc` treats it as
+/// debris from a crashed store rather than one in progress.
+const INCOMPLETE_GRACE: std::time::Duration = std::time::Duration::from_secs(3600);
+
+/// Unlink `dest` before restoring over it: it may be a hardlinked blob from
+/// an earlier `--hardlink` restore, and writing through it would change the
+/// cached content.
+fn remove_existing(dest: &Path) -> Result<()> {
+    match std::fs::remove_file(dest) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("removing {}", dest.display())),
+    }
+}
+
+fn is_not_found(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound)
+}
+
+/// Rename `dir` out of the way, then delete it. The rename makes the unit
+/// disappear for new readers in one step; a reader midway through a restore
+/// sees missing files and treats the unit as a miss.
+fn remove_dir_atomically(dir: &Path) -> Result<()> {
+    let Some(name) = dir.file_name() else {
+        return Ok(());
+    };
+    let doomed = dir.with_file_name(format!(
+        ".gc-{}-{}",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    match std::fs::rename(dir, &doomed) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("renaming {}", dir.display())),
+    }
+    std::fs::remove_dir_all(&doomed)
+        .with_context(|| format!("removing {}", doomed.display()))
 }
 
 impl CacheBackend for FsCache {
@@ -41,45 +274,56 @@ impl CacheBackend for FsCache {
     }
 
     fn list_artifacts(&self, unit_key: &[u8; 32]) -> Result<Vec<String>> {
-        let path = self.manifest_path(unit_key);
-        match std::fs::read(&path) {
-            Ok(data) => Ok(serde_json::from_slice(&data)?),
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
-            Err(e) => Err(e).with_context(|| format!("reading manifest {}", path.display())),
-        }
+        Ok(self
+            .read_index(unit_key)?
+            .map(|index| index.artifacts.clone())
+            .unwrap_or_default())
     }
 
     fn get_artifact(&self, unit_key: &[u8; 32], rel_path: &str) -> Result<Option<Vec<u8>>> {
-        let path = self.artifact_path(unit_key, rel_path);
+        let index = self.unit_index(unit_key)?;
+        let (path, codec) = match index.blobs.get(rel_path) {
+            Some(entry) => (self.blob_path(&entry.blob_name()), entry.codec),
+            None => (self.artifact_path(unit_key, rel_path), index.legacy_codec),
+        };
         match std::fs::read(&path) {
-            Ok(data) => Ok(Some(data)),
+            Ok(data) => Ok(Some(codec::decode(data, codec)?)),
             Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
             Err(e) => Err(e).with_context(|| format!("reading cached artifact {}", path.display())),
         }
     }
 
     fn put_artifact(&self, unit_key: &[u8; 32], rel_path: &str, data: &[u8]) -> Result<()> {
-        let path = self.artifact_path(unit_key, rel_path);
-        if let Some(parent) = path.parent() {
-            std::fs::create_dir_all(parent)?;
-        }
-        let tmp = path.with_extension("tmp");
-        std::fs::write(&tmp, data)
-            .with_context(|| format!("writing artifact {}", path.display()))?;
-        std::fs::rename(&tmp, &path)
-            .with_context(|| format!("renaming artifact {}", path.display()))?;
+        let hash = cas::hash_bytes(data);
+        let codec = self.store_blob(&hash, |tmp| {
+            std::fs::write(tmp, codec::encode(data, self.compression)?)
+                .with_context(|| format!("writing artifact {rel_path}"))
+        })?;
+        self.add_pending(unit_key, BlobEntry {
+            path: rel_path.to_string(),
+            hash,
+            size: data.len() as u64,
+            mode: 0o644,
+            codec,
+        });
         Ok(())
     }
 
     fn finalize_unit(&self, unit_key: &[u8; 32], artifacts: &[String]) -> Result<()> {
+        let stored = self.pending.lock().unwrap().remove(unit_key).unwrap_or_default();
+        let manifest = cas::manifest_for(artifacts, stored)?;
         let path = self.manifest_path(unit_key);
         if let Some(parent) = path.parent() {
             std::fs::create_dir_all(parent)?;
         }
-        let data = serde_json::to_vec(artifacts)?;
+        let data = serde_json::to_vec(&manifest)?;
         let tmp = path.with_extension("tmp");
         std::fs::write(&tmp, &data)?;
         std::fs::rename(&tmp, &path)?;
+        self.indexes
+            .lock()
+            .unwrap()
+            .insert(*unit_key, Arc::new(manifest.into_index()));
         Ok(())
     }
 
@@ -89,14 +333,12 @@ impl CacheBackend for FsCache {
         rel_path: &str,
         src: &Path,
     ) -> Result<()> {
-        let dest = self.artifact_path(unit_key, rel_path);
-        if let Some(parent) = dest.parent() {
-            std::fs::create_dir_all(parent)?;
-        }
-        let tmp = dest.with_extension("tmp");
-        copy_file_range_or_fallback(src, &tmp)?;
-        std::fs::rename(&tmp, &dest)
-            .with_context(|| format!("renaming artifact {}", dest.display()))?;
+        let (hash, size, mode) = cas::hash_file(src)?;
+        let codec = self.store_blob(&hash, |tmp| match self.compression.codec {
+            Codec::None => copy_file_range_or_fallback(src, tmp),
+            Codec::Zstd => codec::compress_file(src, tmp, self.compression),
+        })?;
+        self.add_pending(unit_key, BlobEntry { path: rel_path.to_string(), hash, size, mode, codec });
         Ok(())
     }
 
@@ -106,15 +348,66 @@ impl CacheBackend for FsCache {
         rel_path: &str,
         dest: &Path,
     ) -> Result<bool> {
+        let index = self.unit_index(unit_key)?;
+        remove_existing(dest)?;
+        if let Some(entry) = index.blobs.get(rel_path) {
+            return self.restore_blob(entry, dest);
+        }
         let src_path = self.artifact_path(unit_key, rel_path);
-        match copy_file_range_or_fallback(&src_path, dest) {
+        let copied = match index.legacy_codec {
+            Codec::None => copy_file_range_or_fallback(&src_path, dest),
+            Codec::Zstd => codec::decompress_file(&src_path, dest),
+        };
+        match copied {
             Ok(()) => Ok(true),
-            Err(e) if e.downcast_ref::<std::io::Error>()
-                .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound) => Ok(false),
+            Err(e) if is_not_found(&e) => Ok(false),
             Err(e) => Err(e),
         }
     }
 
+    #[cfg(feature = "io-uring")]
+    fn restore_artifacts(
+        &self,
+        unit_key: &[u8; 32],
+        files: &[(String, PathBuf)],
+    ) -> Result<Vec<bool>> {
+        if !self.io_uring {
+            return files
+                .iter()
+                .map(|(rel, dest)| self.restore_artifact(unit_key, rel, dest))
+                .collect();
+        }
+        // Plain copies go through the ring; hardlinks and decompression
+        // take the per-file path.
+        let index = self.unit_index(unit_key)?;
+        let mut found = vec![false; files.len()];
+        let mut pairs: Vec<(PathBuf, PathBuf)> = Vec::new();
+        let mut slots: Vec<(usize, Option<u32>)> = Vec::new();
+        for (i, (rel, dest)) in files.iter().enumerate() {
+            match index.blobs.get(rel) {
+                Some(entry) if entry.codec.is_none() && !self.hardlink => {
+                    remove_existing(dest)?;
+                    pairs.push((self.blob_path(&entry.blob_name()), dest.clone()));
+                    slots.push((i, Some(entry.mode)));
+                }
+                None if index.legacy_codec.is_none() => {
+                    remove_existing(dest)?;
+                    pairs.push((self.artifact_path(unit_key, rel), dest.clone()));
+                    slots.push((i, None));
+                }
+                _ => found[i] = self.restore_artifact(unit_key, rel, dest)?,
+            }
+        }
+        let copied = super::uring::copy_files(&pairs, copy_file_range_or_fallback)?;
+        for ((i, mode), ok) in slots.into_iter().zip(copied) {
+            found[i] = ok;
+            if ok && let Some(mode) = mode {
+                std::fs::set_permissions(&files[i].1, std::fs::Permissions::from_mode(mode))?;
+            }
+        }
+        Ok(found)
+    }
+
     fn list_dynamic_inputs(&self, static_key: &[u8; 32]) -> Result<Vec<DynamicInputs>> {
         let dir = self.dyn_dir(static_key);
         let entries = match std::fs::read_dir(&dir) {
@@ -131,11 +424,7 @@ impl CacheBackend for FsCache {
             if entry.path().extension().and_then(|s| s.to_str()) != Some("json") {
                 continue;
             }
-            let data = std::fs::read(entry.path())
-                .with_context(|| format!("reading {}", entry.path().display()))?;
-            let inputs: DynamicInputs = serde_json::from_slice(&data)
-                .with_context(|| format!("parsing {}", entry.path().display()))?;
-            out.push(inputs);
+            out.push(Self::read_dynamic_inputs(&entry.path())?);
         }
         Ok(out)
     }
@@ -145,13 +434,143 @@ impl CacheBackend for FsCache {
         std::fs::create_dir_all(&dir)
             .with_context(|| format!("creating {}", dir.display()))?;
         let path = dir.join(format!("{}.json", super::hex(&inputs.shape_hash())));
-        let data = serde_json::to_vec(inputs)?;
+        let existing = Self::read_dynamic_inputs(&path).ok();
+        let merged = super::merge_unit_keys(inputs, existing.as_ref());
+        let data = serde_json::to_vec(&merged)?;
         let tmp = path.with_extension("tmp");
         std::fs::write(&tmp, &data)?;
         std::fs::rename(&tmp, &path)?;
         Ok(())
     }
 
+    /// Bumps the manifests' mtimes; `list_units` reads them back as the access time.
+    fn touch_units(&self, unit_keys: &[[u8; 32]]) -> Result<()> {
+        let now = SystemTime::now();
+        for unit_key in unit_keys {
+            let path = self.manifest_path(unit_key);
+            match File::options().append(true).open(&path) {
+                Ok(f) => f
+                    .set_modified(now)
+                    .with_context(|| format!("touching {}", path.display()))?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e).with_context(|| format!("touching {}", path.display())),
+            }
+        }
+        Ok(())
+    }
+
+    fn list_units(&self) -> Result<Vec<UnitInfo>> {
+        let units_dir = self.root.join("units");
+        let entries = match std::fs::read_dir(&units_dir) {
+            Ok(e) => e,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", units_dir.display())),
+        };
+        let mut out = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name();
+            let Some(name) = name.to_str() else { continue };
+            if name.starts_with(".gc-") {
+                // Left behind by an interrupted gc; another gc may be mid-delete.
+                let _ = std::fs::remove_dir_all(entry.path());
+                continue;
+            }
+            let Some(key) = super::unhex(name) else { continue };
+            let last_access = match std::fs::metadata(self.manifest_path(&key)) {
+                Ok(m) => m.modified()?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    self.sweep_incomplete(&entry.path())?;
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let Some(index) = self.read_index(&key)? else { continue };
+            let blobs = index.blobs.values().map(|b| b.blob_name()).collect();
+            let size = walkdir::WalkDir::new(entry.path())
+                .into_iter()
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_type().is_file())
+                .filter_map(|e| e.metadata().ok())
+                .map(|m| m.len())
+                .sum();
+            out.push(UnitInfo { key, size, blobs, last_access });
+        }
+        Ok(out)
+    }
+
+    fn remove_unit(&self, unit_key: &[u8; 32]) -> Result<()> {
+        self.indexes.lock().unwrap().remove(unit_key);
+        remove_dir_atomically(&self.unit_dir(unit_key))
+    }
+
+    fn list_blobs(&self) -> Result<Vec<BlobInfo>> {
+        let blobs_dir = self.root.join("blobs");
+        let mut out = Vec::new();
+        for entry in walkdir::WalkDir::new(&blobs_dir).min_depth(2).max_depth(2) {
+            let entry = match entry {
+                Ok(e) => e,
+                Err(e) if e.io_error().is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound) => continue,
+                Err(e) => return Err(e).with_context(|| format!("reading {}", blobs_dir.display())),
+            };
+            let Some(name) = entry.file_name().to_str() else { continue };
+            let meta = entry.metadata()?;
+            let modified = meta.modified()?;
+            if name.starts_with(".tmp") {
+                // A store in progress, or one that died mid-write.
+                if modified.elapsed().unwrap_or_default() > INCOMPLETE_GRACE {
+                    let _ = std::fs::remove_file(entry.path());
+                }
+                continue;
+            }
+            out.push(BlobInfo { name: name.to_string(), size: meta.len(), modified });
+        }
+        Ok(out)
+    }
+
+    fn remove_blob(&self, name: &str) -> Result<()> {
+        let path = self.blob_path(name);
+        match std::fs::remove_file(&path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e).with_context(|| format!("removing {}", path.display())),
+        }
+    }
+
+    fn list_all_dynamic_inputs(&self) -> Result<Vec<([u8; 32], DynamicInputs)>> {
+        let dyn_root = self.root.join("dynamic");
+        let entries = match std::fs::read_dir(&dyn_root) {
+            Ok(e) => e,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", dyn_root.display())),
+        };
+        let mut out = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name();
+            let Some(static_key) = name.to_str().and_then(super::unhex) else {
+                continue;
+            };
+            for inputs in self.list_dynamic_inputs(&static_key)? {
+                out.push((static_key, inputs));
+            }
+        }
+        Ok(out)
+    }
+
+    fn remove_dynamic_inputs(&self, static_key: &[u8; 32], shape: &[u8; 32]) -> Result<()> {
+        let dir = self.dyn_dir(static_key);
+        let path = dir.join(format!("{}.json", super::hex(shape)));
+        match std::fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("removing {}", path.display())),
+        }
+        // Fails harmlessly if other shapes (or a concurrent put) remain.
+        let _ = std::fs::remove_dir(&dir);
+        Ok(())
+    }
+
     fn name(&self) -> &str {
         "fs"
     }
@@ -239,9 +658,70 @@ mod tests {
         );
     }
 
+    #[test]
+    fn compressed_round_trip() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let out = tempfile::tempdir().unwrap();
+        let zstd: Compression = "zstd:3".parse().unwrap();
+        let cache = FsCache::new(dir.path()).unwrap().with_compression(zstd);
+        let key = *blake3::hash(b"compressed").as_bytes();
+
+        let src = out.path().join("build-script-build");
+        let data = b"build script ".repeat(1000);
+        std::fs::write(&src, &data).unwrap();
+        std::fs::set_permissions(&src, std::fs::Permissions::from_mode(0o755)).unwrap();
+        cache.store_artifact_from_file(&key, "debug/build-script-build", &src).unwrap();
+        cache.finalize_unit(&key, &["debug/build-script-build".into()]).unwrap();
+
+        let blobs = cache.list_blobs().unwrap();
+        assert_eq!(blobs.len(), 1);
+        assert!(blobs[0].name.ends_with(".zst"));
+        assert!(blobs[0].size < data.len() as u64 / 10);
+
+        // A fresh, uncompressed instance learns the codec from the manifest.
+        let reader = FsCache::new(dir.path()).unwrap();
+        let dest = out.path().join("restored");
+        assert_eq!(reader.list_artifacts(&key).unwrap().len(), 1);
+        assert!(reader.restore_artifact(&key, "debug/build-script-build", &dest).unwrap());
+        assert_eq!(std::fs::read(&dest).unwrap(), data);
+        assert_eq!(std::fs::metadata(&dest).unwrap().permissions().mode() & 0o777, 0o755);
+        assert_eq!(reader.get_artifact(&key, "debug/build-script-build").unwrap().unwrap(), data);
+    }
+
+    #[test]
+    fn identical_contents_share_a_blob() {
+        use std::os::unix::fs::MetadataExt;
+        let dir = tempfile::tempdir().unwrap();
+        let out = tempfile::tempdir().unwrap();
+        let cache = FsCache::new(dir.path()).unwrap().with_hardlinks(true);
+        let a = *blake3::hash(b"a").as_bytes();
+        let b = *blake3::hash(b"b").as_bytes();
+        for key in [&a, &b] {
+            cache.put_artifact(key, "debug/out/gen.rs", b"pub const X: u32 = 1;").unwrap();
+            cache.finalize_unit(key, &["debug/out/gen.rs".into()]).unwrap();
+        }
+        let blobs = cache.list_blobs().unwrap();
+        assert_eq!(blobs.len(), 1);
+
+        let dest = out.path().join("gen.rs");
+        assert!(cache.restore_artifact(&a, "debug/out/gen.rs", &dest).unwrap());
+        let blob = cache.blob_path(&blobs[0].name);
+        assert_eq!(std::fs::metadata(&dest).unwrap().ino(), std::fs::metadata(&blob).unwrap().ino());
+
+        // A copying restore over the hardlink must not write through it.
+        let copier = FsCache::new(dir.path()).unwrap();
+        std::fs::write(out.path().join("other"), b"other").unwrap();
+        copier.store_artifact_from_file(&b, "debug/out/gen.rs", &out.path().join("other")).unwrap();
+        copier.finalize_unit(&b, &["debug/out/gen.rs".into()]).unwrap();
+        assert!(copier.restore_artifact(&b, "debug/out/gen.rs", &dest).unwrap());
+        assert_eq!(std::fs::read(&dest).unwrap(), b"other");
+        assert_eq!(std::fs::read(&blob).unwrap(), b"pub const X: u32 = 1;");
+    }
+
     #[test]
     fn dynamic_inputs_round_trip() {
-        use crate::cache::{DynEnv, DynPath};
+        use crate::cache::{Cutoff, DynEnv, DynPath};
         let dir = tempfile::tempdir().unwrap();
         let cache = FsCache::new(dir.path()).unwrap();
         let static_key = *blake3::hash(b"static").as_bytes();
@@ -254,10 +734,12 @@ mod tests {
                 DynPath { path: "/bar".into(), stored_hash: [2; 32] },
             ],
             envs: vec![DynEnv { name: "X".into(), stored_value: Some("1".into()) }],
+            ..Default::default()
         };
         let inputs_b = DynamicInputs {
             paths: vec![DynPath { path: "/foo".into(), stored_hash: [3; 32] }],
             envs: vec![],
+            ..Default::default()
         };
         cache.put_dynamic_inputs(&static_key, &inputs_a).unwrap();
         cache.put_dynamic_inputs(&static_key, &inputs_b).unwrap();
@@ -265,5 +747,50 @@ mod tests {
 
         let listed = cache.list_dynamic_inputs(&static_key).unwrap();
         assert_eq!(listed.len(), 2);
+
+        // Cutoff keys accumulate across overwrites; a re-recorded key points
+        // at the newest bundle.
+        let cutoff = |key: u8, unit_key: u8| Cutoff { key: [key; 32], unit_key: [unit_key; 32] };
+        for cutoffs in [vec![cutoff(1, 10), cutoff(2, 20)], vec![cutoff(1, 11)]] {
+            let inputs = DynamicInputs { cutoffs, ..inputs_b.clone() };
+            cache.put_dynamic_inputs(&static_key, &inputs).unwrap();
+        }
+        let listed = cache.list_dynamic_inputs(&static_key).unwrap();
+        let b = listed.iter().find(|i| i.envs.is_empty()).unwrap();
+        assert_eq!(b.cutoffs, [cutoff(1, 11), cutoff(2, 20)]);
+    }
+
+    #[cfg(feature = "io-uring")]
+    #[test]
+    fn io_uring_restore_batch() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let out = tempfile::tempdir().unwrap();
+        let cache = FsCache::new(dir.path()).unwrap().with_io_uring(true);
+        let key = *blake3::hash(b"unit").as_bytes();
+
+        let exe = dir.path().join("build-script-build");
+        std::fs::write(&exe, b"#!/bin/sh").unwrap();
+        std::fs::set_permissions(&exe, std::fs::Permissions::from_mode(0o755)).unwrap();
+        cache.store_artifact_from_file(&key, "debug/build-script-build", &exe).unwrap();
+        cache.put_artifact(&key, "debug/libfoo.rlib", &vec![7u8; 300 * 1024]).unwrap();
+        cache.put_artifact(&key, "debug/empty", b"").unwrap();
+        cache.finalize_unit(&key, &[
+            "debug/build-script-build".into(),
+            "debug/libfoo.rlib".into(),
+            "debug/empty".into(),
+        ]).unwrap();
+
+        let files: Vec<(String, PathBuf)> = ["debug/build-script-build", "debug/libfoo.rlib", "debug/empty", "debug/gone"]
+            .iter()
+            .map(|rel| (rel.to_string(), out.path().join(rel.replace('/', "_"))))
+            .collect();
+        let found = cache.restore_artifacts(&key, &files).unwrap();
+        assert_eq!(found, vec![true, true, true, false]);
+        assert_eq!(std::fs::read(&files[0].1).unwrap(), b"#!/bin/sh");
+        let mode = std::fs::metadata(&files[0].1).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o755);
+        assert_eq!(std::fs::read(&files[1].1).unwrap().len(), 300 * 1024);
+        assert!(std::fs::read(&files[2].1).unwrap().is_empty());
     }
 }
@@ -0,0 +1,507 @@
+//! Remote cache over plain HTTP: GET/PUT/HEAD on a fixed key layout under a
+//! base URL, so any server that stores what it's PUT works (nginx WebDAV,
+//! bazel-remote, a test server):
+//!
+//! - `units/<hex>/manifest` — the bundle's blob entries (see `cas`)
+//! - `blobs/<hash>[.zst]` — artifact contents, uploaded once (HEAD first)
+//! - `dynamic/<static>/<shape>` — one `DynamicInputs` manifest
+//! - `dynamic/<static>/index` — JSON list of shape hashes under `<static>`
+//!
+//! Plain HTTP has no listing, hence the per-static_key index. Updating it is
+//! read-modify-write; two builds racing on the same static_key can drop one
+//! shape from the index, which costs a miss, never a wrong hit.
+//!
+//! HTTP drops file modes; restore takes them from the manifest entries.
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use super::cas::{self, BlobEntry, UnitIndex, UnitManifest};
+use super::codec::{self, Codec, Compression};
+use super::{CacheBackend, DynamicInputs};
+
+/// Connection settings for [`HttpCache`].
+#[derive(Debug, Clone)]
+pub struct HttpConfig {
+    /// e.g. `https://cache.example.com/cargo-zb`. Keys are appended as paths.
+    pub base_url: String,
+    /// Extra header sent with every request, as `Name: value`
+    /// (e.g. `Authorization: Bearer ...`).
+    pub auth_header: Option<String>,
+    /// Connect timeout, and the longest a read or write may stall.
+    pub timeout: Duration,
+}
+
+/// Enough for every unit of a large build restoring in parallel.
+const MAX_INDEXES: usize = 4096;
+
+pub struct HttpCache {
+    agent: ureq::Agent,
+    base_url: String,
+    auth: Option<(String, String)>,
+    compression: Compression,
+    /// Blob entries uploaded since each unit's last `finalize_unit`.
+    pending: Mutex<HashMap<[u8; 32], HashMap<String, BlobEntry>>>,
+    /// Manifests fetched by `list_artifacts`, consulted per file on restore.
+    /// Cleared once it holds `MAX_INDEXES`; a dropped one is refetched.
+    indexes: Mutex<HashMap<[u8; 32], Arc<UnitIndex>>>,
+}
+
+impl HttpCache {
+    pub fn new(config: HttpConfig) -> Result<Self> {
+        let auth = match &config.auth_header {
+            Some(h) => {
+                let (name, value) = h
+                    .split_once(':')
+                    .with_context(|| format!("auth header {h:?} is not `Name: value`"))?;
+                Some((name.trim().to_string(), value.trim().to_string()))
+            }
+            None => None,
+        };
+        let agent = ureq::AgentBuilder::new()
+            .timeout_connect(config.timeout)
+            .timeout_read(config.timeout)
+            .timeout_write(config.timeout)
+            .build();
+        Ok(Self {
+            agent,
+            base_url: config.base_url.trim_end_matches('/').to_string(),
+            auth,
+            compression: Compression::default(),
+            pending: Mutex::new(HashMap::new()),
+            indexes: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Compress artifacts uploaded from now on.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    fn unit_url(&self, unit_key: &[u8; 32], suffix: &str) -> String {
+        format!("{}/units/{}/{}", self.base_url, super::hex(unit_key), suffix)
+    }
+
+    fn artifact_url(&self, unit_key: &[u8; 32], rel_path: &str) -> String {
+        self.unit_url(unit_key, &format!("artifacts/{}", encode_path(rel_path)))
+    }
+
+    fn blob_url(&self, name: &str) -> String {
+        format!("{}/blobs/{}", self.base_url, name)
+    }
+
+    fn dyn_url(&self, static_key: &[u8; 32], leaf: &str) -> String {
+        format!("{}/dynamic/{}/{}", self.base_url, super::hex(static_key), leaf)
+    }
+
+    fn request(&self, method: &str, url: &str) -> ureq::Request {
+        let req = self.agent.request(method, url);
+        match &self.auth {
+            Some((name, value)) => req.set(name, value),
+            None => req,
+        }
+    }
+
+    /// GET `url`; `Ok(None)` on 404.
+    fn get(&self, url: &str) -> Result<Option<ureq::Response>> {
+        match self.request("GET", url).call() {
+            Ok(resp) => Ok(Some(resp)),
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("GET {url}")),
+        }
+    }
+
+    fn get_bytes(&self, url: &str) -> Result<Option<Vec<u8>>> {
+        let Some(resp) = self.get(url)? else {
+            return Ok(None);
+        };
+        let mut data = Vec::new();
+        resp.into_reader()
+            .read_to_end(&mut data)
+            .with_context(|| format!("reading body of {url}"))?;
+        Ok(Some(data))
+    }
+
+    /// HEAD `url`: whether it exists.
+    fn exists(&self, url: &str) -> Result<bool> {
+        match self.request("HEAD", url).call() {
+            Ok(_) => Ok(true),
+            Err(ureq::Error::Status(404, _)) => Ok(false),
+            Err(e) => Err(e).with_context(|| format!("HEAD {url}")),
+        }
+    }
+
+    fn put_bytes(&self, url: &str, data: &[u8]) -> Result<()> {
+        self.request("PUT", url)
+            .send_bytes(data)
+            .with_context(|| format!("PUT {url}"))?;
+        Ok(())
+    }
+
+    fn read_index(&self, unit_key: &[u8; 32]) -> Result<Option<Arc<UnitIndex>>> {
+        let Some(data) = self.get_bytes(&self.unit_url(unit_key, "manifest"))? else {
+            return Ok(None);
+        };
+        let manifest: UnitManifest = serde_json::from_slice(&data).context("parsing unit manifest")?;
+        let index = Arc::new(manifest.into_index());
+        let mut indexes = self.indexes.lock().unwrap();
+        if indexes.len() >= MAX_INDEXES {
+            indexes.clear();
+        }
+        indexes.insert(*unit_key, Arc::clone(&index));
+        Ok(Some(index))
+    }
+
+    fn unit_index(&self, unit_key: &[u8; 32]) -> Result<Arc<UnitIndex>> {
+        if let Some(index) = self.indexes.lock().unwrap().get(unit_key) {
+            return Ok(Arc::clone(index));
+        }
+        Ok(self.read_index(unit_key)?.unwrap_or_default())
+    }
+
+    /// URL and codec of one artifact, blob or legacy per-unit upload.
+    fn locate(&self, unit_key: &[u8; 32], rel_path: &str) -> Result<(String, Codec, Option<u32>)> {
+        let index = self.unit_index(unit_key)?;
+        Ok(match index.blobs.get(rel_path) {
+            Some(entry) => (self.blob_url(&entry.blob_name()), entry.codec, Some(entry.mode)),
+            None => (self.artifact_url(unit_key, rel_path), index.legacy_codec, None),
+        })
+    }
+
+    /// Upload a blob unless the server already has it; `body` produces the
+    /// encoded content.
+    fn upload_blob(&self, hash: &str, body: impl FnOnce(&str) -> Result<()>) -> Result<()> {
+        let url = self.blob_url(&cas::blob_name(hash, self.compression.codec));
+        if !self.exists(&url)? {
+            body(&url)?;
+        }
+        Ok(())
+    }
+
+    /// Upload a blob and record it for the unit's manifest. A unit whose
+    /// upload fails is never finalized, so its entries are dropped.
+    fn store_blob(
+        &self,
+        unit_key: &[u8; 32],
+        entry: BlobEntry,
+        body: impl FnOnce(&str) -> Result<()>,
+    ) -> Result<()> {
+        if let Err(e) = self.upload_blob(&entry.hash, body) {
+            self.pending.lock().unwrap().remove(unit_key);
+            return Err(e);
+        }
+        self.add_pending(unit_key, entry);
+        Ok(())
+    }
+
+    fn add_pending(&self, unit_key: &[u8; 32], entry: BlobEntry) {
+        self.pending
+            .lock()
+            .unwrap()
+            .entry(*unit_key)
+            .or_default()
+            .insert(entry.path.clone(), entry);
+    }
+
+    fn shape_index(&self, static_key: &[u8; 32]) -> Result<Vec<String>> {
+        match self.get_bytes(&self.dyn_url(static_key, "index"))? {
+            Some(data) => Ok(serde_json::from_slice(&data).context("parsing dynamic index")?),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+impl CacheBackend for HttpCache {
+    fn contains_unit(&self, unit_key: &[u8; 32]) -> Result<bool> {
+        self.exists(&self.unit_url(unit_key, "manifest"))
+    }
+
+    fn list_artifacts(&self, unit_key: &[u8; 32]) -> Result<Vec<String>> {
+        Ok(self
+            .read_index(unit_key)?
+            .map(|index| index.artifacts.clone())
+            .unwrap_or_default())
+    }
+
+    fn get_artifact(&self, unit_key: &[u8; 32], rel_path: &str) -> Result<Option<Vec<u8>>> {
+        let (url, codec, _) = self.locate(unit_key, rel_path)?;
+        match self.get_bytes(&url)? {
+            Some(data) => Ok(Some(codec::decode(data, codec)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn restore_artifact(&self, unit_key: &[u8; 32], rel_path: &str, dest: &Path) -> Result<bool> {
+        let (url, codec, mode) = self.locate(unit_key, rel_path)?;
+        let Some(resp) = self.get(&url)? else {
+            return Ok(false);
+        };
+        match codec {
+            Codec::None => {
+                let mut file = std::fs::File::create(dest)
+                    .with_context(|| format!("creating {}", dest.display()))?;
+                std::io::copy(&mut resp.into_reader(), &mut file)
+                    .with_context(|| format!("downloading {url}"))?;
+            }
+            Codec::Zstd => codec::decompress_to(resp.into_reader(), dest)
+                .with_context(|| format!("downloading {url}"))?,
+        }
+        if let Some(mode) = mode {
+            std::fs::set_permissions(dest, std::fs::Permissions::from_mode(mode))?;
+        }
+        Ok(true)
+    }
+
+    fn put_artifact(&self, unit_key: &[u8; 32], rel_path: &str, data: &[u8]) -> Result<()> {
+        let entry = BlobEntry {
+            path: rel_path.to_string(),
+            hash: cas::hash_bytes(data),
+            size: data.len() as u64,
+            mode: 0o644,
+            codec: self.compression.codec,
+        };
+        self.store_blob(unit_key, entry, |url| self.put_bytes(url, &codec::encode(data, self.compression)?))
+    }
+
+    fn store_artifact_from_file(&self, unit_key: &[u8; 32], rel_path: &str, src: &Path) -> Result<()> {
+        let (hash, size, mode) = cas::hash_file(src)?;
+        let entry = BlobEntry { path: rel_path.to_string(), hash, size, mode, codec: self.compression.codec };
+        self.store_blob(unit_key, entry, |url| {
+            let file = std::fs::File::open(src)
+                .with_context(|| format!("opening {}", src.display()))?;
+            match self.compression.codec {
+                Codec::None => {
+                    self.request("PUT", url)
+                        .set("Content-Length", &size.to_string())
+                        .send(file)
+                        .with_context(|| format!("PUT {url}"))?;
+                    Ok(())
+                }
+                Codec::Zstd => {
+                    // Buffered so the PUT carries a Content-Length; plain object
+                    // stores often reject chunked uploads.
+                    let data = zstd::stream::encode_all(std::io::BufReader::new(file), self.compression.level)
+                        .with_context(|| format!("compressing {}", src.display()))?;
+                    self.put_bytes(url, &data)
+                }
+            }
+        })
+    }
+
+    fn finalize_unit(&self, unit_key: &[u8; 32], artifacts: &[String]) -> Result<()> {
+        let stored = self.pending.lock().unwrap().remove(unit_key).unwrap_or_default();
+        let manifest = cas::manifest_for(artifacts, stored)?;
+        // Written last: HEAD on the manifest is what makes the unit visible.
+        self.put_bytes(&self.unit_url(unit_key, "manifest"), &serde_json::to_vec(&manifest)?)
+    }
+
+    fn list_dynamic_inputs(&self, static_key: &[u8; 32]) -> Result<Vec<DynamicInputs>> {
+        let mut out = Vec::new();
+        for shape in self.shape_index(static_key)? {
+            if let Some(data) = self.get_bytes(&self.dyn_url(static_key, &shape))? {
+                out.push(serde_json::from_slice(&data).context("parsing dynamic inputs")?);
+            }
+        }
+        Ok(out)
+    }
+
+    fn put_dynamic_inputs(&self, static_key: &[u8; 32], inputs: &DynamicInputs) -> Result<()> {
+        let shape = super::hex(&inputs.shape_hash());
+        let url = self.dyn_url(static_key, &shape);
+        let existing: Option<DynamicInputs> = self
+            .get_bytes(&url)?
+            .and_then(|d| serde_json::from_slice(&d).ok());
+        let merged = super::merge_unit_keys(inputs, existing.as_ref());
+        self.put_bytes(&url, &serde_json::to_vec(&merged)?)?;
+
+        let mut index = self.shape_index(static_key)?;
+        if !index.contains(&shape) {
+            index.push(shape);
+            self.put_bytes(&self.dyn_url(static_key, "index"), &serde_json::to_vec(&index)?)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        // Whatever is still pending belongs to units that were never finalized.
+        self.pending.lock().unwrap().clear();
+        self.indexes.lock().unwrap().clear();
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "http"
+    }
+}
+
+/// Percent-encode a relative artifact path for use in a URL, keeping `/`.
+fn encode_path(rel: &str) -> String {
+    let mut out = String::with_capacity(rel.len());
+    for b in rel.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                out.push(b as char)
+            }
+            _ => out.push_str(&format!("%{b:02X}")),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    /// In-process object store: PUT stores, GET/HEAD serve, 404 otherwise.
+    /// Rejects requests without the expected auth header.
+    fn spawn_server() -> String {
+        let server = Arc::new(tiny_http::Server::http("127.0.0.1:0").unwrap());
+        let addr = server.server_addr().to_ip().unwrap();
+        let objects: Arc<Mutex<HashMap<String, Vec<u8>>>> = Arc::default();
+        std::thread::spawn(move || {
+            for mut req in server.incoming_requests() {
+                let authed = req
+                    .headers()
+                    .iter()
+                    .any(|h| h.field.equiv("X-Token") && h.value.as_str() == "secret");
+                let url = req.url().to_string();
+                let resp = if !authed {
+                    tiny_http::Response::from_data(Vec::new()).with_status_code(401)
+                } else {
+                    match req.method() {
+                        tiny_http::Method::Put => {
+                            let mut body = Vec::new();
+                            req.as_reader().read_to_end(&mut body).unwrap();
+                            objects.lock().unwrap().insert(url, body);
+                            tiny_http::Response::from_data(Vec::new()).with_status_code(201)
+                        }
+                        _ => match objects.lock().unwrap().get(&url) {
+                            Some(body) => tiny_http::Response::from_data(body.clone()),
+                            None => tiny_http::Response::from_data(Vec::new()).with_status_code(404),
+                        },
+                    }
+                };
+                let _ = req.respond(resp);
+            }
+        });
+        format!("http://{addr}/cache")
+    }
+
+    fn client(base_url: String) -> HttpCache {
+        HttpCache::new(HttpConfig {
+            base_url,
+            auth_header: Some("X-Token: secret".into()),
+            timeout: Duration::from_secs(5),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let cache = client(spawn_server());
+        let key = *blake3::hash(b"test-unit").as_bytes();
+
+        assert!(!cache.contains_unit(&key).unwrap());
+        assert!(cache.list_artifacts(&key).unwrap().is_empty());
+
+        let src_dir = tempfile::tempdir().unwrap();
+        let exe = src_dir.path().join("build-script-build");
+        std::fs::write(&exe, b"#!/bin/sh").unwrap();
+        std::fs::set_permissions(&exe, std::fs::Permissions::from_mode(0o755)).unwrap();
+        cache.store_artifact_from_file(&key, "debug/build/foo-1/build-script-build", &exe).unwrap();
+        cache.put_artifact(&key, "debug/deps/lib foo.rlib", b"rlib data").unwrap();
+        cache.finalize_unit(&key, &[
+            "debug/build/foo-1/build-script-build".into(),
+            "debug/deps/lib foo.rlib".into(),
+        ]).unwrap();
+
+        assert!(cache.contains_unit(&key).unwrap());
+        let reader = client(cache.base_url.clone());
+        let artifacts = reader.list_artifacts(&key).unwrap();
+        assert_eq!(artifacts.len(), 2);
+        assert_eq!(
+            reader.get_artifact(&key, "debug/deps/lib foo.rlib").unwrap().unwrap(),
+            b"rlib data"
+        );
+
+        let out = tempfile::tempdir().unwrap();
+        let dest = out.path().join("build-script-build");
+        assert!(reader.restore_artifact(&key, &artifacts[0], &dest).unwrap());
+        let mode = std::fs::metadata(&dest).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o755);
+        assert!(!reader.restore_artifact(&key, "debug/missing", &dest).unwrap());
+    }
+
+    #[test]
+    fn compressed_round_trip() {
+        let cache = client(spawn_server()).with_compression("zstd:1".parse().unwrap());
+        let key = *blake3::hash(b"compressed").as_bytes();
+        let data = b"debuginfo ".repeat(1000);
+
+        let src_dir = tempfile::tempdir().unwrap();
+        let src = src_dir.path().join("foo");
+        std::fs::write(&src, &data).unwrap();
+        cache.store_artifact_from_file(&key, "debug/foo", &src).unwrap();
+        cache.finalize_unit(&key, &["debug/foo".into()]).unwrap();
+
+        // Restore without listing first: the manifest is fetched on demand.
+        let reader = client(cache.base_url.clone());
+        let dest = src_dir.path().join("restored");
+        assert!(reader.restore_artifact(&key, "debug/foo", &dest).unwrap());
+        assert_eq!(std::fs::read(&dest).unwrap(), data);
+        assert_eq!(reader.get_artifact(&key, "debug/foo").unwrap().unwrap(), data);
+    }
+
+    #[test]
+    fn dynamic_inputs_round_trip() {
+        use crate::cache::DynPath;
+        let cache = client(spawn_server());
+        let static_key = *blake3::hash(b"static").as_bytes();
+
+        assert!(cache.list_dynamic_inputs(&static_key).unwrap().is_empty());
+
+        let inputs_a = DynamicInputs {
+            paths: vec![DynPath { path: "/a".into(), stored_hash: [1; 32] }],
+            unit_keys: vec![[9; 32]],
+            ..Default::default()
+        };
+        let inputs_b = DynamicInputs {
+            paths: vec![DynPath { path: "/b".into(), stored_hash: [2; 32] }],
+            ..Default::default()
+        };
+        cache.put_dynamic_inputs(&static_key, &inputs_a).unwrap();
+        cache.put_dynamic_inputs(&static_key, &inputs_b).unwrap();
+        cache.put_dynamic_inputs(&static_key, &DynamicInputs { unit_keys: vec![[8; 32]], ..inputs_a.clone() }).unwrap();
+
+        let listed = cache.list_dynamic_inputs(&static_key).unwrap();
+        assert_eq!(listed.len(), 2);
+        let a = listed.iter().find(|i| i.paths[0].path == Path::new("/a")).unwrap();
+        assert_eq!(a.unit_keys.len(), 2);
+    }
+
+    #[test]
+    fn rejected_auth_is_an_error() {
+        let cache = HttpCache::new(HttpConfig {
+            base_url: spawn_server(),
+            auth_header: None,
+            timeout: Duration::from_secs(5),
+        })
+        .unwrap();
+        assert!(cache.contains_unit(&[0; 32]).is_err());
+        assert!(cache.put_artifact(&[0; 32], "debug/a", b"a").is_err());
+        assert!(cache.pending.lock().unwrap().is_empty());
+
+        // On its own the backend is wrapped, so the build sees a miss.
+        let remote = crate::cache::remote::RemoteCache::new(Box::new(cache));
+        assert!(!remote.contains_unit(&[0; 32]).unwrap());
+        assert!(!remote.is_down());
+    }
+}
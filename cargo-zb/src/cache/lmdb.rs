@@ -1,18 +1,38 @@
+use std::collections::HashMap;
+use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::{Context, Result};
 use heed::types::Bytes;
 use heed::{Database, EnvOpenOptions};
 
-use super::{CacheBackend, DynamicInputs};
+use super::cas::{self, BlobEntry, BlobInfo, UnitIndex, UnitManifest};
+use super::codec::{self, Codec, Compression};
+use super::{CacheBackend, DynamicInputs, UnitInfo};
 
+/// Keys: `m:<unit>` manifest, `t:<unit>` access time, `b:<blob>` blob
+/// contents (see `cas`), `r:<blob>` time of the last manifest referencing
+/// it, `d:<static>:<shape>` dynamic inputs. Units stored before the blob
+/// store keep their artifacts under `a:<unit>:<rel>`.
 pub struct LmdbCache {
     env: heed::Env,
     db: Database<Bytes, Bytes>,
+    /// New blobs, written with the next `finalize_unit`'s transaction.
     pending: Mutex<Vec<(Vec<u8>, Vec<u8>)>>,
+    /// Blob entries stored since each unit's last `finalize_unit`.
+    entries: Mutex<HashMap<[u8; 32], HashMap<String, BlobEntry>>>,
+    /// Parsed manifests, so restores don't re-parse them per file.
+    indexes: Mutex<HashMap<[u8; 32], Arc<UnitIndex>>>,
+    /// Each blob's `r:` time as `list_blobs` last reported it.
+    listed: Mutex<HashMap<String, [u8; 8]>>,
+    compression: Compression,
 }
 
+/// Stored bytes, their codec and (for blobs) the recorded mode.
+type StoredArtifact<'t> = (&'t [u8], Codec, Option<u32>);
+
 impl LmdbCache {
     pub fn open(path: &Path, max_size: Option<usize>) -> Result<Self> {
         std::fs::create_dir_all(path)
@@ -36,9 +56,106 @@ impl LmdbCache {
             env,
             db,
             pending: Mutex::new(Vec::new()),
+            entries: Mutex::new(HashMap::new()),
+            indexes: Mutex::new(HashMap::new()),
+            listed: Mutex::new(HashMap::new()),
+            compression: Compression::default(),
         })
     }
 
+    /// Compress artifacts stored from now on. Existing blobs keep the
+    /// codec they were written with.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    fn read_index(&self, rtxn: &heed::RoTxn, unit_key: &[u8; 32]) -> Result<Option<Arc<UnitIndex>>> {
+        let Some(data) = self.db.get(rtxn, &Self::unit_manifest_key(unit_key))? else {
+            return Ok(None);
+        };
+        let index = Arc::new(serde_json::from_slice::<UnitManifest>(data)?.into_index());
+        self.indexes.lock().unwrap().insert(*unit_key, Arc::clone(&index));
+        Ok(Some(index))
+    }
+
+    fn unit_index(&self, rtxn: &heed::RoTxn, unit_key: &[u8; 32]) -> Result<Arc<UnitIndex>> {
+        if let Some(index) = self.indexes.lock().unwrap().get(unit_key) {
+            return Ok(Arc::clone(index));
+        }
+        Ok(self.read_index(rtxn, unit_key)?.unwrap_or_default())
+    }
+
+    /// The stored bytes and codec for one artifact, blob or legacy.
+    fn artifact_data<'t>(
+        &self,
+        rtxn: &'t heed::RoTxn,
+        unit_key: &[u8; 32],
+        rel_path: &str,
+    ) -> Result<Option<StoredArtifact<'t>>> {
+        let index = self.unit_index(rtxn, unit_key)?;
+        let (key, codec, mode) = match index.blobs.get(rel_path) {
+            Some(entry) => (Self::blob_key(&entry.blob_name()), entry.codec, Some(entry.mode)),
+            None => (Self::artifact_key(unit_key, rel_path), index.legacy_codec, None),
+        };
+        Ok(self.db.get(rtxn, &key)?.map(|data| (data, codec, mode)))
+    }
+
+    fn blob_key(name: &str) -> Vec<u8> {
+        let mut k = Vec::with_capacity(2 + name.len());
+        k.extend_from_slice(b"b:");
+        k.extend_from_slice(name.as_bytes());
+        k
+    }
+
+    /// Reference an existing blob for `data` or queue a new one.
+    fn put_blob(&self, unit_key: &[u8; 32], rel_path: &str, data: &[u8], mode: u32) -> Result<()> {
+        let hash = cas::hash_bytes(data);
+        let preferred = self.compression.codec;
+        let other = if preferred.is_none() { Codec::Zstd } else { Codec::None };
+        let existing = {
+            let rtxn = self.env.read_txn()?;
+            let mut found = None;
+            for codec in [preferred, other] {
+                if self.db.get(&rtxn, &Self::blob_key(&cas::blob_name(&hash, codec)))?.is_some() {
+                    found = Some(codec);
+                    break;
+                }
+            }
+            found
+        };
+        let codec = match existing {
+            Some(codec) => codec,
+            None => {
+                let key = Self::blob_key(&cas::blob_name(&hash, preferred));
+                let data = codec::encode(data, self.compression)?;
+                self.pending.lock().unwrap().push((key, data));
+                preferred
+            }
+        };
+        let entry = BlobEntry {
+            path: rel_path.to_string(),
+            hash,
+            size: data.len() as u64,
+            mode,
+            codec,
+        };
+        self.entries
+            .lock()
+            .unwrap()
+            .entry(*unit_key)
+            .or_default()
+            .insert(entry.path.clone(), entry);
+        Ok(())
+    }
+
+    fn reuse_key(name: &str) -> Vec<u8> {
+        let mut k = Vec::with_capacity(2 + name.len());
+        k.extend_from_slice(b"r:");
+        k.extend_from_slice(name.as_bytes());
+        k
+    }
+
     fn unit_manifest_key(unit_key: &[u8; 32]) -> Vec<u8> {
         let hex = super::hex(unit_key);
         let mut k = Vec::with_capacity(2 + 64);
@@ -47,6 +164,23 @@ impl LmdbCache {
         k
     }
 
+    fn access_key(unit_key: &[u8; 32]) -> Vec<u8> {
+        let hex = super::hex(unit_key);
+        let mut k = Vec::with_capacity(2 + 64);
+        k.extend_from_slice(b"t:");
+        k.extend_from_slice(hex.as_bytes());
+        k
+    }
+
+    fn artifact_prefix(unit_key: &[u8; 32]) -> Vec<u8> {
+        let hex = super::hex(unit_key);
+        let mut k = Vec::with_capacity(2 + 64 + 1);
+        k.extend_from_slice(b"a:");
+        k.extend_from_slice(hex.as_bytes());
+        k.push(b':');
+        k
+    }
+
     fn artifact_key(unit_key: &[u8; 32], rel_path: &str) -> Vec<u8> {
         let hex = super::hex(unit_key);
         let mut k = Vec::with_capacity(2 + 64 + 1 + rel_path.len());
@@ -76,6 +210,14 @@ impl LmdbCache {
         k.push(b':');
         k
     }
+
+    fn now_secs() -> [u8; 8] {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        secs.to_le_bytes()
+    }
 }
 
 impl CacheBackend for LmdbCache {
@@ -87,18 +229,16 @@ impl CacheBackend for LmdbCache {
 
     fn list_artifacts(&self, unit_key: &[u8; 32]) -> Result<Vec<String>> {
         let rtxn = self.env.read_txn()?;
-        let key = Self::unit_manifest_key(unit_key);
-        match self.db.get(&rtxn, &key)? {
-            Some(data) => Ok(serde_json::from_slice(data)?),
-            None => Ok(Vec::new()),
-        }
+        Ok(self
+            .read_index(&rtxn, unit_key)?
+            .map(|index| index.artifacts.clone())
+            .unwrap_or_default())
     }
 
     fn get_artifact(&self, unit_key: &[u8; 32], rel_path: &str) -> Result<Option<Vec<u8>>> {
         let rtxn = self.env.read_txn()?;
-        let key = Self::artifact_key(unit_key, rel_path);
-        match self.db.get(&rtxn, &key)? {
-            Some(data) => Ok(Some(data.to_vec())),
+        match self.artifact_data(&rtxn, unit_key, rel_path)? {
+            Some((data, codec, _)) => Ok(Some(codec::decode(data.to_vec(), codec)?)),
             None => Ok(None),
         }
     }
@@ -110,32 +250,64 @@ impl CacheBackend for LmdbCache {
         dest: &std::path::Path,
     ) -> Result<bool> {
         let rtxn = self.env.read_txn()?;
-        let key = Self::artifact_key(unit_key, rel_path);
-        match self.db.get(&rtxn, &key)? {
-            Some(data) => {
-                std::fs::write(dest, data)?;
-                Ok(true)
-            }
-            None => Ok(false),
+        let Some((data, codec, mode)) = self.artifact_data(&rtxn, unit_key, rel_path)? else {
+            return Ok(false);
+        };
+        match codec {
+            Codec::None => std::fs::write(dest, data)?,
+            Codec::Zstd => codec::decompress_to(data, dest)?,
+        }
+        if let Some(mode) = mode {
+            std::fs::set_permissions(dest, std::fs::Permissions::from_mode(mode))?;
         }
+        Ok(true)
     }
 
     fn put_artifact(&self, unit_key: &[u8; 32], rel_path: &str, data: &[u8]) -> Result<()> {
-        let key = Self::artifact_key(unit_key, rel_path);
-        self.pending.lock().unwrap().push((key, data.to_vec()));
-        Ok(())
+        self.put_blob(unit_key, rel_path, data, 0o644)
+    }
+
+    fn store_artifact_from_file(&self, unit_key: &[u8; 32], rel_path: &str, src: &Path) -> Result<()> {
+        let data = std::fs::read(src).with_context(|| format!("reading {}", src.display()))?;
+        let mode = std::fs::metadata(src)?.permissions().mode();
+        self.put_blob(unit_key, rel_path, &data, mode)
     }
 
     fn finalize_unit(&self, unit_key: &[u8; 32], artifacts: &[String]) -> Result<()> {
+        let stored = self.entries.lock().unwrap().remove(unit_key).unwrap_or_default();
+        let manifest = cas::manifest_for(artifacts, stored)?;
         let pending = std::mem::take(&mut *self.pending.lock().unwrap());
         let mut wtxn = self.env.write_txn()?;
         for (key, data) in &pending {
-            self.db.put(&mut wtxn, key, data)?;
+            if self.db.get(&wtxn, key)?.is_none() {
+                self.db.put(&mut wtxn, key, data)?;
+            }
+        }
+        // A blob we found at store time may have been collected since; skip
+        // caching the unit this time rather than record a dangling manifest.
+        // The rest are marked reused, for a gc that listed them before now.
+        let now = Self::now_secs();
+        if let UnitManifest::Blobs { blobs } = &manifest {
+            for entry in blobs {
+                if self.db.get(&wtxn, &Self::blob_key(&entry.blob_name()))?.is_none() {
+                    tracing::warn!("blob for {} was collected mid-store; not caching unit", entry.path);
+                    wtxn.commit()?;
+                    return Ok(());
+                }
+            }
+            for entry in blobs {
+                self.db.put(&mut wtxn, &Self::reuse_key(&entry.blob_name()), &now)?;
+            }
         }
         let manifest_key = Self::unit_manifest_key(unit_key);
-        let manifest_data = serde_json::to_vec(artifacts)?;
+        let manifest_data = serde_json::to_vec(&manifest)?;
         self.db.put(&mut wtxn, &manifest_key, &manifest_data)?;
+        self.db.put(&mut wtxn, &Self::access_key(unit_key), &now)?;
         wtxn.commit()?;
+        self.indexes
+            .lock()
+            .unwrap()
+            .insert(*unit_key, Arc::new(manifest.into_index()));
         Ok(())
     }
 
@@ -155,12 +327,141 @@ impl CacheBackend for LmdbCache {
     fn put_dynamic_inputs(&self, static_key: &[u8; 32], inputs: &DynamicInputs) -> Result<()> {
         let key = Self::dyn_key(static_key, &inputs.shape_hash());
         let mut wtxn = self.env.write_txn()?;
-        let data = serde_json::to_vec(inputs)?;
+        let existing: Option<DynamicInputs> = match self.db.get(&wtxn, &key)? {
+            Some(v) => serde_json::from_slice(v).ok(),
+            None => None,
+        };
+        let merged = super::merge_unit_keys(inputs, existing.as_ref());
+        let data = serde_json::to_vec(&merged)?;
         self.db.put(&mut wtxn, &key, &data)?;
         wtxn.commit()?;
         Ok(())
     }
 
+    fn touch_units(&self, unit_keys: &[[u8; 32]]) -> Result<()> {
+        if unit_keys.is_empty() {
+            return Ok(());
+        }
+        let now = Self::now_secs();
+        let mut wtxn = self.env.write_txn()?;
+        for unit_key in unit_keys {
+            self.db.put(&mut wtxn, &Self::access_key(unit_key), &now)?;
+        }
+        wtxn.commit()?;
+        Ok(())
+    }
+
+    fn list_units(&self) -> Result<Vec<UnitInfo>> {
+        let rtxn = self.env.read_txn()?;
+        let mut out = Vec::new();
+        for entry in self.db.prefix_iter(&rtxn, b"m:")? {
+            let (k, v) = entry?;
+            let Some(key) = std::str::from_utf8(&k[2..]).ok().and_then(super::unhex) else {
+                continue;
+            };
+            let index = serde_json::from_slice::<UnitManifest>(v)?.into_index();
+            let blobs = index.blobs.values().map(|b| b.blob_name()).collect();
+            let mut size = v.len() as u64;
+            for a in self.db.prefix_iter(&rtxn, &Self::artifact_prefix(&key))? {
+                size += a?.1.len() as u64;
+            }
+            // Entries written before access tracking have no `t:` record and
+            // sort as least recently used.
+            let secs = self
+                .db
+                .get(&rtxn, &Self::access_key(&key))?
+                .and_then(|v| v.try_into().ok())
+                .map(u64::from_le_bytes)
+                .unwrap_or(0);
+            let last_access = UNIX_EPOCH + Duration::from_secs(secs);
+            out.push(UnitInfo { key, size, blobs, last_access });
+        }
+        Ok(out)
+    }
+
+    /// Deletes the manifest and artifacts in one write transaction. Freed pages
+    /// are reused by later writes; the LMDB file itself does not shrink.
+    fn remove_unit(&self, unit_key: &[u8; 32]) -> Result<()> {
+        self.indexes.lock().unwrap().remove(unit_key);
+        let mut wtxn = self.env.write_txn()?;
+        let artifact_keys: Vec<Vec<u8>> = self
+            .db
+            .prefix_iter(&wtxn, &Self::artifact_prefix(unit_key))?
+            .map(|e| e.map(|(k, _)| k.to_vec()))
+            .collect::<std::result::Result<_, _>>()?;
+        self.db.delete(&mut wtxn, &Self::unit_manifest_key(unit_key))?;
+        self.db.delete(&mut wtxn, &Self::access_key(unit_key))?;
+        for k in &artifact_keys {
+            self.db.delete(&mut wtxn, k)?;
+        }
+        wtxn.commit()?;
+        Ok(())
+    }
+
+    /// `modified` is when the last manifest referencing the blob was written
+    /// (`UNIX_EPOCH` for blobs stored before that was recorded).
+    fn list_blobs(&self) -> Result<Vec<BlobInfo>> {
+        let rtxn = self.env.read_txn()?;
+        let mut listed = self.listed.lock().unwrap();
+        let mut out = Vec::new();
+        for entry in self.db.prefix_iter(&rtxn, b"b:")? {
+            let (k, v) = entry?;
+            let Ok(name) = std::str::from_utf8(&k[2..]) else { continue };
+            let stamp: [u8; 8] = self
+                .db
+                .get(&rtxn, &Self::reuse_key(name))?
+                .and_then(|v| v.try_into().ok())
+                .unwrap_or_default();
+            let modified = UNIX_EPOCH + Duration::from_secs(u64::from_le_bytes(stamp));
+            listed.insert(name.to_string(), stamp);
+            out.push(BlobInfo { name: name.to_string(), size: v.len() as u64, modified });
+        }
+        Ok(out)
+    }
+
+    /// Keeps a blob that a `finalize_unit` has referenced since `list_blobs`
+    /// reported it: gc's references were read in earlier transactions, and
+    /// that store committed in between.
+    fn remove_blob(&self, name: &str) -> Result<()> {
+        let listed = self.listed.lock().unwrap().remove(name);
+        let mut wtxn = self.env.write_txn()?;
+        let stamp = self.db.get(&wtxn, &Self::reuse_key(name))?.map(<[u8]>::to_vec);
+        if let Some(listed) = listed
+            && stamp.as_deref().unwrap_or(&[0; 8]) != listed
+        {
+            tracing::debug!("blob {name} was reused since it was listed; keeping it");
+            return Ok(());
+        }
+        self.db.delete(&mut wtxn, &Self::blob_key(name))?;
+        self.db.delete(&mut wtxn, &Self::reuse_key(name))?;
+        wtxn.commit()?;
+        Ok(())
+    }
+
+    fn list_all_dynamic_inputs(&self) -> Result<Vec<([u8; 32], DynamicInputs)>> {
+        let rtxn = self.env.read_txn()?;
+        let mut out = Vec::new();
+        for entry in self.db.prefix_iter(&rtxn, b"d:")? {
+            let (k, v) = entry?;
+            let Some(static_key) = k
+                .get(2..2 + 64)
+                .and_then(|h| std::str::from_utf8(h).ok())
+                .and_then(super::unhex)
+            else {
+                continue;
+            };
+            out.push((static_key, serde_json::from_slice(v)?));
+        }
+        Ok(out)
+    }
+
+    fn remove_dynamic_inputs(&self, static_key: &[u8; 32], shape: &[u8; 32]) -> Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        self.db.delete(&mut wtxn, &Self::dyn_key(static_key, shape))?;
+        wtxn.commit()?;
+        Ok(())
+    }
+
     fn name(&self) -> &str {
         "lmdb"
     }
@@ -196,6 +497,26 @@ mod tests {
         );
     }
 
+    #[test]
+    fn compressed_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = tempfile::tempdir().unwrap();
+        let cache = LmdbCache::open(dir.path(), Some(10 * 1024 * 1024))
+            .unwrap()
+            .with_compression("zstd".parse().unwrap());
+        let key = *blake3::hash(b"compressed").as_bytes();
+        let data = b"rlib data ".repeat(1000);
+
+        cache.put_artifact(&key, "debug/libfoo.rlib", &data).unwrap();
+        cache.finalize_unit(&key, &["debug/libfoo.rlib".into()]).unwrap();
+        assert!(cache.list_blobs().unwrap()[0].size < data.len() as u64 / 10);
+
+        let dest = out.path().join("libfoo.rlib");
+        assert!(cache.restore_artifact(&key, "debug/libfoo.rlib", &dest).unwrap());
+        assert_eq!(std::fs::read(&dest).unwrap(), data);
+        assert_eq!(cache.get_artifact(&key, "debug/libfoo.rlib").unwrap().unwrap(), data);
+    }
+
     #[test]
     fn dynamic_inputs_round_trip() {
         use crate::cache::DynPath;
@@ -208,6 +529,7 @@ mod tests {
         let inputs_a = DynamicInputs {
             paths: vec![DynPath { path: "/a".into(), stored_hash: [1; 32] }],
             envs: vec![],
+            ..Default::default()
         };
         let inputs_b = DynamicInputs {
             paths: vec![
@@ -215,10 +537,50 @@ mod tests {
                 DynPath { path: "/b".into(), stored_hash: [2; 32] },
             ],
             envs: vec![],
+            ..Default::default()
         };
         cache.put_dynamic_inputs(&static_key, &inputs_a).unwrap();
         cache.put_dynamic_inputs(&static_key, &inputs_b).unwrap();
         cache.put_dynamic_inputs(&static_key, &inputs_a).unwrap();
         assert_eq!(cache.list_dynamic_inputs(&static_key).unwrap().len(), 2);
     }
+
+    #[test]
+    fn a_blob_reused_after_gc_listed_it_is_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = tempfile::tempdir().unwrap();
+        let cache = LmdbCache::open(dir.path(), Some(10 * 1024 * 1024)).unwrap();
+        let store = |name: &str| {
+            let key = *blake3::hash(name.as_bytes()).as_bytes();
+            cache.put_artifact(&key, "debug/libx.rlib", b"shared").unwrap();
+            cache.finalize_unit(&key, &["debug/libx.rlib".into()]).unwrap();
+            key
+        };
+        let old = store("old");
+        cache.remove_unit(&old).unwrap();
+        // Settled: last referenced long ago.
+        let name = cache.list_blobs().unwrap()[0].name.clone();
+        let mut wtxn = cache.env.write_txn().unwrap();
+        cache.db.put(&mut wtxn, &LmdbCache::reuse_key(&name), &[0; 8]).unwrap();
+        wtxn.commit().unwrap();
+
+        // gc sees the blob unreferenced and settled, then a store reuses it
+        // before gc removes it.
+        let listed = cache.list_blobs().unwrap();
+        assert_eq!(listed[0].modified, UNIX_EPOCH);
+        let racing = store("racing");
+        cache.remove_blob(&listed[0].name).unwrap();
+        cache.indexes.lock().unwrap().clear();
+        let dest = out.path().join("libx.rlib");
+        assert_eq!(cache.list_artifacts(&racing).unwrap().len(), 1);
+        assert!(cache.restore_artifact(&racing, "debug/libx.rlib", &dest).unwrap());
+        assert_eq!(std::fs::read(&dest).unwrap(), b"shared");
+
+        // Listed again without a store in between, it goes.
+        cache.remove_unit(&racing).unwrap();
+        let listed = cache.list_blobs().unwrap();
+        assert!(listed[0].modified > UNIX_EPOCH);
+        cache.remove_blob(&listed[0].name).unwrap();
+        assert!(cache.list_blobs().unwrap().is_empty());
+    }
 }
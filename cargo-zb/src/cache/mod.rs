@@ -1,13 +1,24 @@
+pub mod cas;
+pub mod codec;
 pub mod fs;
+#[cfg(feature = "http")]
+pub mod http;
 pub mod lmdb;
+pub mod remote;
 #[cfg(feature = "tikv")]
 pub mod tikv;
+pub mod tiered;
+#[cfg(feature = "io-uring")]
+mod uring;
 
 use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 
+use crate::remap::PathRoots;
+
 pub fn default_cache_dir() -> Result<PathBuf> {
     if let Ok(dir) = std::env::var("CARGO_ZB_CACHE_DIR") {
         return Ok(PathBuf::from(dir));
@@ -20,6 +31,18 @@ pub fn hex(bytes: &[u8; 32]) -> String {
     bytes.iter().map(|b| format!("{b:02x}")).collect()
 }
 
+/// Inverse of [`hex`]. Returns `None` for anything that isn't exactly 64 hex digits.
+pub fn unhex(s: &str) -> Option<[u8; 32]> {
+    if s.len() != 64 {
+        return None;
+    }
+    let mut out = [0u8; 32];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(out)
+}
+
 /// Files and env vars whose state must be folded into a unit's cache key.
 ///
 /// Each entry carries a snapshot of its content/value at the time the manifest
@@ -30,10 +53,35 @@ pub fn hex(bytes: &[u8; 32]) -> String {
 pub struct DynamicInputs {
     pub paths: Vec<DynPath>,
     pub envs: Vec<DynEnv>,
+    /// Full keys of the unit bundles stored from this manifest. Backends union
+    /// these across `put_dynamic_inputs` calls for the same shape; `gc` drops
+    /// the manifest once none of them exist. Not part of any hash.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unit_keys: Vec<[u8; 32]>,
+    /// `paths` is the package's whole file list, cargo's fallback for a build
+    /// script that declares no `rerun-if-*`. Only for miss explanations.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub whole_package: bool,
+    /// Early-cutoff keys (see `cutoff`) of bundles in `unit_keys`, in the
+    /// manifests stored under a unit's own key. Merged like `unit_keys`;
+    /// not part of any hash.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cutoffs: Vec<Cutoff>,
+}
+
+/// A bundle stored from a manifest, found by its output-addressed key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cutoff {
+    /// `hash::cutoff_key` of the unit when the bundle was stored.
+    pub key: [u8; 32],
+    /// The bundle's full key.
+    pub unit_key: [u8; 32],
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DynPath {
+    /// Normalized: the workspace, `CARGO_HOME` and target dir prefixes are
+    /// placeholders (see `remap`).
     pub path: PathBuf,
     /// blake3 of the file/dir contents at the time this manifest was written.
     /// `[0; 32]` is the sentinel for "missing" (the file didn't exist).
@@ -43,6 +91,7 @@ pub struct DynPath {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DynEnv {
     pub name: String,
+    /// Normalized like paths.
     pub stored_value: Option<String>,
 }
 
@@ -53,9 +102,13 @@ pub struct DiffReport {
     pub missing_paths: Vec<PathBuf>,
     pub appeared_paths: Vec<PathBuf>,
     pub changed_envs: Vec<String>,
+    /// The paths compared are a package's whole file list (see
+    /// [`DynamicInputs::whole_package`]).
+    pub whole_package: bool,
 }
 
 impl DiffReport {
+    #[allow(dead_code)]
     pub fn is_empty(&self) -> bool {
         self.changed_paths.is_empty()
             && self.missing_paths.is_empty()
@@ -98,15 +151,21 @@ impl DynamicInputs {
     /// Hash of *current* contents of declared files + *current* values of
     /// declared env vars. Combined with a unit's static_key + dep full_keys
     /// to derive the unit's full content-addressed cache key. Independent of
-    /// `stored_hash`/`stored_value` snapshots.
-    pub fn content_hash<F: Fn(&str) -> Option<String>>(&self, env_lookup: F) -> Result<[u8; 32]> {
+    /// `stored_hash`/`stored_value` snapshots. Paths are stored normalized
+    /// (see `remap`) and read at their location under `roots`; env values are
+    /// normalized before hashing.
+    pub fn content_hash<F: Fn(&str) -> Option<String>>(
+        &self,
+        roots: &PathRoots,
+        env_lookup: F,
+    ) -> Result<[u8; 32]> {
         let mut hasher = blake3::Hasher::new();
-        hasher.update(b"dyn-inputs-content-v2\0");
+        hasher.update(b"dyn-inputs-content-v3\0");
 
         let mut paths: Vec<&DynPath> = self.paths.iter().collect();
         paths.sort_by(|a, b| a.path.cmp(&b.path));
         for p in &paths {
-            let h = hash_path_current(&p.path)?;
+            let h = hash_path_current(&roots.resolve(&p.path))?;
             hasher.update(p.path.to_string_lossy().as_bytes());
             hasher.update(b"\0");
             hasher.update(&h);
@@ -118,7 +177,7 @@ impl DynamicInputs {
         for e in &envs {
             hasher.update(e.name.as_bytes());
             hasher.update(b"=");
-            match env_lookup(&e.name) {
+            match env_lookup(&e.name).map(|v| roots.normalize_str(&v)) {
                 Some(v) => {
                     hasher.update(b"s");
                     hasher.update(&(v.len() as u64).to_le_bytes());
@@ -135,23 +194,32 @@ impl DynamicInputs {
         Ok(*hasher.finalize().as_bytes())
     }
 
-    /// Compare current state against this manifest's stored snapshot.
-    pub fn diff_current<F: Fn(&str) -> Option<String>>(&self, env_lookup: F) -> Result<DiffReport> {
-        let mut report = DiffReport::default();
+    /// Compare current state against this manifest's stored snapshot. The
+    /// report names paths as they are under `roots`.
+    pub fn diff_current<F: Fn(&str) -> Option<String>>(
+        &self,
+        roots: &PathRoots,
+        env_lookup: F,
+    ) -> Result<DiffReport> {
+        let mut report = DiffReport {
+            whole_package: self.whole_package,
+            ..Default::default()
+        };
         for p in &self.paths {
-            let cur = hash_path_current(&p.path)?;
+            let path = roots.resolve(&p.path);
+            let cur = hash_path_current(&path)?;
             let stored_missing = p.stored_hash == [0u8; 32];
             let cur_missing = cur == [0u8; 32];
             match (stored_missing, cur_missing) {
                 (true, true) => {}
-                (true, false) => report.appeared_paths.push(p.path.clone()),
-                (false, true) => report.missing_paths.push(p.path.clone()),
-                (false, false) if cur != p.stored_hash => report.changed_paths.push(p.path.clone()),
+                (true, false) => report.appeared_paths.push(path),
+                (false, true) => report.missing_paths.push(path),
+                (false, false) if cur != p.stored_hash => report.changed_paths.push(path),
                 _ => {}
             }
         }
         for e in &self.envs {
-            let cur = env_lookup(&e.name);
+            let cur = env_lookup(&e.name).map(|v| roots.normalize_str(&v));
             if cur.as_deref() != e.stored_value.as_deref() {
                 report.changed_envs.push(e.name.clone());
             }
@@ -161,7 +229,8 @@ impl DynamicInputs {
 }
 
 /// Hash content of a single path (file or directory). Returns `[0; 32]` if
-/// the path does not exist — used as a stable sentinel.
+/// the path does not exist — used as a stable sentinel. File contents are
+/// hashed through the stat cache.
 pub fn hash_path_current(path: &Path) -> Result<[u8; 32]> {
     let mut hasher = blake3::Hasher::new();
     let meta = match std::fs::metadata(path) {
@@ -169,12 +238,11 @@ pub fn hash_path_current(path: &Path) -> Result<[u8; 32]> {
         Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok([0u8; 32]),
         Err(e) => return Err(e).with_context(|| format!("stat {}", path.display())),
     };
+    let stat_cache = crate::statcache::global();
     if meta.is_file() {
-        let bytes = std::fs::read(path)
-            .with_context(|| format!("hashing {}", path.display()))?;
         hasher.update(b"f");
-        hasher.update(&(bytes.len() as u64).to_le_bytes());
-        hasher.update(&bytes);
+        hasher.update(&meta.len().to_le_bytes());
+        hasher.update(&stat_cache.file_hash(path, &meta)?);
     } else if meta.is_dir() {
         hasher.update(b"d");
         let mut entries: Vec<_> = walkdir::WalkDir::new(path)
@@ -188,10 +256,11 @@ pub fn hash_path_current(path: &Path) -> Result<[u8; 32]> {
             let rel = entry.path().strip_prefix(path).unwrap_or(entry.path());
             hasher.update(rel.to_string_lossy().as_bytes());
             hasher.update(b"\0");
-            let bytes = std::fs::read(entry.path())
-                .with_context(|| format!("hashing {}", entry.path().display()))?;
-            hasher.update(&(bytes.len() as u64).to_le_bytes());
-            hasher.update(&bytes);
+            let meta = entry
+                .metadata()
+                .with_context(|| format!("stat {}", entry.path().display()))?;
+            hasher.update(&meta.len().to_le_bytes());
+            hasher.update(&stat_cache.file_hash(entry.path(), &meta)?);
         }
     } else {
         // non-regular (symlink to nowhere, socket, etc.) — treat as missing
@@ -200,6 +269,35 @@ pub fn hash_path_current(path: &Path) -> Result<[u8; 32]> {
     Ok(*hasher.finalize().as_bytes())
 }
 
+/// Hash `paths` on every core, so the `hash_path_current` calls that follow
+/// for them are served by the stat cache. Errors are left for those calls.
+pub fn prehash(paths: &[PathBuf]) {
+    let next = std::sync::atomic::AtomicUsize::new(0);
+    let threads = std::thread::available_parallelism().map_or(4, |n| n.get());
+    std::thread::scope(|scope| {
+        for _ in 0..threads.min(paths.len()) {
+            scope.spawn(|| {
+                while let Some(path) = paths.get(next.fetch_add(1, std::sync::atomic::Ordering::Relaxed)) {
+                    let _ = hash_path_current(path);
+                }
+            });
+        }
+    });
+}
+
+/// Size and recency of one stored unit bundle, as seen by `gc`.
+#[derive(Debug, Clone)]
+pub struct UnitInfo {
+    pub key: [u8; 32],
+    /// Bytes held by the bundle itself: its manifest, plus the artifacts of
+    /// bundles stored before the blob store.
+    pub size: u64,
+    /// Names of the blobs its manifest references (see `cas`).
+    pub blobs: Vec<String>,
+    /// Last restore (or store, if never restored).
+    pub last_access: SystemTime,
+}
+
 pub trait CacheBackend: Send + Sync {
     fn contains_unit(&self, unit_key: &[u8; 32]) -> Result<bool>;
 
@@ -222,6 +320,20 @@ pub trait CacheBackend: Send + Sync {
         }
     }
 
+    /// Restore a batch of one unit's artifacts (`(rel_path, dest)` pairs).
+    /// Returns, per entry, whether it was found. Called from restore worker
+    /// threads; backends with a batched I/O path override this.
+    fn restore_artifacts(
+        &self,
+        unit_key: &[u8; 32],
+        files: &[(String, PathBuf)],
+    ) -> Result<Vec<bool>> {
+        files
+            .iter()
+            .map(|(rel, dest)| self.restore_artifact(unit_key, rel, dest))
+            .collect()
+    }
+
     fn put_artifact(&self, unit_key: &[u8; 32], rel_path: &str, data: &[u8]) -> Result<()>;
 
     fn store_artifact_from_file(
@@ -242,5 +354,67 @@ pub trait CacheBackend: Send + Sync {
     /// the same `shape_hash` so per-entry snapshots stay current.
     fn put_dynamic_inputs(&self, static_key: &[u8; 32], inputs: &DynamicInputs) -> Result<()>;
 
+    /// Record that the unit bundles were just used, for LRU eviction. Called
+    /// once per batch of restores.
+    fn touch_units(&self, _unit_keys: &[[u8; 32]]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Every finalized unit bundle with its size and last access time.
+    fn list_units(&self) -> Result<Vec<UnitInfo>> {
+        anyhow::bail!("{} backend does not support gc", self.name())
+    }
+
+    /// Remove a unit bundle. Readers that already hold its manifest see the
+    /// artifacts disappear and fall back to a miss (see `artifacts::Restorer`).
+    fn remove_unit(&self, _unit_key: &[u8; 32]) -> Result<()> {
+        anyhow::bail!("{} backend does not support gc", self.name())
+    }
+
+    /// Every recorded dynamic-inputs manifest, paired with its static_key.
+    fn list_all_dynamic_inputs(&self) -> Result<Vec<([u8; 32], DynamicInputs)>> {
+        anyhow::bail!("{} backend does not support gc", self.name())
+    }
+
+    /// Remove the manifest with the given `shape_hash` under `static_key`.
+    fn remove_dynamic_inputs(&self, _static_key: &[u8; 32], _shape: &[u8; 32]) -> Result<()> {
+        anyhow::bail!("{} backend does not support gc", self.name())
+    }
+
+    /// Every stored blob, referenced or not.
+    fn list_blobs(&self) -> Result<Vec<cas::BlobInfo>> {
+        anyhow::bail!("{} backend does not support gc", self.name())
+    }
+
+    fn remove_blob(&self, _name: &str) -> Result<()> {
+        anyhow::bail!("{} backend does not support gc", self.name())
+    }
+
+    /// Wait for writes that were accepted but not yet persisted (the tiered
+    /// backend's background uploads). Called once at the end of a build.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
     fn name(&self) -> &str;
 }
+
+/// Union of the recorded `unit_keys` of an existing manifest into a new one
+/// for the same shape, so overwriting keeps earlier bundles reachable for `gc`.
+/// Cutoff keys are merged too; the new manifest's win.
+pub(crate) fn merge_unit_keys(inputs: &DynamicInputs, existing: Option<&DynamicInputs>) -> DynamicInputs {
+    let mut merged = inputs.clone();
+    if let Some(existing) = existing {
+        for k in &existing.unit_keys {
+            if !merged.unit_keys.contains(k) {
+                merged.unit_keys.push(*k);
+            }
+        }
+        for c in &existing.cutoffs {
+            if !merged.cutoffs.iter().any(|m| m.key == c.key) {
+                merged.cutoffs.push(*c);
+            }
+        }
+    }
+    merged
+}
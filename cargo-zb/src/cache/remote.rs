@@ -0,0 +1,287 @@
+//! A shared cache as one build uses it: helpful when it answers, never
+//! fatal to a lookup when it doesn't.
+//!
+//! - A lookup that fails is logged and counts as a miss.
+//! - A write that fails is logged and dropped; the build cargo finished
+//!   stays green. A unit with a failed artifact upload is never finalized,
+//!   so the cache never lists a bundle it doesn't hold in full.
+//! - The first timeout or connection failure marks the cache down for the
+//!   rest of the run. A blackholed server would otherwise cost the full
+//!   timeout on every call. From then on lookups miss and writes are
+//!   dropped without asking it.
+
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use anyhow::Result;
+
+use super::{CacheBackend, DynamicInputs};
+
+pub struct RemoteCache {
+    inner: Box<dyn CacheBackend>,
+    down: AtomicBool,
+    /// Units with an artifact upload that failed: not to be finalized.
+    incomplete: Mutex<HashSet<[u8; 32]>>,
+    /// Uploads (finalized units, dynamic-inputs manifests) that were dropped.
+    dropped: AtomicUsize,
+}
+
+impl RemoteCache {
+    pub fn new(inner: Box<dyn CacheBackend>) -> Self {
+        Self {
+            inner,
+            down: AtomicBool::new(false),
+            incomplete: Mutex::new(HashSet::new()),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    /// Uploads dropped so far because the cache failed or was down.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn is_down(&self) -> bool {
+        self.down.load(Ordering::Relaxed)
+    }
+
+    /// Note a failed call, taking the cache out of the run if it is
+    /// unreachable.
+    fn failed(&self, what: &str, e: &anyhow::Error) {
+        tracing::warn!("{} cache {what} failed: {e:#}", self.inner.name());
+        if is_unreachable(e) && !self.down.swap(true, Ordering::Relaxed) {
+            tracing::warn!(
+                "{} cache is unreachable; not using it for the rest of this run",
+                self.inner.name()
+            );
+        }
+    }
+
+    /// Run a lookup, turning a failure into a miss (`T::default()`).
+    fn lookup<T: Default>(&self, what: &str, call: impl FnOnce() -> Result<T>) -> T {
+        if self.is_down() {
+            return T::default();
+        }
+        call().unwrap_or_else(|e| {
+            self.failed(what, &e);
+            T::default()
+        })
+    }
+
+    /// Run a write; `false` if it failed or the cache is down.
+    fn write(&self, what: &str, call: impl FnOnce() -> Result<()>) -> bool {
+        if self.is_down() {
+            return false;
+        }
+        call().map_err(|e| self.failed(what, &e)).is_ok()
+    }
+
+    /// Upload one file of a unit, marking the unit incomplete if it fails.
+    fn write_artifact(
+        &self,
+        unit_key: &[u8; 32],
+        rel_path: &str,
+        call: impl FnOnce() -> Result<()>,
+    ) {
+        if !self.write(&format!("upload of {rel_path}"), call) {
+            self.incomplete.lock().unwrap().insert(*unit_key);
+        }
+    }
+
+    fn drop_upload(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Whether `e` came from not reaching the server at all (refused, reset,
+/// timed out, unresolvable), as opposed to an answer we didn't like.
+fn is_unreachable(e: &anyhow::Error) -> bool {
+    use std::io::ErrorKind;
+    e.chain().any(|cause| {
+        #[cfg(feature = "http")]
+        if let Some(ureq::Error::Transport(_)) = cause.downcast_ref::<ureq::Error>() {
+            return true;
+        }
+        cause.downcast_ref::<std::io::Error>().is_some_and(|io| {
+            matches!(
+                io.kind(),
+                ErrorKind::TimedOut
+                    | ErrorKind::WouldBlock
+                    | ErrorKind::ConnectionRefused
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::NotConnected
+                    | ErrorKind::HostUnreachable
+                    | ErrorKind::NetworkUnreachable
+            )
+        })
+    })
+}
+
+impl CacheBackend for RemoteCache {
+    fn contains_unit(&self, unit_key: &[u8; 32]) -> Result<bool> {
+        Ok(self.lookup("lookup", || self.inner.contains_unit(unit_key)))
+    }
+
+    fn list_artifacts(&self, unit_key: &[u8; 32]) -> Result<Vec<String>> {
+        Ok(self.lookup("lookup", || self.inner.list_artifacts(unit_key)))
+    }
+
+    fn get_artifact(&self, unit_key: &[u8; 32], rel_path: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.lookup(&format!("fetch of {rel_path}"), || {
+            self.inner.get_artifact(unit_key, rel_path)
+        }))
+    }
+
+    fn restore_artifact(&self, unit_key: &[u8; 32], rel_path: &str, dest: &Path) -> Result<bool> {
+        Ok(self.lookup(&format!("fetch of {rel_path}"), || {
+            self.inner.restore_artifact(unit_key, rel_path, dest)
+        }))
+    }
+
+    fn put_artifact(&self, unit_key: &[u8; 32], rel_path: &str, data: &[u8]) -> Result<()> {
+        self.write_artifact(unit_key, rel_path, || {
+            self.inner.put_artifact(unit_key, rel_path, data)
+        });
+        Ok(())
+    }
+
+    fn store_artifact_from_file(
+        &self,
+        unit_key: &[u8; 32],
+        rel_path: &str,
+        src: &Path,
+    ) -> Result<()> {
+        self.write_artifact(unit_key, rel_path, || {
+            self.inner.store_artifact_from_file(unit_key, rel_path, src)
+        });
+        Ok(())
+    }
+
+    fn finalize_unit(&self, unit_key: &[u8; 32], artifacts: &[String]) -> Result<()> {
+        let incomplete = self.incomplete.lock().unwrap().remove(unit_key);
+        if incomplete || !self.write("upload", || self.inner.finalize_unit(unit_key, artifacts)) {
+            self.drop_upload();
+        }
+        Ok(())
+    }
+
+    fn list_dynamic_inputs(&self, static_key: &[u8; 32]) -> Result<Vec<DynamicInputs>> {
+        Ok(self.lookup("lookup", || self.inner.list_dynamic_inputs(static_key)))
+    }
+
+    fn put_dynamic_inputs(&self, static_key: &[u8; 32], inputs: &DynamicInputs) -> Result<()> {
+        if !self.write("upload", || {
+            self.inner.put_dynamic_inputs(static_key, inputs)
+        }) {
+            self.drop_upload();
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        let dropped = self.dropped();
+        if dropped > 0 {
+            tracing::warn!(
+                "{dropped} uploads to the {} cache failed",
+                self.inner.name()
+            );
+        }
+        self.inner.flush()
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+}
+
+#[cfg(all(test, feature = "http"))]
+mod tests {
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::cache::http::{HttpCache, HttpConfig};
+
+    #[test]
+    fn a_blackholed_server_costs_one_timeout() {
+        // Accepts connections and never answers.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&accepted);
+        std::thread::spawn(move || {
+            let mut held = Vec::new();
+            for stream in listener.incoming() {
+                counter.fetch_add(1, Ordering::SeqCst);
+                held.push(stream);
+            }
+        });
+        let http = HttpCache::new(HttpConfig {
+            base_url: format!("http://{addr}/cache"),
+            auth_header: None,
+            timeout: Duration::from_millis(300),
+        })
+        .unwrap();
+        let remote = RemoteCache::new(Box::new(http));
+
+        let start = Instant::now();
+        for i in 0..5 {
+            assert!(!remote.contains_unit(&[i; 32]).unwrap());
+            assert!(remote.list_dynamic_inputs(&[i; 32]).unwrap().is_empty());
+        }
+        assert!(
+            start.elapsed() < Duration::from_secs(2),
+            "{:?}",
+            start.elapsed()
+        );
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+        // Writes are dropped, not failed.
+        remote.finalize_unit(&[0; 32], &[]).unwrap();
+        assert_eq!(remote.dropped(), 1);
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn a_unit_with_a_failed_upload_is_never_finalized() {
+        // Has nothing, and refuses every PUT.
+        let server = Arc::new(tiny_http::Server::http("127.0.0.1:0").unwrap());
+        let addr = server.server_addr().to_ip().unwrap();
+        let puts = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = Arc::clone(&puts);
+        std::thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let status = if *request.method() == tiny_http::Method::Put {
+                    seen.lock().unwrap().push(request.url().to_string());
+                    500
+                } else {
+                    404
+                };
+                let _ = request.respond(tiny_http::Response::empty(status));
+            }
+        });
+        let http = HttpCache::new(HttpConfig {
+            base_url: format!("http://{addr}/cache"),
+            auth_header: None,
+            timeout: Duration::from_secs(5),
+        })
+        .unwrap();
+        let remote = RemoteCache::new(Box::new(http));
+
+        remote
+            .put_artifact(&[1; 32], "debug/liba.rlib", b"a")
+            .unwrap();
+        remote
+            .finalize_unit(&[1; 32], &["debug/liba.rlib".into()])
+            .unwrap();
+        let puts = puts.lock().unwrap();
+        assert_eq!(puts.len(), 1, "{puts:?}");
+        assert!(puts[0].contains("/blobs/"), "{puts:?}");
+        assert_eq!(remote.dropped(), 1);
+        assert!(!remote.is_down());
+    }
+}
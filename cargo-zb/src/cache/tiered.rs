@@ -0,0 +1,363 @@
+//! Stacked backends, e.g. `--cache-backend fs,http`: a fast local tier in
+//! front of slower shared ones.
+//!
+//! - **Reads** try tiers in order. A unit restored from a lower tier is copied
+//!   into the local tier file by file and finalized there once every file has
+//!   arrived, so the next build hits locally.
+//! - **Writes** land in the local tier synchronously; a background thread then
+//!   uploads finalized bundles and dynamic-inputs manifests to the other tiers
+//!   (write-back). `flush` waits for it before the process exits.
+//! - `list_dynamic_inputs` merges every tier, one manifest per shape, and
+//!   copies remote-only manifests down.
+//!
+//! Errors from lower tiers never fail a build: each is wrapped in a
+//! [`RemoteCache`], so a read error counts as a miss and a tier that times
+//! out or refuses connections is skipped for the rest of the run. Upload
+//! errors are logged.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use anyhow::Result;
+
+use super::remote::RemoteCache;
+use super::{CacheBackend, DynamicInputs, UnitInfo};
+
+enum Upload {
+    Unit { unit_key: [u8; 32], artifacts: Vec<String> },
+    Dynamic { static_key: [u8; 32], inputs: DynamicInputs },
+}
+
+/// A unit being copied down from a lower tier.
+struct Populating {
+    artifacts: Vec<String>,
+    /// Files not yet copied; a file restored twice counts once.
+    remaining: HashSet<String>,
+}
+
+pub struct TieredCache {
+    local: Arc<dyn CacheBackend>,
+    remotes: Vec<Arc<RemoteCache>>,
+    name: String,
+    populating: Mutex<HashMap<[u8; 32], Populating>>,
+    tx: Mutex<Option<mpsc::Sender<Upload>>>,
+    uploader: Mutex<Option<JoinHandle<usize>>>,
+}
+
+impl TieredCache {
+    pub fn new(local: Box<dyn CacheBackend>, remotes: Vec<Box<dyn CacheBackend>>) -> Result<Self> {
+        let local: Arc<dyn CacheBackend> = Arc::from(local);
+        let remotes: Vec<Arc<RemoteCache>> =
+            remotes.into_iter().map(|r| Arc::new(RemoteCache::new(r))).collect();
+        let name = std::iter::once(local.name())
+            .chain(remotes.iter().map(|r| r.name()))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let (tx, rx) = mpsc::channel::<Upload>();
+        let up_local = Arc::clone(&local);
+        let up_remotes = remotes.clone();
+        let uploader = std::thread::Builder::new()
+            .name("cargo-zb-upload".into())
+            .spawn(move || {
+                let mut failures = 0;
+                for job in rx {
+                    for remote in &up_remotes {
+                        if remote.is_down() {
+                            failures += 1;
+                        } else if let Err(e) = upload(&*up_local, &**remote, &job) {
+                            tracing::warn!("upload to {} cache failed: {e:#}", remote.name());
+                            failures += 1;
+                        }
+                    }
+                }
+                failures
+            })?;
+
+        Ok(Self {
+            local,
+            remotes,
+            name,
+            populating: Mutex::new(HashMap::new()),
+            tx: Mutex::new(Some(tx)),
+            uploader: Mutex::new(Some(uploader)),
+        })
+    }
+
+    fn enqueue(&self, job: Upload) {
+        if let Some(tx) = self.tx.lock().unwrap().as_ref() {
+            let _ = tx.send(job);
+        }
+    }
+
+    /// The first remote tier holding the unit, if any.
+    fn remote_with(&self, unit_key: &[u8; 32]) -> Option<&Arc<RemoteCache>> {
+        self.remotes.iter().find(|r| r.contains_unit(unit_key).unwrap_or(false))
+    }
+}
+
+fn upload(local: &dyn CacheBackend, remote: &dyn CacheBackend, job: &Upload) -> Result<()> {
+    match job {
+        Upload::Unit { unit_key, artifacts } => {
+            if remote.contains_unit(unit_key)? {
+                return Ok(());
+            }
+            // Round-trip through a temp file so backends that track file
+            // modes (http) see the real ones.
+            let tmp = tempfile::tempdir()?;
+            let staged = tmp.path().join("artifact");
+            for rel in artifacts {
+                if !local.restore_artifact(unit_key, rel, &staged)? {
+                    anyhow::bail!("{rel} vanished from the local tier before upload");
+                }
+                remote.store_artifact_from_file(unit_key, rel, &staged)?;
+            }
+            remote.finalize_unit(unit_key, artifacts)
+        }
+        Upload::Dynamic { static_key, inputs } => remote.put_dynamic_inputs(static_key, inputs),
+    }
+}
+
+impl CacheBackend for TieredCache {
+    fn contains_unit(&self, unit_key: &[u8; 32]) -> Result<bool> {
+        Ok(self.local.contains_unit(unit_key)? || self.remote_with(unit_key).is_some())
+    }
+
+    fn list_artifacts(&self, unit_key: &[u8; 32]) -> Result<Vec<String>> {
+        let local = self.local.list_artifacts(unit_key)?;
+        if !local.is_empty() {
+            return Ok(local);
+        }
+        for remote in &self.remotes {
+            let artifacts = remote.list_artifacts(unit_key)?;
+            if artifacts.is_empty() {
+                continue;
+            }
+            // Listing a unit again mid-restore (harvest does) keeps its progress.
+            self.populating
+                .lock()
+                .unwrap()
+                .entry(*unit_key)
+                .or_insert_with(|| Populating {
+                    artifacts: artifacts.clone(),
+                    remaining: artifacts.iter().cloned().collect(),
+                });
+            return Ok(artifacts);
+        }
+        Ok(Vec::new())
+    }
+
+    fn get_artifact(&self, unit_key: &[u8; 32], rel_path: &str) -> Result<Option<Vec<u8>>> {
+        if let Some(data) = self.local.get_artifact(unit_key, rel_path)? {
+            return Ok(Some(data));
+        }
+        for remote in &self.remotes {
+            if let Some(data) = remote.get_artifact(unit_key, rel_path)? {
+                return Ok(Some(data));
+            }
+        }
+        Ok(None)
+    }
+
+    fn restore_artifact(&self, unit_key: &[u8; 32], rel_path: &str, dest: &Path) -> Result<bool> {
+        let populating = self.populating.lock().unwrap().contains_key(unit_key);
+        if !populating {
+            return self.local.restore_artifact(unit_key, rel_path, dest);
+        }
+        let mut found = false;
+        for remote in &self.remotes {
+            if remote.restore_artifact(unit_key, rel_path, dest)? {
+                found = true;
+                break;
+            }
+        }
+        if !found {
+            // Never finalize a partial copy; gc sweeps the leftovers.
+            self.populating.lock().unwrap().remove(unit_key);
+            return Ok(false);
+        }
+        self.local.store_artifact_from_file(unit_key, rel_path, dest)?;
+
+        let done = {
+            let mut map = self.populating.lock().unwrap();
+            match map.get_mut(unit_key) {
+                Some(p) => {
+                    p.remaining.remove(rel_path);
+                    if p.remaining.is_empty() { map.remove(unit_key) } else { None }
+                }
+                None => None,
+            }
+        };
+        if let Some(p) = done {
+            self.local.finalize_unit(unit_key, &p.artifacts)?;
+        }
+        Ok(true)
+    }
+
+    fn restore_artifacts(&self, unit_key: &[u8; 32], files: &[(String, PathBuf)]) -> Result<Vec<bool>> {
+        if self.populating.lock().unwrap().contains_key(unit_key) {
+            files
+                .iter()
+                .map(|(rel, dest)| self.restore_artifact(unit_key, rel, dest))
+                .collect()
+        } else {
+            // Keep the local tier's batched path (io_uring) on local hits.
+            self.local.restore_artifacts(unit_key, files)
+        }
+    }
+
+    fn put_artifact(&self, unit_key: &[u8; 32], rel_path: &str, data: &[u8]) -> Result<()> {
+        self.local.put_artifact(unit_key, rel_path, data)
+    }
+
+    fn store_artifact_from_file(&self, unit_key: &[u8; 32], rel_path: &str, src: &Path) -> Result<()> {
+        self.local.store_artifact_from_file(unit_key, rel_path, src)
+    }
+
+    fn finalize_unit(&self, unit_key: &[u8; 32], artifacts: &[String]) -> Result<()> {
+        self.local.finalize_unit(unit_key, artifacts)?;
+        self.enqueue(Upload::Unit { unit_key: *unit_key, artifacts: artifacts.to_vec() });
+        Ok(())
+    }
+
+    fn list_dynamic_inputs(&self, static_key: &[u8; 32]) -> Result<Vec<DynamicInputs>> {
+        let mut by_shape: HashMap<[u8; 32], DynamicInputs> = HashMap::new();
+        for inputs in self.local.list_dynamic_inputs(static_key)? {
+            by_shape.insert(inputs.shape_hash(), inputs);
+        }
+        for remote in &self.remotes {
+            for inputs in remote.list_dynamic_inputs(static_key)? {
+                let shape = inputs.shape_hash();
+                match by_shape.get_mut(&shape) {
+                    Some(known) => *known = super::merge_unit_keys(known, Some(&inputs)),
+                    None => {
+                        self.local.put_dynamic_inputs(static_key, &inputs)?;
+                        by_shape.insert(shape, inputs);
+                    }
+                }
+            }
+        }
+        Ok(by_shape.into_values().collect())
+    }
+
+    fn put_dynamic_inputs(&self, static_key: &[u8; 32], inputs: &DynamicInputs) -> Result<()> {
+        self.local.put_dynamic_inputs(static_key, inputs)?;
+        self.enqueue(Upload::Dynamic { static_key: *static_key, inputs: inputs.clone() });
+        Ok(())
+    }
+
+    fn touch_units(&self, unit_keys: &[[u8; 32]]) -> Result<()> {
+        self.local.touch_units(unit_keys)
+    }
+
+    // gc only manages the local tier; shared tiers are evicted server-side.
+
+    fn list_units(&self) -> Result<Vec<UnitInfo>> {
+        self.local.list_units()
+    }
+
+    fn remove_unit(&self, unit_key: &[u8; 32]) -> Result<()> {
+        self.local.remove_unit(unit_key)
+    }
+
+    fn list_all_dynamic_inputs(&self) -> Result<Vec<([u8; 32], DynamicInputs)>> {
+        self.local.list_all_dynamic_inputs()
+    }
+
+    fn list_blobs(&self) -> Result<Vec<super::cas::BlobInfo>> {
+        self.local.list_blobs()
+    }
+
+    fn remove_blob(&self, name: &str) -> Result<()> {
+        self.local.remove_blob(name)
+    }
+
+    fn remove_dynamic_inputs(&self, static_key: &[u8; 32], shape: &[u8; 32]) -> Result<()> {
+        self.local.remove_dynamic_inputs(static_key, shape)
+    }
+
+    fn flush(&self) -> Result<()> {
+        drop(self.tx.lock().unwrap().take());
+        if let Some(handle) = self.uploader.lock().unwrap().take() {
+            tracing::debug!("waiting for uploads to {} to finish...", self.name);
+            let failures = handle
+                .join()
+                .map_err(|_| anyhow::anyhow!("upload thread panicked"))?
+                + self.remotes.iter().map(|r| r.dropped()).sum::<usize>();
+            if failures > 0 {
+                tracing::warn!("{failures} uploads to the shared cache failed; entries stay local only");
+            }
+        }
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Drop for TieredCache {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::fs::FsCache;
+    use crate::cache::DynPath;
+
+    #[test]
+    fn reads_populate_local_and_writes_reach_remote() {
+        let local_dir = tempfile::tempdir().unwrap();
+        let remote_dir = tempfile::tempdir().unwrap();
+        let out = tempfile::tempdir().unwrap();
+        let remote = FsCache::new(remote_dir.path()).unwrap();
+
+        // A unit only the shared tier has.
+        let shared = *blake3::hash(b"shared").as_bytes();
+        remote.put_artifact(&shared, "debug/libshared.rlib", b"shared rlib").unwrap();
+        remote.put_artifact(&shared, "debug/libshared.rmeta", b"shared rmeta").unwrap();
+        remote
+            .finalize_unit(&shared, &["debug/libshared.rlib".into(), "debug/libshared.rmeta".into()])
+            .unwrap();
+
+        let tiered = TieredCache::new(
+            Box::new(FsCache::new(local_dir.path()).unwrap()),
+            vec![Box::new(FsCache::new(remote_dir.path()).unwrap())],
+        )
+        .unwrap();
+        assert!(tiered.contains_unit(&shared).unwrap());
+        let artifacts = tiered.list_artifacts(&shared).unwrap();
+        assert!(tiered.restore_artifact(&shared, &artifacts[0], &out.path().join("x")).unwrap());
+        // Listed again mid-restore, as harvest does.
+        assert_eq!(tiered.list_artifacts(&shared).unwrap(), artifacts);
+        assert!(tiered.restore_artifact(&shared, &artifacts[1], &out.path().join("y")).unwrap());
+        let local = FsCache::new(local_dir.path()).unwrap();
+        assert!(local.contains_unit(&shared).unwrap());
+
+        // A unit built here goes local first, then is uploaded.
+        let built = *blake3::hash(b"built").as_bytes();
+        tiered.put_artifact(&built, "debug/libbuilt.rlib", b"built rlib").unwrap();
+        tiered.finalize_unit(&built, &["debug/libbuilt.rlib".into()]).unwrap();
+        let static_key = *blake3::hash(b"static").as_bytes();
+        let inputs = DynamicInputs {
+            paths: vec![DynPath { path: "/a".into(), stored_hash: [1; 32] }],
+            unit_keys: vec![built],
+            ..Default::default()
+        };
+        tiered.put_dynamic_inputs(&static_key, &inputs).unwrap();
+        tiered.flush().unwrap();
+
+        assert_eq!(
+            remote.get_artifact(&built, "debug/libbuilt.rlib").unwrap().unwrap(),
+            b"built rlib"
+        );
+        assert!(remote.contains_unit(&built).unwrap());
+        assert_eq!(remote.list_dynamic_inputs(&static_key).unwrap().len(), 1);
+    }
+}
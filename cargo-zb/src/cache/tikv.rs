@@ -117,10 +117,11 @@ impl CacheBackend for TikvCache {
 
     fn put_dynamic_inputs(&self, static_key: &[u8; 32], inputs: &DynamicInputs) -> Result<()> {
         let key = Self::dyn_key(static_key, &inputs.shape_hash());
-        if self.get_raw(key.clone())?.is_some() {
-            return Ok(());
-        }
-        let data = serde_json::to_vec(inputs)?;
+        let existing: Option<DynamicInputs> = self
+            .get_raw(key.clone())?
+            .and_then(|v| serde_json::from_slice(&v).ok());
+        let merged = super::merge_unit_keys(inputs, existing.as_ref());
+        let data = serde_json::to_vec(&merged)?;
         self.put_raw(key, data)
     }
 
@@ -1,16 +1,23 @@
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use anyhow::Result;
-use cargo::core::Workspace;
+use anyhow::{Context, Result};
+use cargo::core::{Package, Workspace};
 use cargo::core::compiler::unit_graph::UnitGraph;
 use cargo::core::compiler::{
-    BuildContext, BuildRunner, DefaultExecutor, Executor, Unit, UnitInterner,
+    BuildContext, BuildRunner, Compilation, CompileKind, Executor, Unit, UnitInterner,
 };
 use cargo::ops::{self, CompileOptions};
 use cargo::util::important_paths::find_root_manifest_for_wd;
 use cargo::GlobalContext;
 
+use crate::diagnostics::{CapturingExecutor, Diagnostics};
+use crate::hash::{self, KeyContext, WorkspaceWrapper};
+use crate::native;
+use crate::remap::PathRoots;
+use crate::toolchain;
+
 pub fn resolve_manifest(manifest_path: Option<&Path>, gctx: &GlobalContext) -> Result<PathBuf> {
     match manifest_path {
         Some(p) => Ok(p.to_path_buf()),
@@ -30,7 +37,7 @@ pub fn build_bcx<'a, 'gctx>(
     interner: &'a UnitInterner,
     compile_opts: &'a CompileOptions,
 ) -> Result<BuildContext<'a, 'gctx>> {
-    Ok(ops::create_bcx(ws, compile_opts, interner, None)?)
+    ops::create_bcx(ws, compile_opts, interner, None)
 }
 
 /// Build a `BuildRunner` and prepare it through the planning phase (no
@@ -49,20 +56,247 @@ pub fn prepared_runner<'a, 'gctx>(
     Ok(runner)
 }
 
-pub fn rustc_verbose_version(ws: &Workspace<'_>) -> Result<String> {
-    let gctx = ws.gctx();
-    let rustc = gctx.load_global_rustc(Some(ws))?;
-    Ok(rustc.verbose_version.clone())
+/// The build contexts of one invocation, one per requested profile, with
+/// their unit graphs merged. The contexts share a `UnitInterner`, so a unit
+/// two profiles have in common (a host dependency whose profile doesn't
+/// change) is the same `Unit` and appears once in `unit_graph`.
+pub struct Plan<'a, 'gctx> {
+    pub bcxs: Vec<BuildContext<'a, 'gctx>>,
+    pub unit_graph: UnitGraph,
+    pub roots: Vec<Unit>,
+}
+
+impl<'a, 'gctx> Plan<'a, 'gctx> {
+    pub fn new(
+        ws: &'a Workspace<'gctx>,
+        interner: &'a UnitInterner,
+        compile_opts: &'a [CompileOptions],
+    ) -> Result<Self> {
+        let bcxs = compile_opts
+            .iter()
+            .map(|opts| build_bcx(ws, interner, opts))
+            .collect::<Result<Vec<_>>>()?;
+        let mut unit_graph = UnitGraph::new();
+        let mut roots: Vec<Unit> = Vec::new();
+        for bcx in &bcxs {
+            for (unit, deps) in &bcx.unit_graph {
+                unit_graph.entry(unit.clone()).or_insert_with(|| deps.clone());
+            }
+            for root in &bcx.roots {
+                if !roots.contains(root) {
+                    roots.push(root.clone());
+                }
+            }
+        }
+        Ok(Self { bcxs, unit_graph, roots })
+    }
+
+    /// A prepared runner per build context (see [`prepared_runner`]).
+    pub fn runners(&self) -> Result<Runners<'_, 'gctx>> {
+        let runners = self.bcxs.iter().map(prepared_runner).collect::<Result<_>>()?;
+        Ok(Runners(runners))
+    }
+}
+
+pub struct Runners<'a, 'gctx>(Vec<BuildRunner<'a, 'gctx>>);
+
+impl<'a, 'gctx> Runners<'a, 'gctx> {
+    /// The runner of a build context `unit` belongs to; its paths for the
+    /// unit are the same in every context that has it.
+    pub fn get(&mut self, unit: &Unit) -> &mut BuildRunner<'a, 'gctx> {
+        self.0
+            .iter_mut()
+            .find(|r| r.bcx.unit_graph.contains_key(unit))
+            .expect("unit from the plan")
+    }
+}
+
+/// Everything build-wide that goes into the static keys of `plan`'s units.
+pub fn key_context(ws: &Workspace<'_>, plan: &Plan<'_, '_>) -> Result<KeyContext> {
+    let rustc = ws.gctx().load_global_rustc(Some(ws))?;
+    let rustc_version = toolchain::identity(&rustc)?;
+    let wrapper = workspace_wrapper_identity(ws)?.map(|identity| WorkspaceWrapper {
+        identity,
+        members: ws.members().map(|p| p.package_id()).collect(),
+    });
+    let mut native_cpus = HashMap::new();
+    for unit in plan.unit_graph.keys() {
+        if hash::target_cpu(&unit.rustflags) == Some("native") && !native_cpus.contains_key(&unit.kind) {
+            let cpu = native_cpu(ws, unit.kind)?.unwrap_or_else(|| {
+                tracing::warn!(
+                    "could not tell what `-C target-cpu=native` means on this machine; \
+                     units built with it will not be restored from the cache"
+                );
+                unresolved_native_cpu()
+            });
+            native_cpus.insert(unit.kind, cpu);
+        }
+    }
+    // cargo's own resolution of `host.linker`, `target.<triple>.linker` and
+    // `target.'cfg(..)'.linker`.
+    let mut linkers = HashMap::new();
+    for bcx in &plan.bcxs {
+        let compilation = Compilation::new(bcx)?;
+        for kind in &bcx.all_kinds {
+            if let Some(linker) = compilation.target_linker(*kind) {
+                linkers.insert(*kind, linker);
+            }
+        }
+    }
+    // A wrapper's contents stand in for its version, as the C compiler's do
+    // for build scripts (see `native`).
+    let rustc_wrapper = match &rustc.wrapper {
+        Some(wrapper) => {
+            let binary = wrapper.to_str().and_then(native::find_program);
+            let contents = match binary {
+                Some(binary) => crate::cache::hash_path_current(&binary)?,
+                None => [0; 32],
+            };
+            Some((wrapper.clone(), contents))
+        }
+        None => None,
+    };
+    let mut config_env: Vec<(String, String)> = ws
+        .gctx()
+        .env_config()?
+        .iter()
+        .map(|(k, v)| (k.clone(), v.to_string_lossy().into_owned()))
+        .collect();
+    config_env.sort();
+    let mut package_files = HashMap::new();
+    for unit in plan.unit_graph.keys() {
+        let id = unit.pkg.package_id();
+        if id.source_id().is_path() && !package_files.contains_key(&id) {
+            package_files.insert(id, package_files_of(ws, &unit.pkg)?);
+        }
+    }
+    Ok(KeyContext {
+        rustc_version,
+        host: rustc.host.to_string(),
+        wrapper,
+        paths: PathRoots::new(ws),
+        native_cpus,
+        linkers,
+        rustc_wrapper,
+        config_env,
+        package_files,
+    })
+}
+
+/// The files of a path package that go into its units' keys, sorted: cargo's
+/// own listing plus `Cargo.toml`, but not the workspace's `Cargo.lock`, whose
+/// resolved versions the dependencies' keys already carry.
+fn package_files_of(ws: &Workspace<'_>, pkg: &Package) -> Result<Vec<PathBuf>> {
+    let target_dir = target_dir(ws);
+    let lockfile = ws.lock_root().as_path_unlocked().join("Cargo.lock");
+    let mut files: Vec<PathBuf> = cargo::sources::path::list_files(pkg, ws.gctx())?
+        .iter()
+        .map(|f| f.to_path_buf())
+        .filter(|f| f.is_file() && !f.starts_with(&target_dir) && *f != lockfile)
+        .collect();
+    files.push(pkg.manifest_path().to_path_buf());
+    files.sort();
+    files.dedup();
+    Ok(files)
+}
+
+/// What `-C target-cpu=native` resolves to when compiling for `kind`: the CPU
+/// rustc detects on this machine and the target features it enables. `None`
+/// if rustc's answer isn't in the expected shape.
+pub fn native_cpu(ws: &Workspace<'_>, kind: CompileKind) -> Result<Option<String>> {
+    let rustc = ws.gctx().load_global_rustc(Some(ws))?;
+    let mut cmd = rustc.process();
+    cmd.args(&["--print", "target-cpus", "--print", "cfg", "-C", "target-cpu=native"]);
+    if let CompileKind::Target(target) = kind {
+        cmd.arg("--target").arg(target.rustc_target().as_str());
+    }
+    let output = cmd.exec_with_output()?;
+    Ok(parse_native_cpu(&String::from_utf8_lossy(&output.stdout)))
+}
+
+fn parse_native_cpu(stdout: &str) -> Option<String> {
+    // `    native - Select the CPU of the current host (currently znver4).`
+    let cpu = stdout
+        .lines()
+        .find(|l| l.trim_start().starts_with("native "))
+        .and_then(|l| l.rsplit_once("(currently ")?.1.strip_suffix(")."))?;
+    let mut features: Vec<&str> = stdout.lines().filter(|l| l.starts_with("target_feature=")).collect();
+    features.sort();
+    Some(format!("{cpu} {}", features.join(" ")))
+}
+
+/// A stand-in for a native CPU rustc didn't name: unique to this run, so
+/// keys folding it in match nothing stored, here or on another machine.
+fn unresolved_native_cpu() -> String {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let seed = format!("{}:{}", std::process::id(), now.as_nanos());
+    format!("unresolved-{}", blake3::hash(seed.as_bytes()).to_hex())
+}
+
+/// Identity of the `RUSTC_WORKSPACE_WRAPPER` cargo will use, if any: its
+/// `-V` output (clippy-driver reports its own version) or, for wrappers that
+/// don't answer `-V`, a hash of the binary, plus the `CLIPPY_ARGS` it reads.
+pub fn workspace_wrapper_identity(ws: &Workspace<'_>) -> Result<Option<String>> {
+    let rustc = ws.gctx().load_global_rustc(Some(ws))?;
+    let Some(wrapper) = &rustc.workspace_wrapper else {
+        return Ok(None);
+    };
+    let version = std::process::Command::new(wrapper)
+        .arg("-V")
+        .output()
+        .ok()
+        .filter(|o| o.status.success());
+    let version = match version {
+        Some(o) => String::from_utf8_lossy(&o.stdout).trim().to_string(),
+        None => {
+            let bytes = std::fs::read(wrapper).with_context(|| {
+                format!("running workspace wrapper {}", wrapper.display())
+            })?;
+            blake3::hash(&bytes).to_hex().to_string()
+        }
+    };
+    let clippy_args = ws.gctx().get_env("CLIPPY_ARGS").unwrap_or_default();
+    Ok(Some(format!("{} {version} {clippy_args}", wrapper.display())))
 }
 
 pub fn target_dir(ws: &Workspace<'_>) -> PathBuf {
     ws.target_dir().into_path_unlocked()
 }
 
-pub fn execute_build(ws: &Workspace<'_>, compile_opts: &CompileOptions) -> Result<()> {
-    let exec: Arc<dyn Executor> = Arc::new(DefaultExecutor);
-    ops::compile_with_exec(ws, compile_opts, &exec)?;
-    Ok(())
+/// Run the build, recording the diagnostics rustc emits for each unit it
+/// compiles in `diagnostics`.
+pub fn execute_build<'gctx>(
+    ws: &Workspace<'gctx>,
+    compile_opts: &CompileOptions,
+    diagnostics: &Arc<Diagnostics>,
+) -> Result<Compilation<'gctx>> {
+    let exec: Arc<dyn Executor> = Arc::new(CapturingExecutor(diagnostics.clone()));
+    execute_build_with(ws, compile_opts, &exec)
+}
+
+/// Run the build with rustc invocations going through `exec`.
+pub fn execute_build_with<'gctx>(
+    ws: &Workspace<'gctx>,
+    compile_opts: &CompileOptions,
+    exec: &Arc<dyn Executor>,
+) -> Result<Compilation<'gctx>> {
+    ops::compile_with_exec(ws, compile_opts, exec)
+}
+
+/// The run of `unit`'s own build script, whose `rustc-env` its compilation
+/// sees. `None` for a script run itself: the runs among its deps are its
+/// `links` dependencies', and their env never reaches it.
+pub fn own_script_run<'a>(unit_graph: &'a UnitGraph, unit: &Unit) -> Option<&'a Unit> {
+    if unit.mode.is_run_custom_build() {
+        return None;
+    }
+    unit_graph
+        .get(unit)?
+        .iter()
+        .map(|d| &d.unit)
+        .find(|d| d.mode.is_run_custom_build() && d.pkg.package_id() == unit.pkg.package_id())
 }
 
 /// Topologically order units (deps before consumers).
@@ -96,10 +330,81 @@ pub fn topo_order(unit_graph: &UnitGraph, roots: &[Unit]) -> Vec<Unit> {
 /// Helper: look up a previously-stored env value via cargo's env_config or stdlib env.
 #[allow(dead_code)]
 pub fn env_lookup(gctx: &GlobalContext, name: &str) -> Option<String> {
-    if let Ok(cfg) = gctx.env_config() {
-        if let Some(v) = cfg.get(name) {
-            return v.to_str().map(ToOwned::to_owned);
-        }
+    if let Ok(cfg) = gctx.env_config()
+        && let Some(v) = cfg.get(name)
+    {
+        return v.to_str().map(ToOwned::to_owned);
     }
     gctx.get_env(name).ok().map(|s| s.to_string())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn native_cpu_is_read_from_rustc_or_left_unresolved() {
+        let stdout = "Available CPUs for this target:\n    \
+                      native - Select the CPU of the current host (currently znver4).\n    \
+                      x86-64\ntarget_feature=\"sse2\"\ntarget_feature=\"avx2\"\ntarget_os=\"linux\"\n";
+        assert_eq!(
+            parse_native_cpu(stdout).as_deref(),
+            Some("znver4 target_feature=\"avx2\" target_feature=\"sse2\""),
+        );
+        assert_eq!(parse_native_cpu("native - Select the CPU of the current host.\n"), None);
+    }
+
+    #[test]
+    fn a_script_run_sees_no_other_packages_script_env() {
+        use cargo::core::compiler::{UnitInterner, UserIntent};
+        use cargo::core::shell::Shell;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let write = |rel: &str, contents: &str| {
+            let path = root.join(rel);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        };
+        write(
+            "Cargo.toml",
+            "[package]\nname = \"app\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\
+             [dependencies]\nsys = { path = \"sys\" }\n",
+        );
+        write("build.rs", "fn main() {}\n");
+        write("src/lib.rs", "");
+        // A `links` package: its run is a dependency of app's run too.
+        write(
+            "sys/Cargo.toml",
+            "[package]\nname = \"sys\"\nversion = \"0.1.0\"\nedition = \"2021\"\nlinks = \"sys\"\n",
+        );
+        write("sys/build.rs", "fn main() {}\n");
+        write("sys/src/lib.rs", "");
+
+        let gctx = GlobalContext::new(
+            Shell::from_write(Box::new(std::io::sink())),
+            root.into(),
+            root.join("home"),
+        );
+        let ws = Workspace::new(&root.join("Cargo.toml"), &gctx).unwrap();
+        let opts = [CompileOptions::new(&gctx, UserIntent::Build).unwrap()];
+        let interner = UnitInterner::new();
+        let plan = Plan::new(&ws, &interner, &opts).unwrap();
+        let unit = |pkg: &str, run: bool| {
+            plan.unit_graph
+                .keys()
+                .find(|u| {
+                    u.pkg.name() == pkg
+                        && u.mode.is_run_custom_build() == run
+                        && (run || u.target.is_lib())
+                })
+                .unwrap()
+        };
+        let (app_run, sys_run) = (unit("app", true), unit("sys", true));
+        assert!(plan.unit_graph[app_run].iter().any(|d| &d.unit == sys_run));
+
+        assert_eq!(own_script_run(&plan.unit_graph, unit("app", false)), Some(app_run));
+        assert_eq!(own_script_run(&plan.unit_graph, unit("sys", false)), Some(sys_run));
+        assert_eq!(own_script_run(&plan.unit_graph, app_run), None);
+    }
+}
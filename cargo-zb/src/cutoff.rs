@@ -0,0 +1,385 @@
+//! Early cutoff (`--early-cutoff`): units found by what their dependencies
+//! produced rather than what they were built from.
+//!
+//! Full keys fold in the dependencies' full keys, so touching a low-level
+//! crate misses every unit above it, even when its rebuilt `.rmeta` comes out
+//! byte-identical. In this mode each stored unit also records a cutoff key
+//! (`hash::cutoff_key`): its own key and dynamic content, plus a digest of
+//! everything its compilation reads from its dependencies. cargo still
+//! decides what to rebuild, but [`CutoffExecutor`] gets each unit before
+//! rustc does. By then its dependencies are built, so their outputs can be
+//! hashed, and if they match an earlier build's, the unit's bundle is
+//! restored instead of compiled. Its dependents then see unchanged outputs
+//! in turn.
+//!
+//! What a compilation reads of its dependencies, transitively:
+//!
+//! - a library (which cargo pipelines) reads the libraries' `.rmeta`;
+//! - anything that links (binaries, tests, proc macros, dylibs, build
+//!   scripts) reads their full outputs;
+//! - either reads the build-script runs below it: their `output`, `OUT_DIR`
+//!   and the native libraries they link (see `native`). A script's own
+//!   dependencies show only through what it emitted.
+//!
+//! Build-script runs aren't rustc invocations; they run as usual. A rerun
+//! that emits the same output is where the cutoff pays off most, as rustc
+//! records source file hashes in `.rmeta`.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use cargo::core::compiler::{BuildRunner, CompileMode, Executor, FileFlavor, Unit};
+use cargo::core::{PackageId, Target};
+use cargo::util::errors::CargoResult;
+use cargo_util::ProcessBuilder;
+
+use crate::artifacts;
+use crate::cache::{CacheBackend, hash_path_current};
+use crate::cargo_interop::Plan;
+use crate::diagnostics::{self, CapturingExecutor, Diagnostics, UnitId};
+use crate::harvest::ScriptOutputs;
+use crate::hash::{self, CacheKey};
+use crate::remap::PathRoots;
+
+/// What a consumer reads of one dependency.
+enum Output {
+    /// Library metadata, or a unit's rustc outputs in full.
+    Files(Vec<PathBuf>),
+    /// A build-script run, in its `build/<pkg>-<hash>/` dir.
+    Script { run_dir: PathBuf, target: String },
+}
+
+impl Output {
+    fn of(runner: &BuildRunner<'_, '_>, unit: &Unit, metadata_only: bool) -> Result<Self> {
+        if unit.mode.is_run_custom_build() {
+            return Ok(Output::Script {
+                run_dir: runner.files().build_script_run_dir(unit),
+                target: runner.bcx.target_data.short_name(&unit.kind).to_string(),
+            });
+        }
+        let outputs = runner.outputs(unit)?;
+        let files = |keep: fn(&FileFlavor) -> bool| -> Vec<PathBuf> {
+            outputs
+                .iter()
+                .filter(|o| keep(&o.flavor))
+                .map(|o| o.path.clone())
+                .collect()
+        };
+        let rmeta = files(|f| *f == FileFlavor::Rmeta);
+        Ok(Output::Files(if metadata_only && !rmeta.is_empty() {
+            rmeta
+        } else {
+            files(|f| {
+                !matches!(
+                    f,
+                    FileFlavor::DebugInfo | FileFlavor::Sbom | FileFlavor::DocParts
+                )
+            })
+        }))
+    }
+
+    /// `None` while (or if) the dependency hasn't produced it.
+    fn digest(&self, roots: &PathRoots, target_dir: &Path) -> Result<Option<[u8; 32]>> {
+        let mut hasher = blake3::Hasher::new();
+        match self {
+            Output::Files(paths) => {
+                hasher.update(b"files\0");
+                for path in paths {
+                    let hash = hash_path_current(path)?;
+                    if hash == [0; 32] {
+                        return Ok(None);
+                    }
+                    hasher.update(roots.normalize(path).to_string_lossy().as_bytes());
+                    hasher.update(b"\0");
+                    hasher.update(&hash);
+                }
+            }
+            Output::Script { run_dir, target } => {
+                let path = run_dir.join("output");
+                let output = match std::fs::read_to_string(&path) {
+                    Ok(o) => o,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                    Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+                };
+                hasher.update(b"script\0");
+                hasher.update(roots.normalize_str(&output).as_bytes());
+                hasher.update(b"\0");
+                hasher.update(&hash_path_current(&run_dir.join("out"))?);
+                let (native, _) =
+                    crate::native::build_script_inputs(&output, target, target_dir, |n| {
+                        std::env::var(n).ok()
+                    });
+                for path in native {
+                    hasher.update(roots.normalize(&path).to_string_lossy().as_bytes());
+                    hasher.update(b"\0");
+                    hasher.update(&hash_path_current(&path)?);
+                }
+            }
+        }
+        Ok(Some(*hasher.finalize().as_bytes()))
+    }
+}
+
+/// A unit cargo may hand to the executor.
+struct CutoffUnit {
+    name: String,
+    /// The unit's own key: its cutoff manifests are stored under it.
+    own_key: CacheKey,
+    /// Indices into [`Cutoffs::outputs`].
+    reads: Vec<usize>,
+    /// `output` of the package's build-script run, for `rustc-env` vars.
+    script_output: Option<PathBuf>,
+    fingerprint_dir: PathBuf,
+}
+
+/// The plan's units as the executor sees them, with what each one reads.
+pub struct Cutoffs {
+    units: HashMap<UnitId, CutoffUnit>,
+    outputs: Vec<Output>,
+    /// Digests of `outputs` taken so far. Only consumers ask, and cargo
+    /// starts them once what they read is final.
+    digests: Mutex<HashMap<usize, [u8; 32]>>,
+    roots: PathRoots,
+    target_dir: PathBuf,
+}
+
+impl Cutoffs {
+    pub fn new(
+        plan: &Plan<'_, '_>,
+        own_keys: &HashMap<Unit, CacheKey>,
+        roots: &PathRoots,
+        target_dir: &Path,
+    ) -> Result<Self> {
+        let mut runners = plan.runners()?;
+        let mut outputs = Vec::new();
+        let mut index: HashMap<(Unit, bool), usize> = HashMap::new();
+        let mut units = HashMap::new();
+        for unit in plan.unit_graph.keys() {
+            if unit.mode.is_run_custom_build() || unit.mode.is_doc() || unit.mode.is_doc_test() {
+                continue;
+            }
+            let metadata_only = !unit.requires_upstream_objects();
+            let mut reads = Vec::new();
+            let mut seen = HashSet::new();
+            let mut stack: Vec<&Unit> = plan.unit_graph[unit].iter().map(|d| &d.unit).collect();
+            while let Some(dep) = stack.pop() {
+                if !seen.insert(dep) {
+                    continue;
+                }
+                let i = match index.get(&(dep.clone(), metadata_only)) {
+                    Some(i) => *i,
+                    None => {
+                        outputs.push(Output::of(runners.get(dep), dep, metadata_only)?);
+                        index.insert((dep.clone(), metadata_only), outputs.len() - 1);
+                        outputs.len() - 1
+                    }
+                };
+                reads.push(i);
+                if !dep.mode.is_run_custom_build() {
+                    stack.extend(plan.unit_graph[dep].iter().map(|d| &d.unit));
+                }
+            }
+            let script_output = crate::cargo_interop::own_script_run(&plan.unit_graph, unit)
+                .map(|run| runners.get(run).files().build_script_run_dir(run).join("output"));
+            let runner = runners.get(unit);
+            units.insert(
+                diagnostics::unit_id(runner, unit),
+                CutoffUnit {
+                    name: format!("{} ({})", unit.pkg.name(), unit.target.name()),
+                    own_key: own_keys[unit],
+                    reads,
+                    script_output,
+                    fingerprint_dir: runner.files().fingerprint_dir(unit),
+                },
+            );
+        }
+        Ok(Self {
+            units,
+            outputs,
+            digests: Mutex::new(HashMap::new()),
+            roots: roots.clone(),
+            target_dir: target_dir.to_path_buf(),
+        })
+    }
+
+    /// The cutoff key of the unit `id` with dynamic content `content`, from
+    /// its dependencies' outputs as they are now.
+    pub fn key(&self, id: &UnitId, content: &[u8; 32]) -> Result<Option<CacheKey>> {
+        let Some(unit) = self.units.get(id) else {
+            return Ok(None);
+        };
+        Ok(self
+            .dep_outputs(unit)?
+            .map(|outputs| hash::cutoff_key(&unit.own_key, content, &outputs)))
+    }
+
+    fn dep_outputs(&self, unit: &CutoffUnit) -> Result<Option<Vec<[u8; 32]>>> {
+        let mut digests = Vec::with_capacity(unit.reads.len());
+        for &i in &unit.reads {
+            let known = self.digests.lock().unwrap().get(&i).copied();
+            let digest = match known {
+                Some(d) => d,
+                None => match self.outputs[i].digest(&self.roots, &self.target_dir)? {
+                    Some(d) => *self.digests.lock().unwrap().entry(i).or_insert(d),
+                    None => return Ok(None),
+                },
+            };
+            digests.push(digest);
+        }
+        Ok(Some(digests))
+    }
+
+    /// Full keys of the stored bundles for `unit` as it would build now.
+    fn lookup(&self, cache: &dyn CacheBackend, unit: &CutoffUnit) -> Result<Vec<[u8; 32]>> {
+        let Some(outputs) = self.dep_outputs(unit)? else {
+            return Ok(Vec::new());
+        };
+        let script = unit
+            .script_output
+            .as_deref()
+            .map(ScriptOutputs::read_file)
+            .transpose()?;
+        let mut found = Vec::new();
+        for inputs in cache.list_dynamic_inputs(unit.own_key.as_bytes())? {
+            if inputs.cutoffs.is_empty() {
+                continue;
+            }
+            let content = inputs.content_hash(&self.roots, |n| match &script {
+                Some(outputs) => outputs.lookup(n),
+                None => std::env::var(n).ok(),
+            })?;
+            let key = hash::cutoff_key(&unit.own_key, &content, &outputs);
+            found.extend(
+                inputs
+                    .cutoffs
+                    .iter()
+                    .filter(|c| c.key == *key.as_bytes())
+                    .map(|c| c.unit_key),
+            );
+        }
+        Ok(found)
+    }
+}
+
+/// [`CapturingExecutor`], restoring instead of compiling a unit whose
+/// cutoff key has a stored bundle.
+pub struct CutoffExecutor {
+    cutoffs: Arc<Cutoffs>,
+    cache: Arc<dyn CacheBackend>,
+    io_threads: usize,
+    inner: CapturingExecutor,
+    restored: AtomicUsize,
+}
+
+impl CutoffExecutor {
+    pub fn new(
+        cutoffs: Arc<Cutoffs>,
+        cache: Arc<dyn CacheBackend>,
+        io_threads: usize,
+        diagnostics: Arc<Diagnostics>,
+    ) -> Self {
+        Self {
+            cutoffs,
+            cache,
+            io_threads,
+            inner: CapturingExecutor(diagnostics),
+            restored: AtomicUsize::new(0),
+        }
+    }
+
+    /// Units restored so far.
+    pub fn restored(&self) -> usize {
+        self.restored.load(Ordering::Relaxed)
+    }
+
+    fn try_restore(&self, unit: &CutoffUnit) -> Result<bool> {
+        for unit_key in self.cutoffs.lookup(&*self.cache, unit)? {
+            let restored = std::thread::scope(|scope| -> Result<bool> {
+                let mut restorer = artifacts::Restorer::start(
+                    scope,
+                    &*self.cache,
+                    &self.cutoffs.target_dir,
+                    &self.cutoffs.roots,
+                    self.io_threads,
+                );
+                let submitted = restorer.submit(&unit_key)?.is_some();
+                Ok(submitted && restorer.finish()?.failed.is_empty())
+            })?;
+            if restored {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+impl Executor for CutoffExecutor {
+    fn exec(
+        &self,
+        cmd: &ProcessBuilder,
+        id: PackageId,
+        target: &Target,
+        mode: CompileMode,
+        on_stdout_line: &mut dyn FnMut(&str) -> CargoResult<()>,
+        on_stderr_line: &mut dyn FnMut(&str) -> CargoResult<()>,
+    ) -> CargoResult<()> {
+        if let Some(unit) = self
+            .cutoffs
+            .units
+            .get(&diagnostics::exec_unit_id(cmd, id, target, mode))
+        {
+            match self.try_restore(unit) {
+                Ok(true) => {
+                    tracing::debug!("early cutoff: restored {}", unit.name);
+                    self.restored.fetch_add(1, Ordering::Relaxed);
+                    return diagnostics::forward(&unit.fingerprint_dir, on_stderr_line);
+                }
+                Ok(false) => {}
+                Err(e) => tracing::debug!("early cutoff lookup failed for {}: {e:#}", unit.name),
+            }
+        }
+        self.inner
+            .exec(cmd, id, target, mode, on_stdout_line, on_stderr_line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn script_digest_follows_what_consumers_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let roots = PathRoots::from_roots(Path::new("/ws"), Path::new("/home"), dir.path());
+        let run_dir = dir.path().join("build/foo-1234");
+        std::fs::create_dir_all(run_dir.join("out")).unwrap();
+        let script = Output::Script {
+            run_dir: run_dir.clone(),
+            target: "x86_64-unknown-linux-gnu".into(),
+        };
+        let digest = || script.digest(&roots, dir.path()).unwrap();
+
+        assert_eq!(digest(), None);
+        std::fs::write(run_dir.join("output"), "cargo:rustc-cfg=fast\n").unwrap();
+        std::fs::write(run_dir.join("out/gen.rs"), "pub const X: u32 = 1;").unwrap();
+        let first = digest().unwrap();
+
+        // A rerun leaves the same output behind: same digest.
+        std::fs::write(run_dir.join("stderr"), "warning: rerun\n").unwrap();
+        std::fs::write(run_dir.join("output"), "cargo:rustc-cfg=fast\n").unwrap();
+        assert_eq!(digest(), Some(first));
+
+        std::fs::write(run_dir.join("out/gen.rs"), "pub const X: u32 = 2;").unwrap();
+        assert_ne!(digest(), Some(first));
+
+        let rmeta = dir.path().join("debug/deps/libfoo-1234.rmeta");
+        let files = Output::Files(vec![rmeta.clone()]);
+        assert_eq!(files.digest(&roots, dir.path()).unwrap(), None);
+        std::fs::create_dir_all(rmeta.parent().unwrap()).unwrap();
+        std::fs::write(&rmeta, "rust").unwrap();
+        assert!(files.digest(&roots, dir.path()).unwrap().is_some());
+    }
+}
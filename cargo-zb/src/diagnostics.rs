@@ -0,0 +1,283 @@
+//! Compiler diagnostics captured during a build and replayed on cache hits.
+//!
+//! When every unit hits, cargo never runs, so nothing would print the
+//! warnings the original build emitted. Phase 2 therefore runs rustc through
+//! [`CapturingExecutor`], which keeps each unit's JSON diagnostic lines. The
+//! harvest writes them to `zb-diagnostics` in the unit's fingerprint
+//! directory, so they are stored and restored with the rest of the bundle,
+//! and an all-hit run replays them the way cargo prints them: each rendered
+//! diagnostic, then "`pkg` (lib) generated N warnings".
+//!
+//! Runs where cargo does execute need none of this: cargo replays fresh
+//! units' diagnostics from its own `output-*` cache in the same directory.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use cargo::core::compiler::{BuildRunner, CompileMode, Executor, MessageFormat, Unit};
+use cargo::core::shell::Shell;
+use cargo::core::{PackageId, Target};
+use cargo::util::errors::CargoResult;
+use cargo::util::machine_message::{FromCompiler, Message};
+use cargo_util::ProcessBuilder;
+use serde::Deserialize;
+use serde_json::value::RawValue;
+
+const FILE_NAME: &str = "zb-diagnostics";
+
+/// The executor's view of a unit. `-C metadata` tells apart units of one
+/// target that differ in kind or profile, e.g. a build dependency and the
+/// same crate as a normal one, or one crate built for two `--target`s.
+pub type UnitId = (PackageId, Target, CompileMode, String);
+
+/// The id under which an executor sees `unit` compiled.
+pub fn unit_id(runner: &BuildRunner<'_, '_>, unit: &Unit) -> UnitId {
+    let metadata = runner.files().metadata(unit).c_metadata().to_string();
+    (unit.pkg.package_id(), unit.target.clone(), unit.mode, metadata)
+}
+
+/// The id of the unit an [`Executor::exec`] call compiles.
+pub fn exec_unit_id(
+    cmd: &ProcessBuilder,
+    id: PackageId,
+    target: &Target,
+    mode: CompileMode,
+) -> UnitId {
+    let metadata = cmd
+        .get_args()
+        .find_map(|a| a.to_str()?.strip_prefix("metadata="))
+        .unwrap_or_default()
+        .to_string();
+    (id, target.clone(), mode, metadata)
+}
+
+/// Diagnostic lines per compiled unit. Units cargo found fresh are absent.
+#[derive(Default)]
+pub struct Diagnostics(Mutex<HashMap<UnitId, Vec<String>>>);
+
+impl Diagnostics {
+    fn take(&self, runner: &BuildRunner<'_, '_>, unit: &Unit) -> Option<Vec<String>> {
+        self.0.lock().unwrap().remove(&unit_id(runner, unit))
+    }
+}
+
+/// `DefaultExecutor`, plus a copy of every diagnostic rustc emits.
+pub struct CapturingExecutor(pub Arc<Diagnostics>);
+
+impl Executor for CapturingExecutor {
+    fn exec(
+        &self,
+        cmd: &ProcessBuilder,
+        id: PackageId,
+        target: &Target,
+        mode: CompileMode,
+        on_stdout_line: &mut dyn FnMut(&str) -> CargoResult<()>,
+        on_stderr_line: &mut dyn FnMut(&str) -> CargoResult<()>,
+    ) -> CargoResult<()> {
+        let unit = exec_unit_id(cmd, id, target, mode);
+        let mut captured = Vec::new();
+        let result = cmd.exec_with_streaming(
+            on_stdout_line,
+            &mut |line| {
+                if line.starts_with('{') && line.contains(r#""$message_type":"diagnostic""#) {
+                    captured.push(line.to_string());
+                }
+                on_stderr_line(line)
+            },
+            false,
+        );
+        self.0.0.lock().unwrap().insert(unit, captured);
+        result.map(drop)
+    }
+}
+
+/// Hand a unit's restored diagnostics to cargo in place of rustc's, so a
+/// unit restored mid-build prints (and caches) them like a compiled one.
+pub fn forward(
+    fingerprint_dir: &Path,
+    on_stderr_line: &mut dyn FnMut(&str) -> CargoResult<()>,
+) -> CargoResult<()> {
+    let path = fingerprint_dir.join(FILE_NAME);
+    let data = match std::fs::read_to_string(&path) {
+        Ok(d) => d,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+    };
+    data.lines().try_for_each(on_stderr_line)
+}
+
+/// Record what `unit` emitted in this build next to its fingerprint, ready
+/// to be collected into its bundle. A unit that compiled cleanly drops any
+/// file left by an earlier build; one cargo didn't compile keeps its own.
+pub fn write_for_unit(
+    runner: &BuildRunner<'_, '_>,
+    diagnostics: &Diagnostics,
+    unit: &Unit,
+) -> Result<()> {
+    let Some(lines) = diagnostics.take(runner, unit) else {
+        return Ok(());
+    };
+    let path = runner.files().fingerprint_dir(unit).join(FILE_NAME);
+    if lines.is_empty() {
+        return match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("removing {}", path.display()))
+            }
+            _ => Ok(()),
+        };
+    }
+    let mut data = lines.join("\n");
+    data.push('\n');
+    std::fs::write(&path, data).with_context(|| format!("writing {}", path.display()))
+}
+
+#[derive(Deserialize)]
+struct CompilerMessage {
+    rendered: String,
+    message: String,
+    level: String,
+}
+
+/// Print a restored unit's diagnostics as cargo would have in `format`:
+/// rendered on stderr, or as `compiler-message` lines on stdout for JSON
+/// output that doesn't render them.
+pub fn replay(
+    shell: &mut Shell,
+    runner: &BuildRunner<'_, '_>,
+    unit: &Unit,
+    format: MessageFormat,
+) -> Result<()> {
+    let path = runner.files().fingerprint_dir(unit).join(FILE_NAME);
+    let data = match std::fs::read_to_string(&path) {
+        Ok(d) => d,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+    };
+    let (messages, warnings) = render(&data, format)?;
+    for message in messages {
+        if let MessageFormat::Json {
+            render_diagnostics: false,
+            ..
+        } = format
+        {
+            let msg = FromCompiler {
+                package_id: unit.pkg.package_id().to_spec(),
+                manifest_path: unit.pkg.manifest_path(),
+                target: &unit.target,
+                message: RawValue::from_string(message)?,
+            }
+            .to_json_string();
+            drop(writeln!(shell.out(), "{msg}"));
+        } else {
+            shell.err().write_all(message.as_bytes())?;
+        }
+    }
+    if warnings > 0 {
+        let plural = if warnings == 1 { "" } else { "s" };
+        shell.warn(format!(
+            "{} generated {warnings} warning{plural}",
+            describe(unit)
+        ))?;
+    }
+    Ok(())
+}
+
+/// The stored diagnostics in the form `format` prints them, either rendered
+/// text or rustc's JSON, and the number of warnings among them.
+fn render(data: &str, format: MessageFormat) -> Result<(Vec<String>, usize)> {
+    let mut out = Vec::new();
+    let mut warnings = 0;
+    for line in data.lines() {
+        let Ok(msg) = serde_json::from_str::<CompilerMessage>(line) else {
+            continue;
+        };
+        // rustc's own summaries; cargo prints its per-unit one instead.
+        if msg.message.starts_with("aborting due to")
+            || msg.message.ends_with("warning emitted")
+            || msg.message.ends_with("warnings emitted")
+        {
+            continue;
+        }
+        if msg.level == "warning" {
+            warnings += 1;
+        }
+        match format {
+            // rustc is always asked for ANSI colors; like cargo, drop them
+            // unless they were requested.
+            MessageFormat::Json {
+                render_diagnostics: false,
+                ansi: false,
+                ..
+            } => {
+                let mut value: serde_json::Map<String, serde_json::Value> =
+                    serde_json::from_str(line)?;
+                let plain = anstream::adapter::strip_str(&msg.rendered).to_string();
+                value.insert("rendered".into(), plain.into());
+                out.push(serde_json::to_string(&value)?);
+            }
+            MessageFormat::Json {
+                render_diagnostics: false,
+                ansi: true,
+                ..
+            } => out.push(line.to_string()),
+            _ => out.push(msg.rendered),
+        }
+    }
+    Ok((out, warnings))
+}
+
+/// cargo's name for a unit in warning summaries, e.g. "`foo` (bin "bar" test)".
+fn describe(unit: &Unit) -> String {
+    let mode = if unit.mode.is_rustc_test() && !(unit.target.is_test() || unit.target.is_bench()) {
+        " test"
+    } else {
+        ""
+    };
+    format!(
+        "`{}` ({}{mode})",
+        unit.pkg.name(),
+        unit.target.description_named()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_skips_rustc_summaries() {
+        let data = [
+            r#"{"$message_type":"diagnostic","message":"unused variable: `x`","level":"warning","rendered":"warning: unused variable: `x`\n\n"}"#,
+            r#"{"$message_type":"diagnostic","message":"consider using a slice","level":"help","rendered":"help: consider using a slice\n\n"}"#,
+            r#"{"$message_type":"diagnostic","message":"1 warning emitted","level":"warning","rendered":"warning: 1 warning emitted\n"}"#,
+        ]
+        .join("\n");
+        let (out, warnings) = render(&data, MessageFormat::Human).unwrap();
+        assert_eq!(warnings, 1);
+        assert_eq!(
+            out,
+            [
+                "warning: unused variable: `x`\n\n",
+                "help: consider using a slice\n\n"
+            ]
+        );
+    }
+
+    #[test]
+    fn render_json_strips_colors_unless_asked() {
+        let line = r#"{"$message_type":"diagnostic","message":"m","level":"warning","rendered":"\u001b[33mwarning\u001b[0m: m\n"}"#;
+        let json = |ansi| MessageFormat::Json {
+            short: false,
+            ansi,
+            render_diagnostics: false,
+        };
+        let (out, _) = render(line, json(false)).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&out[0]).unwrap();
+        assert_eq!(value["rendered"], "warning: m\n");
+        assert_eq!(value["$message_type"], "diagnostic");
+        let (out, _) = render(line, json(true)).unwrap();
+        assert_eq!(out, [line]);
+    }
+}
@@ -0,0 +1,290 @@
+//! `cargo zb gc`: bound the cache by size and age.
+//!
+//! Unit bundles are evicted least-recently-used first, using the access time
+//! backends record on every restore. Artifact contents are shared blobs (see
+//! `cache::cas`): refcounts are taken from the unit manifests, and evicting a
+//! unit frees only the blobs no remaining unit references. Unreferenced
+//! blobs, whether orphaned or freed by an eviction, are removed only once
+//! past a grace period that covers stores still in flight. Dynamic-inputs
+//! manifests carry the full keys of the bundles stored from them
+//! (`DynamicInputs::unit_keys`); once none of those exist the manifest can
+//! never lead to a hit and is dropped too.
+//!
+//! Safe to run next to a build: removals are atomic per unit (rename-then-
+//! delete for `fs`, one write transaction for `lmdb`), and a restore that
+//! loses its bundle midway reports a miss instead of failing.
+
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use tracing::{debug, info};
+
+use crate::cache::cas::BlobInfo;
+use crate::cache::{self, CacheBackend};
+
+#[derive(Debug, Default)]
+pub struct GcReport {
+    pub units_kept: usize,
+    pub bytes_kept: u64,
+    pub units_evicted: usize,
+    pub bytes_evicted: u64,
+    pub blobs_removed: usize,
+    pub manifests_removed: usize,
+}
+
+/// How old an unreferenced blob must be before it counts as garbage rather
+/// than part of a store whose manifest hasn't been written yet.
+const BLOB_GRACE: Duration = Duration::from_secs(3600);
+
+pub fn run_gc(
+    cache: &dyn CacheBackend,
+    max_size: Option<u64>,
+    max_age: Option<Duration>,
+) -> Result<GcReport> {
+    let mut units = cache.list_units()?;
+    // Oldest first; ties broken by key so repeated runs evict deterministically.
+    units.sort_by(|a, b| a.last_access.cmp(&b.last_access).then_with(|| a.key.cmp(&b.key)));
+
+    let mut report = GcReport::default();
+    let mut refs: HashMap<&str, usize> = HashMap::new();
+    for unit in &units {
+        for blob in &unit.blobs {
+            *refs.entry(blob.as_str()).or_default() += 1;
+        }
+    }
+
+    // A store reuses an existing blob by bumping its `modified` (fs: before
+    // it writes the manifest that references it), so only blobs past the
+    // grace period may go once nothing references them.
+    let grace_cutoff = SystemTime::now().checked_sub(BLOB_GRACE);
+    let settled = |blob: &BlobInfo| grace_cutoff.is_some_and(|c| blob.modified < c);
+    let mut blobs: HashMap<String, BlobInfo> = HashMap::new();
+    for blob in cache.list_blobs()? {
+        let unreferenced = !refs.contains_key(blob.name.as_str());
+        if unreferenced && settled(&blob) {
+            debug!("removing unreferenced blob {}", blob.name);
+            cache.remove_blob(&blob.name)?;
+            report.blobs_removed += 1;
+            report.bytes_evicted += blob.size;
+        } else {
+            blobs.insert(blob.name.clone(), blob);
+        }
+    }
+
+    let cutoff = max_age.and_then(|age| SystemTime::now().checked_sub(age));
+    let mut total: u64 =
+        units.iter().map(|u| u.size).sum::<u64>() + blobs.values().map(|b| b.size).sum::<u64>();
+
+    for unit in &units {
+        let too_old = cutoff.is_some_and(|c| unit.last_access < c);
+        let too_big = max_size.is_some_and(|max| total > max);
+        if !too_old && !too_big {
+            report.units_kept += 1;
+            continue;
+        }
+        debug!("evicting unit {} ({} bytes)", cache::hex(&unit.key), unit.size);
+        cache.remove_unit(&unit.key)?;
+        total -= unit.size;
+        report.units_evicted += 1;
+        report.bytes_evicted += unit.size;
+        for blob in &unit.blobs {
+            let count = refs.get_mut(blob.as_str()).expect("counted above");
+            *count -= 1;
+            if *count > 0 {
+                continue;
+            }
+            if let Some(info) = blobs.get(blob)
+                && settled(info)
+            {
+                cache.remove_blob(blob)?;
+                total -= info.size;
+                report.blobs_removed += 1;
+                report.bytes_evicted += info.size;
+                blobs.remove(blob);
+            }
+        }
+    }
+    report.bytes_kept = total;
+
+    // Re-check existence rather than trusting the list above: a concurrent
+    // build may have stored new bundles since.
+    for (static_key, inputs) in cache.list_all_dynamic_inputs()? {
+        if inputs.unit_keys.is_empty() {
+            continue; // written before key tracking; can't tell, keep it
+        }
+        let mut live = false;
+        for k in &inputs.unit_keys {
+            if cache.contains_unit(k)? {
+                live = true;
+                break;
+            }
+        }
+        if !live {
+            cache.remove_dynamic_inputs(&static_key, &inputs.shape_hash())?;
+            report.manifests_removed += 1;
+        }
+    }
+
+    info!(
+        "cargo-zb gc: evicted {} units and {} blobs ({}), removed {} orphaned manifests; {} units ({}) remain",
+        report.units_evicted,
+        report.blobs_removed,
+        format_bytes(report.bytes_evicted),
+        report.manifests_removed,
+        report.units_kept,
+        format_bytes(report.bytes_kept),
+    );
+    Ok(report)
+}
+
+/// Parse a size like `20G`, `512M`, `1.5T` or a plain byte count. Suffixes
+/// are binary (`G` = GiB); a trailing `B`/`iB` is accepted.
+pub fn parse_size(s: &str) -> Result<u64> {
+    let t = s.trim();
+    let t = t.strip_suffix("iB").or_else(|| t.strip_suffix('B')).unwrap_or(t);
+    let (num, mult) = match t.chars().last() {
+        Some('K' | 'k') => (&t[..t.len() - 1], 1u64 << 10),
+        Some('M' | 'm') => (&t[..t.len() - 1], 1 << 20),
+        Some('G' | 'g') => (&t[..t.len() - 1], 1 << 30),
+        Some('T' | 't') => (&t[..t.len() - 1], 1 << 40),
+        _ => (t, 1),
+    };
+    let n: f64 = num
+        .trim()
+        .parse()
+        .with_context(|| format!("invalid size {s:?} (expected e.g. 20G, 512M)"))?;
+    anyhow::ensure!(n >= 0.0, "invalid size {s:?}");
+    Ok((n * mult as f64) as u64)
+}
+
+/// Parse an age like `14d`, `12h`, `30m`, `2w` or plain seconds.
+pub fn parse_age(s: &str) -> Result<Duration> {
+    let t = s.trim();
+    let (num, mult) = match t.chars().last() {
+        Some('s') => (&t[..t.len() - 1], 1u64),
+        Some('m') => (&t[..t.len() - 1], 60),
+        Some('h') => (&t[..t.len() - 1], 3600),
+        Some('d') => (&t[..t.len() - 1], 86400),
+        Some('w') => (&t[..t.len() - 1], 7 * 86400),
+        _ => (t, 1),
+    };
+    let n: u64 = num
+        .trim()
+        .parse()
+        .with_context(|| format!("invalid age {s:?} (expected e.g. 14d, 12h)"))?;
+    let secs = n.checked_mul(mult).with_context(|| format!("age {s:?} is too large"))?;
+    Ok(Duration::from_secs(secs))
+}
+
+fn format_bytes(bytes: u64) -> String {
+    if bytes >= 1 << 30 {
+        format!("{:.1} GiB", bytes as f64 / (1u64 << 30) as f64)
+    } else if bytes >= 1 << 20 {
+        format!("{:.1} MiB", bytes as f64 / (1u64 << 20) as f64)
+    } else {
+        format!("{:.0} KiB", bytes as f64 / 1024.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::fs::FsCache;
+    use crate::cache::DynamicInputs;
+
+    fn store(cache: &FsCache, name: &str, contents: &[u8]) -> [u8; 32] {
+        let key = *blake3::hash(name.as_bytes()).as_bytes();
+        cache.put_artifact(&key, "debug/libx.rlib", contents).unwrap();
+        cache.finalize_unit(&key, &["debug/libx.rlib".into()]).unwrap();
+        key
+    }
+
+    /// Move every blob's mtime past the grace period.
+    fn settle_blobs(cache: &FsCache) {
+        let past = SystemTime::now() - 2 * BLOB_GRACE;
+        for blob in cache.list_blobs().unwrap() {
+            let file = std::fs::File::open(cache.blob_path(&blob.name)).unwrap();
+            file.set_modified(past).unwrap();
+        }
+    }
+
+    #[test]
+    fn evicts_lru_and_orphaned_manifests() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FsCache::new(dir.path()).unwrap();
+        let old = store(&cache, "old", &[1u8; 1000]);
+        std::thread::sleep(Duration::from_millis(20));
+        let new = store(&cache, "new", &[2u8; 1000]);
+        std::thread::sleep(Duration::from_millis(20));
+        // Restoring `old` makes it the most recently used.
+        cache.touch_units(&[old]).unwrap();
+
+        let static_key = *blake3::hash(b"static").as_bytes();
+        let inputs = DynamicInputs { unit_keys: vec![new], ..Default::default() };
+        cache.put_dynamic_inputs(&static_key, &inputs).unwrap();
+
+        settle_blobs(&cache);
+        let report = run_gc(&cache, Some(1500), None).unwrap();
+        assert_eq!(report.units_evicted, 1);
+        assert_eq!(report.blobs_removed, 1);
+        assert_eq!(report.manifests_removed, 1);
+        assert!(cache.contains_unit(&old).unwrap());
+        assert!(!cache.contains_unit(&new).unwrap());
+        assert!(cache.list_dynamic_inputs(&static_key).unwrap().is_empty());
+    }
+
+    #[test]
+    fn shared_blobs_outlive_one_referrer() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FsCache::new(dir.path()).unwrap();
+        let a = store(&cache, "a", &[7u8; 1000]);
+        std::thread::sleep(Duration::from_millis(20));
+        let b = store(&cache, "b", &[7u8; 1000]);
+        assert_eq!(cache.list_blobs().unwrap().len(), 1);
+
+        // Room for the blob and one manifest: only `a`'s manifest goes, the
+        // blob is still `b`'s.
+        let manifest = cache.list_units().unwrap()[0].size;
+        let report = run_gc(&cache, Some(1000 + manifest), None).unwrap();
+        assert_eq!((report.units_evicted, report.blobs_removed), (1, 0));
+        assert!(!cache.contains_unit(&a).unwrap());
+        let dest = dir.path().join("restored");
+        cache.list_artifacts(&b).unwrap();
+        assert!(cache.restore_artifact(&b, "debug/libx.rlib", &dest).unwrap());
+
+        settle_blobs(&cache);
+        let report = run_gc(&cache, Some(0), None).unwrap();
+        assert_eq!((report.units_evicted, report.blobs_removed), (1, 1));
+        assert!(cache.list_blobs().unwrap().is_empty());
+    }
+
+    #[test]
+    fn evictions_keep_blobs_a_store_may_be_reusing() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FsCache::new(dir.path()).unwrap();
+        let old = store(&cache, "old", &[3u8; 1000]);
+        settle_blobs(&cache);
+        // A build storing the same contents bumps the blob, then writes its
+        // manifest after gc has listed the units.
+        store(&cache, "racing", &[3u8; 1000]);
+        cache.remove_unit(blake3::hash(b"racing").as_bytes()).unwrap();
+
+        let report = run_gc(&cache, Some(0), None).unwrap();
+        assert_eq!((report.units_evicted, report.blobs_removed), (1, 0));
+        assert!(!cache.contains_unit(&old).unwrap());
+        assert_eq!(cache.list_blobs().unwrap().len(), 1);
+        assert_eq!(report.bytes_kept, 1000);
+    }
+
+    #[test]
+    fn parses_sizes_and_ages() {
+        assert_eq!(parse_size("20G").unwrap(), 20 << 30);
+        assert_eq!(parse_size("512MiB").unwrap(), 512 << 20);
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert_eq!(parse_age("14d").unwrap(), Duration::from_secs(14 * 86400));
+        assert_eq!(parse_age("90").unwrap(), Duration::from_secs(90));
+        assert!(parse_age("99999999999999999w").is_err());
+        assert!(parse_size("lots").is_err());
+    }
+}
@@ -13,32 +13,40 @@
 //!
 //! 2. **Build script `output` file** at `target/<target>/release/build/<pkg>-<unit_hash>/output`
 //!    for `RunCustomBuild` units. Captures `cargo:rerun-if-changed=PATH` and
-//!    `cargo:rerun-if-env-changed=NAME` declarations verbatim.
+//!    `cargo:rerun-if-env-changed=NAME` declarations verbatim (or, for a script
+//!    that declares neither, the package's whole file list), plus the C
+//!    toolchain and system libraries behind any `rustc-link-*` lines (see
+//!    `native`).
 
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::path::{Path, PathBuf};
 
 use anyhow::{Context, Result};
 use cargo::core::compiler::{BuildRunner, CompileMode, Unit};
 
-use crate::cache::{hash_path_current, DynEnv, DynPath, DynamicInputs};
+use crate::cache::{hash_path_current, CacheBackend, DynEnv, DynPath, DynamicInputs};
+use crate::hash::CacheKey;
+use crate::remap::PathRoots;
 
 /// Harvest the unit's dynamic inputs (paths + env vars) from the post-build state.
 ///
 /// Returns `Ok(None)` if the unit hasn't actually been built (no fingerprint state on disk).
+/// Paths and env values come back normalized against `roots`.
 pub fn harvest_unit(
     runner: &BuildRunner<'_, '_>,
+    roots: &PathRoots,
     unit: &Unit,
 ) -> Result<Option<DynamicInputs>> {
     if unit.mode == CompileMode::RunCustomBuild {
-        harvest_run_custom_build(runner, unit)
+        harvest_run_custom_build(runner, roots, unit)
     } else {
-        harvest_compile(runner, unit)
+        harvest_compile(runner, roots, unit)
     }
 }
 
 fn harvest_compile(
     runner: &BuildRunner<'_, '_>,
+    roots: &PathRoots,
     unit: &Unit,
 ) -> Result<Option<DynamicInputs>> {
     let files = runner.files();
@@ -80,9 +88,13 @@ fn harvest_compile(
     // Drop files inside pkg_root with .rs extension — already in static_key. Keep:
     //   - any path outside pkg_root (macro-resolved external includes, OUT_DIR refs)
     //   - non-.rs files inside pkg_root (e.g. .fbs, .html, .json)
+    // rustc names workspace members' sources relative to the workspace root,
+    // the directory cargo runs it in.
+    let ws_root = runner.bcx.ws.root();
     let mut path_set: Vec<PathBuf> = info
         .files
         .into_iter()
+        .map(|p| ws_root.join(p))
         .filter(|p: &PathBuf| {
             if !p.starts_with(pkg_root) {
                 return true;
@@ -97,20 +109,24 @@ fn harvest_compile(
         .into_iter()
         .map(|p| {
             let h = hash_path_current(&p).unwrap_or([0u8; 32]);
-            DynPath { path: p, stored_hash: h }
+            DynPath { path: roots.normalize(&p), stored_hash: h }
         })
         .collect();
     let envs: Vec<DynEnv> = info
         .env
         .into_iter()
-        .map(|(name, stored_value)| DynEnv { name, stored_value })
+        .map(|(name, value)| DynEnv {
+            name,
+            stored_value: value.map(|v| roots.normalize_str(&v)),
+        })
         .collect();
 
-    Ok(Some(DynamicInputs { paths, envs }))
+    Ok(Some(DynamicInputs { paths, envs, ..Default::default() }))
 }
 
 fn harvest_run_custom_build(
     runner: &BuildRunner<'_, '_>,
+    roots: &PathRoots,
     unit: &Unit,
 ) -> Result<Option<DynamicInputs>> {
     let output_path = runner.files().build_script_run_dir(unit).join("output");
@@ -145,6 +161,29 @@ fn harvest_run_custom_build(
             env_names.push(name.to_string());
         }
     }
+    let target_dir = crate::cargo_interop::target_dir(runner.bcx.ws);
+    // Like cargo, rerun a script that declares neither on any change to its
+    // package's files (registry and git packages never change).
+    let whole_package = paths.is_empty()
+        && env_names.is_empty()
+        && unit.pkg.package_id().source_id().is_path();
+    if whole_package {
+        let files = cargo::sources::path::list_files(&unit.pkg, runner.bcx.gctx)?;
+        paths.extend(
+            files
+                .iter()
+                .filter(|f| !f.starts_with(&target_dir))
+                .map(|f| f.to_path_buf()),
+        );
+    }
+    let (native_paths, native_envs) = crate::native::build_script_inputs(
+        &contents,
+        runner.bcx.target_data.short_name(&unit.kind),
+        &target_dir,
+        |n| std::env::var(n).ok(),
+    );
+    paths.extend(native_paths);
+    env_names.extend(native_envs);
     paths.sort();
     paths.dedup();
     env_names.sort();
@@ -154,13 +193,13 @@ fn harvest_run_custom_build(
         .into_iter()
         .map(|p| {
             let h = hash_path_current(&p).unwrap_or([0u8; 32]);
-            DynPath { path: p, stored_hash: h }
+            DynPath { path: roots.normalize(&p), stored_hash: h }
         })
         .collect();
     let env_entries: Vec<DynEnv> = env_names
         .into_iter()
         .map(|n| {
-            let v = std::env::var(&n).ok();
+            let v = std::env::var(&n).ok().map(|v| roots.normalize_str(&v));
             DynEnv { name: n, stored_value: v }
         })
         .collect();
@@ -168,9 +207,107 @@ fn harvest_run_custom_build(
     Ok(Some(DynamicInputs {
         paths: path_entries,
         envs: env_entries,
+        whole_package,
+        ..Default::default()
     }))
 }
 
+/// What a build script hands its package's compilations: `rustc-env` vars
+/// (visible to `env!`) and `rustc-cfg` flags.
+///
+/// The vars appear as `# env-dep` entries in the consumers' dep-info but
+/// never in cargo-zb's own environment, so the env lookup for a consumer's
+/// dynamic inputs resolves them here first ([`lookup`](Self::lookup)).
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ScriptOutputs {
+    pub env: BTreeMap<String, String>,
+    pub cfgs: Vec<String>,
+}
+
+impl ScriptOutputs {
+    pub fn parse(output: &str) -> Self {
+        let mut out = Self::default();
+        for line in output.lines() {
+            let body = line
+                .strip_prefix("cargo::")
+                .or_else(|| line.strip_prefix("cargo:"));
+            let Some(body) = body else { continue };
+            if let Some(value) = body.strip_prefix("rustc-env=") {
+                if let Some((name, value)) = value.split_once('=') {
+                    out.env.insert(name.to_string(), value.to_string());
+                }
+            } else if let Some(cfg) = body.strip_prefix("rustc-cfg=") {
+                out.cfgs.push(cfg.to_string());
+            }
+        }
+        out
+    }
+
+    /// Outputs of the build-script run `unit`, as left in `target/` by the
+    /// build or a restore; none if the script never ran.
+    pub fn read(runner: &BuildRunner<'_, '_>, unit: &Unit) -> Result<Self> {
+        Self::read_file(&runner.files().build_script_run_dir(unit).join("output"))
+    }
+
+    /// [`read`](Self::read), given the path of the script's `output` file.
+    pub fn read_file(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(output) => Ok(Self::parse(&output)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+        }
+    }
+
+    /// Outputs of the build-script run stored under `unit_key`, read from the
+    /// bundle so a lookup needn't wait for its restore.
+    pub fn from_cache(
+        cache: &dyn CacheBackend,
+        roots: &PathRoots,
+        unit_key: &[u8; 32],
+    ) -> Result<Self> {
+        let Some(rel) = cache.list_artifacts(unit_key)?.into_iter().find(|rel| {
+            let path = Path::new(rel);
+            path.file_name() == Some("output".as_ref())
+                && path.parent().and_then(|p| p.parent()).and_then(|p| p.file_name())
+                    == Some("build".as_ref())
+        }) else {
+            return Ok(Self::default());
+        };
+        let Some(data) = cache.get_artifact(unit_key, &rel)? else {
+            return Ok(Self::default());
+        };
+        Ok(Self::parse(&roots.resolve_str(&String::from_utf8_lossy(&data))))
+    }
+
+    /// A var's value for the package's compilations: cargo sets the script's
+    /// `rustc-env` over the inherited environment.
+    pub fn lookup(&self, name: &str) -> Option<String> {
+        self.env
+            .get(name)
+            .cloned()
+            .or_else(|| std::env::var(name).ok())
+    }
+
+    /// Folded into the consumers' full keys alongside their dependencies'
+    /// keys, so they follow what the script actually emitted.
+    pub fn key(&self, roots: &PathRoots) -> CacheKey {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(b"zb-script-outputs-v1\0");
+        for (name, value) in &self.env {
+            hasher.update(name.as_bytes());
+            hasher.update(b"=");
+            hasher.update(roots.normalize_str(value).as_bytes());
+            hasher.update(b"\0");
+        }
+        hasher.update(b"env-end\0");
+        for cfg in &self.cfgs {
+            hasher.update(cfg.as_bytes());
+            hasher.update(b"\0");
+        }
+        CacheKey(*hasher.finalize().as_bytes())
+    }
+}
+
 /// Find rustc's text-format `.d` for this unit in `deps_dir`. Cargo names them
 /// `<crate-name>-<unit_hash>.d` (with various prefixes: `lib` for lib targets,
 /// `build_script_build` for build script COMPILE units, plain `<bin>` for bins).
@@ -290,3 +427,87 @@ pub fn dedup_paths(paths: Vec<PathBuf>) -> Vec<PathBuf> {
     out.sort();
     out
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn script_outputs_override_the_environment() {
+        let outputs = ScriptOutputs::parse(
+            "cargo:rustc-env=GIT_HASH=abc=def\n\
+             cargo::rustc-env=PATH=/script\n\
+             cargo:rustc-cfg=has_feature\n\
+             cargo:rustc-cfg=level=\"2\"\n\
+             cargo:rerun-if-changed=build.rs\n",
+        );
+        assert_eq!(outputs.env.get("GIT_HASH").map(String::as_str), Some("abc=def"));
+        assert_eq!(outputs.cfgs, ["has_feature", "level=\"2\""]);
+        assert_eq!(outputs.lookup("PATH").as_deref(), Some("/script"));
+        assert_eq!(outputs.lookup("HOME"), std::env::var("HOME").ok());
+
+        let roots = PathRoots::from_roots(Path::new("/w"), Path::new("/h"), Path::new("/t"));
+        let mut other = outputs.clone();
+        other.cfgs.pop();
+        assert_ne!(outputs.key(&roots), other.key(&roots));
+    }
+
+    #[test]
+    fn scripts_without_rerun_if_see_edited_and_added_files() {
+        use cargo::GlobalContext;
+        use cargo::core::Workspace;
+        use cargo::core::compiler::{UnitInterner, UserIntent};
+        use cargo::core::shell::Shell;
+        use cargo::ops::CompileOptions;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let write = |rel: &str, contents: &str| {
+            let path = root.join(rel);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        };
+        write("Cargo.toml", "[package]\nname = \"whole\"\nversion = \"0.1.0\"\nedition = \"2021\"\n");
+        write("build.rs", "fn main() {}\n");
+        write("src/lib.rs", "");
+        write("tests/it.rs", "");
+
+        let gctx = GlobalContext::new(
+            Shell::from_write(Box::new(std::io::sink())),
+            root.into(),
+            root.join("home"),
+        );
+        let opts = [CompileOptions::new(&gctx, UserIntent::Build).unwrap()];
+        // The static key of the script run, and its manifest as harvested
+        // after a run that printed nothing.
+        let plan_script = || {
+            let ws = Workspace::new(&root.join("Cargo.toml"), &gctx).unwrap();
+            let interner = UnitInterner::new();
+            let plan = crate::cargo_interop::Plan::new(&ws, &interner, &opts).unwrap();
+            let ctx = crate::cargo_interop::key_context(&ws, &plan).unwrap();
+            let keys = crate::hash::compute_cache_keys(&plan.unit_graph, &plan.roots, &ctx).unwrap();
+            let unit = plan.unit_graph.keys().find(|u| u.mode.is_run_custom_build()).unwrap();
+            let mut runners = plan.runners().unwrap();
+            let runner = runners.get(unit);
+            let run_dir = runner.files().build_script_run_dir(unit);
+            std::fs::create_dir_all(&run_dir).unwrap();
+            std::fs::write(run_dir.join("output"), "").unwrap();
+            let inputs = harvest_unit(runner, &ctx.paths, unit).unwrap().unwrap();
+            (keys.static_keys[unit], inputs, ctx.paths)
+        };
+
+        let (key, inputs, roots) = plan_script();
+        assert!(inputs.whole_package);
+        let content = inputs.content_hash(&roots, |_| None).unwrap();
+        // An edit shows in the manifest; the file is another target's.
+        write("tests/it.rs", "#[test]\nfn t() {}\n");
+        let (edited_key, _, _) = plan_script();
+        assert_eq!(edited_key, key);
+        assert_ne!(inputs.content_hash(&roots, |_| None).unwrap(), content);
+
+        // A new file isn't in the manifest at all.
+        write("tests/new.rs", "");
+        let (added_key, _, _) = plan_script();
+        assert_ne!(added_key, key);
+    }
+}
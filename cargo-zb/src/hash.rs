@@ -1,9 +1,14 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 
 use anyhow::{Context, Result};
+use cargo::core::PackageId;
 use cargo::core::compiler::{CompileKind, Unit};
 use cargo::core::compiler::unit_graph::UnitGraph;
 
+use crate::cache::hash_path_current;
+use crate::remap::PathRoots;
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct CacheKey(pub [u8; 32]);
 
@@ -13,7 +18,7 @@ impl CacheKey {
     }
 
     #[allow(dead_code)]
-    pub fn to_hex(&self) -> String {
+    pub fn to_hex(self) -> String {
         blake3::Hash::from(self.0).to_hex().to_string()
     }
 }
@@ -22,13 +27,13 @@ impl CacheKey {
 /// the **full_keys of its dependencies** to yield the unit's full content-addressed
 /// cache key.
 ///
-/// Why dep full_keys: when a build script injects env vars via `cargo:rustc-env=`,
-/// the dependent crate's compilation receives those values and rustc records them
-/// as `# env-dep:NAME=VALUE` in the dep-info. Those env vars are NOT in the user's
-/// process env at cargo-zb invocation time, so hashing current values misses the
-/// change. Folding dep full_keys propagates "any dep's content has changed" up to
-/// consumers, which is exactly the invalidation cargo's own DepFingerprint
-/// achieves at the unit-graph level.
+/// Why dep full_keys: a consumer's dynamic inputs only cover what its own
+/// compilation read; what it links or expands from its dependencies is only
+/// as current as their keys. Folding dep full_keys propagates "any dep's
+/// content has changed" up to consumers, which is exactly the invalidation
+/// cargo's own DepFingerprint achieves at the unit-graph level. Units with a
+/// build script also get the key of its `rustc-env` / `rustc-cfg` outputs
+/// (`harvest::ScriptOutputs`) among their dep keys.
 pub fn combine_full_key(
     static_key: &CacheKey,
     dynamic_content_hash: &[u8; 32],
@@ -39,7 +44,7 @@ pub fn combine_full_key(
     hasher.update(static_key.as_bytes());
     hasher.update(dynamic_content_hash);
     let mut deps: Vec<&CacheKey> = dep_full_keys.iter().collect();
-    deps.sort_by(|a, b| a.0.cmp(&b.0));
+    deps.sort_by_key(|k| k.0);
     for k in deps {
         hasher.update(k.as_bytes());
     }
@@ -47,6 +52,81 @@ pub fn combine_full_key(
     CacheKey(*hasher.finalize().as_bytes())
 }
 
+/// Output-addressed counterpart of [`combine_full_key`], for `--early-cutoff`:
+/// the digests of what the unit's compilation reads from its dependencies
+/// (see `cutoff`) stand in for their full keys. A dependency rebuilt into
+/// the same bytes leaves it unchanged. `own_key` (from [`UnitKeys`]) leaves
+/// the dependencies' keys out for the same reason.
+pub fn cutoff_key(
+    own_key: &CacheKey,
+    dynamic_content_hash: &[u8; 32],
+    dep_outputs: &[[u8; 32]],
+) -> CacheKey {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(b"cargo-zb-cutoff-v1\0");
+    hasher.update(own_key.as_bytes());
+    hasher.update(dynamic_content_hash);
+    let mut deps = dep_outputs.to_vec();
+    deps.sort();
+    for d in &deps {
+        hasher.update(d);
+    }
+    hasher.update(b"deps-end\0");
+    CacheKey(*hasher.finalize().as_bytes())
+}
+
+/// Key of a test binary's stored result: the unit's full key plus what a run
+/// can observe beyond the binary itself — its command line (a configured
+/// `runner` and the test args) and the content hash of its runtime inputs
+/// (see `testrun::runtime_inputs`).
+pub fn test_result_key(
+    unit_full_key: &CacheKey,
+    command: &[String],
+    runtime_content_hash: &[u8; 32],
+) -> CacheKey {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(b"cargo-zb-test-v2\0");
+    for arg in command {
+        hasher.update(arg.as_bytes());
+        hasher.update(b"\0");
+    }
+    hasher.update(b"args-end\0");
+    let args_key = CacheKey(*hasher.finalize().as_bytes());
+    combine_full_key(&args_key, runtime_content_hash, &[*unit_full_key])
+}
+
+/// Build-wide inputs of every unit's static key.
+pub struct KeyContext {
+    /// `rustc -vV`, plus a hash of the compiler its sysroot holds.
+    pub rustc_version: String,
+    /// The triple `CompileKind::Host` stands for on this machine.
+    pub host: String,
+    pub wrapper: Option<WorkspaceWrapper>,
+    /// Roots that paths in keys are normalized against (see `remap`).
+    pub paths: PathRoots,
+    /// What `-C target-cpu=native` means for each compile kind whose units
+    /// ask for it: the CPU rustc detects and its target features.
+    pub native_cpus: HashMap<CompileKind, String>,
+    /// The linker configured for each compile kind that has one.
+    pub linkers: HashMap<CompileKind, PathBuf>,
+    /// `RUSTC_WRAPPER` / `build.rustc-wrapper`, and a hash of the binary it
+    /// names (`[0; 32]` if it can't be found).
+    pub rustc_wrapper: Option<(PathBuf, [u8; 32])>,
+    /// The `[env]` config table as cargo applies it, sorted.
+    pub config_env: Vec<(String, String)>,
+    /// Each path package's files as cargo lists them for packaging: what
+    /// `include` / `exclude` and `.gitignore` leave, without nested packages.
+    pub package_files: HashMap<PackageId, Vec<PathBuf>>,
+}
+
+/// A `RUSTC_WORKSPACE_WRAPPER` (clippy-driver under `cargo zb clippy`), which
+/// cargo runs instead of plain rustc for workspace members only.
+pub struct WorkspaceWrapper {
+    /// The wrapper's version and the lint arguments it is given.
+    pub identity: String,
+    pub members: HashSet<PackageId>,
+}
+
 /// Compute static cache keys for all units in the graph (bottom-up topo order).
 ///
 /// The "static" qualifier distinguishes this from the unit's full content-addressed
@@ -55,12 +135,22 @@ pub fn combine_full_key(
 /// keys, and `*.rs` source contents (for path packages). It does NOT cover external
 /// file deps reached via macros or build script `rerun-if-*` declarations — those
 /// land in the dynamic inputs, harvested post-build.
+///
+/// Paths that go into a key (path packages' locations, rustflags such as
+/// `--remap-path-prefix`) are normalized against `ctx.paths`, so checkouts in
+/// different directories share keys.
 pub fn compute_cache_keys(
     unit_graph: &UnitGraph,
     roots: &[Unit],
-    rustc_version: &str,
-) -> Result<HashMap<Unit, CacheKey>> {
+    ctx: &KeyContext,
+) -> Result<UnitKeys> {
     let mut keys: HashMap<Unit, CacheKey> = HashMap::new();
+    let mut own_keys: HashMap<Unit, CacheKey> = HashMap::new();
+    // Units of a package (lib, bins, tests, every profile and target) share
+    // most of its files, so read each once, and all of them in parallel.
+    let mut sources: HashMap<PathBuf, [u8; 32]> = HashMap::new();
+    let files: Vec<PathBuf> = ctx.package_files.values().flatten().cloned().collect();
+    crate::cache::prehash(&files);
 
     let mut visited = std::collections::HashSet::new();
     let mut order = Vec::new();
@@ -87,32 +177,76 @@ pub fn compute_cache_keys(
     }
 
     for unit in &order {
-        let key = compute_unit_key(unit, unit_graph, &keys, rustc_version)?;
-        keys.insert(unit.clone(), key);
+        let own = compute_own_key(unit, &mut sources, ctx)?;
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(b"cargo-zb-static-v1\0");
+        hasher.update(own.as_bytes());
+        if let Some(deps) = unit_graph.get(unit) {
+            let mut dep_entries: Vec<_> = deps
+                .iter()
+                .filter_map(|dep| {
+                    keys.get(&dep.unit).map(|key| {
+                        (dep.unit.pkg.package_id().name().as_str().to_string(), *key)
+                    })
+                })
+                .collect();
+            dep_entries.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.0.cmp(&b.1.0)));
+            for (name, key) in &dep_entries {
+                hasher.update(name.as_bytes());
+                hasher.update(b"=");
+                hasher.update(key.as_bytes());
+                hasher.update(b"\0");
+            }
+        }
+        hasher.update(b"deps-end\0");
+        keys.insert(unit.clone(), CacheKey(*hasher.finalize().as_bytes()));
+        own_keys.insert(unit.clone(), own);
     }
 
-    Ok(keys)
+    Ok(UnitKeys { static_keys: keys, own_keys })
+}
+
+/// Per-unit keys from [`compute_cache_keys`].
+pub struct UnitKeys {
+    pub static_keys: HashMap<Unit, CacheKey>,
+    /// Each unit's static key without its dependencies' keys, which early
+    /// cutoff replaces with what they built (see `cutoff`).
+    pub own_keys: HashMap<Unit, CacheKey>,
 }
 
-fn compute_unit_key(
+/// Everything in a unit's static key but its dependencies.
+fn compute_own_key(
     unit: &Unit,
-    unit_graph: &UnitGraph,
-    dep_keys: &HashMap<Unit, CacheKey>,
-    rustc_version: &str,
+    sources: &mut HashMap<PathBuf, [u8; 32]>,
+    ctx: &KeyContext,
 ) -> Result<CacheKey> {
+    let paths = &ctx.paths;
     let mut hasher = blake3::Hasher::new();
 
     hasher.update(b"cargo-zb-v1\0");
 
-    hasher.update(rustc_version.as_bytes());
+    hasher.update(ctx.rustc_version.as_bytes());
     hasher.update(b"\0");
+    // Keeps clippy's rmeta (and its lints) apart from `check`'s, as cargo's
+    // own unit metadata does.
+    if let Some(wrapper) = &ctx.wrapper
+        && wrapper.members.contains(&unit.pkg.package_id())
+    {
+        hasher.update(b"wrapper:");
+        hasher.update(wrapper.identity.as_bytes());
+        hasher.update(b"\0");
+    }
 
     let pkg_id = unit.pkg.package_id();
     hasher.update(pkg_id.name().as_bytes());
     hasher.update(b"\0");
     hasher.update(pkg_id.version().to_string().as_bytes());
     hasher.update(b"\0");
-    hasher.update(pkg_id.source_id().to_string().as_bytes());
+    if pkg_id.source_id().is_path() {
+        hasher.update(paths.normalize(unit.pkg.root()).to_string_lossy().as_bytes());
+    } else {
+        hasher.update(pkg_id.source_id().to_string().as_bytes());
+    }
     hasher.update(b"\0");
 
     hasher.update(unit.target.name().as_bytes());
@@ -123,11 +257,19 @@ fn compute_unit_key(
     hasher.update(format!("{:?}", unit.mode).as_bytes());
     hasher.update(b"\0");
 
-    hash_profile(&mut hasher, &unit.profile);
+    // Test units of the same target differ in whether rustc gets `--test`:
+    // `harness = false` builds the test as a plain binary.
+    if unit.mode.is_any_test() {
+        hasher.update(if unit.target.harness() { b"harness\0" } else { b"no-harness\0" });
+    }
+
+    hash_profile(&mut hasher, &unit.profile)?;
 
     match unit.kind {
         CompileKind::Host => {
-            hasher.update(b"host\0");
+            hasher.update(b"host:");
+            hasher.update(ctx.host.as_bytes());
+            hasher.update(b"\0");
         }
         CompileKind::Target(t) => {
             hasher.update(b"target:");
@@ -145,81 +287,341 @@ fn compute_unit_key(
     hasher.update(b"features-end\0");
 
     for flag in unit.rustflags.iter() {
-        hasher.update(flag.as_bytes());
+        hasher.update(paths.normalize_str(flag).as_bytes());
         hasher.update(b"\0");
     }
     hasher.update(b"rustflags-end\0");
-
-    if let Some(deps) = unit_graph.get(unit) {
-        let mut dep_entries: Vec<_> = deps
-            .iter()
-            .filter_map(|dep| {
-                dep_keys.get(&dep.unit).map(|key| {
-                    (dep.unit.pkg.package_id().name().as_str().to_string(), *key)
-                })
-            })
-            .collect();
-        dep_entries.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.0.cmp(&b.1.0)));
-        for (name, key) in &dep_entries {
-            hasher.update(name.as_bytes());
-            hasher.update(b"=");
-            hasher.update(key.as_bytes());
+    if unit.mode.is_doc() || unit.mode.is_doc_test() {
+        for flag in unit.rustdocflags.iter() {
+            hasher.update(paths.normalize_str(flag).as_bytes());
             hasher.update(b"\0");
         }
+        hasher.update(b"rustdocflags-end\0");
     }
-    hasher.update(b"deps-end\0");
+    if let Some(linker) = ctx.linkers.get(&unit.kind) {
+        hasher.update(b"linker:");
+        hasher.update(paths.normalize(linker).to_string_lossy().as_bytes());
+        hasher.update(b"\0");
+    }
+    // A `target.<triple>.runner` is not keyed here: it only runs what was
+    // built, and test results are keyed on the command that includes it.
+    if let Some((wrapper, contents)) = &ctx.rustc_wrapper {
+        hasher.update(b"rustc-wrapper:");
+        hasher.update(paths.normalize(wrapper).to_string_lossy().as_bytes());
+        hasher.update(b"\0");
+        hasher.update(contents);
+    }
+    // Set for rustc and build scripts alike; `env!` reads them.
+    for (name, value) in &ctx.config_env {
+        hasher.update(name.as_bytes());
+        hasher.update(b"=");
+        hasher.update(paths.normalize_str(value).as_bytes());
+        hasher.update(b"\0");
+    }
+    hasher.update(b"config-env-end\0");
+    // `native` is a different CPU on every machine; key on the one it is here.
+    if let Some(native) = ctx.native_cpus.get(&unit.kind)
+        && target_cpu(&unit.rustflags) == Some("native")
+    {
+        hasher.update(b"native-cpu:");
+        hasher.update(native.as_bytes());
+        hasher.update(b"\0");
+    }
+
+    // The manifest's `[lints]` table, passed to rustc (and clippy) as flags.
+    for flag in unit.pkg.manifest().lint_rustflags() {
+        hasher.update(flag.as_bytes());
+        hasher.update(b"\0");
+    }
+    hasher.update(b"lints-end\0");
 
     // Path packages: hash source files. Registry/git: version is in pkg_id.
-    if pkg_id.source_id().is_path() {
-        hash_source_files(&mut hasher, unit)?;
+    if let Some(files) = ctx.package_files.get(&pkg_id) {
+        let pkg_root = unit.pkg.root();
+        hasher.update(b"sources:");
+        for path in target_files(unit, files) {
+            let digest = match sources.get(path) {
+                Some(d) => *d,
+                None => {
+                    let d = hash_path_current(path)?;
+                    sources.insert(path.clone(), d);
+                    d
+                }
+            };
+            let rel = path.strip_prefix(pkg_root).unwrap_or(path);
+            hasher.update(rel.to_string_lossy().as_bytes());
+            hasher.update(b"\0");
+            hasher.update(&digest);
+        }
+        hasher.update(b"sources-end\0");
+        // A build script without `rerun-if-*` reruns on any change to its
+        // package, and its manifest (see `harvest`) lists only the files
+        // there were. A file added to another target is the one such change
+        // the sources above leave out.
+        if unit.mode.is_run_custom_build() {
+            hasher.update(b"listing:");
+            for path in files {
+                let rel = path.strip_prefix(pkg_root).unwrap_or(path);
+                hasher.update(rel.to_string_lossy().as_bytes());
+                hasher.update(b"\0");
+            }
+            hasher.update(b"listing-end\0");
+        }
     }
 
     Ok(CacheKey(*hasher.finalize().as_bytes()))
 }
 
-fn hash_profile(hasher: &mut blake3::Hasher, profile: &cargo::core::profiles::Profile) {
-    hasher.update(format!("{}", profile.opt_level).as_bytes());
-    hasher.update(b"\0");
-    hasher.update(format!("{:?}", profile.debuginfo).as_bytes());
-    hasher.update(b"\0");
-    hasher.update(format!("{:?}", profile.debug_assertions).as_bytes());
-    hasher.update(b"\0");
-    hasher.update(format!("{:?}", profile.overflow_checks).as_bytes());
-    hasher.update(b"\0");
-    hasher.update(format!("{:?}", profile.lto).as_bytes());
-    hasher.update(b"\0");
-    hasher.update(format!("{:?}", profile.panic).as_bytes());
-    hasher.update(b"\0");
-    hasher.update(format!("{:?}", profile.codegen_units).as_bytes());
-    hasher.update(b"\0");
-    hasher.update(format!("{:?}", profile.strip).as_bytes());
+/// Every field of the resolved profile, so settings cargo adds later are
+/// keyed too. Per-package overrides, `build-override` and profile rustflags
+/// are already merged into a unit's profile.
+fn hash_profile(hasher: &mut blake3::Hasher, profile: &cargo::core::profiles::Profile) -> Result<()> {
+    hasher.update(&serde_json::to_vec(profile).context("serializing profile")?);
     hasher.update(b"\0");
+    Ok(())
 }
 
-fn hash_source_files(hasher: &mut blake3::Hasher, unit: &Unit) -> Result<()> {
-    let pkg_root = unit.pkg.root();
+/// The files of `unit`'s package that can affect its target: all of them
+/// except the package's other targets. Those are their entry points, plus
+/// the directory of a target that has one to itself (`examples/foo/main.rs`,
+/// `tests/it/main.rs`): one holding no other target's entry point and not
+/// the package root or `src`. Whatever a target reaches of another one (the
+/// lib, from a bin) comes in through that unit's key.
+fn target_files<'a>(unit: &Unit, files: &'a [PathBuf]) -> impl Iterator<Item = &'a PathBuf> {
+    let entries: Vec<&Path> = unit.pkg.targets().iter().filter_map(|t| t.src_path().path()).collect();
+    let own = unit.target.src_path().path();
+    let others = entries.iter().copied().filter(|path| Some(*path) != own);
+    let (excluded_files, excluded_dirs) = other_targets(unit.pkg.root(), &entries, others);
+    files.iter().filter(move |path| {
+        !excluded_files.contains(path) && !excluded_dirs.iter().any(|dir| path.starts_with(dir))
+    })
+}
+
+/// Entry files and dedicated directories of the targets `others`, given the
+/// entry files of all of the package's targets.
+fn other_targets<'a>(
+    pkg_root: &Path,
+    entries: &[&Path],
+    others: impl Iterator<Item = &'a Path>,
+) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let src_dir = pkg_root.join("src");
+    let mut excluded_files = Vec::new();
+    let mut excluded_dirs = Vec::new();
+    for src_path in others {
+        excluded_files.push(src_path.to_path_buf());
+        if let Some(dir) = src_path.parent()
+            && src_path.file_name() == Some("main.rs".as_ref())
+            && dir != src_dir
+            && dir.starts_with(pkg_root)
+            && dir != pkg_root
+            && !entries.iter().any(|e| *e != src_path && e.starts_with(dir))
+        {
+            excluded_dirs.push(dir.to_path_buf());
+        }
+    }
+    (excluded_files, excluded_dirs)
+}
 
-    let mut paths: Vec<_> = walkdir::WalkDir::new(pkg_root)
-        .into_iter()
-        .filter_map(|e| e.ok())
-        .filter(|e| {
-            e.file_type().is_file()
-                && e.path().extension().is_some_and(|ext| ext == "rs")
-                && !e.path().components().any(|c| c.as_os_str() == "target")
+/// The `-C target-cpu` that `flags` leave in effect: the last one, in any of
+/// the spellings rustc takes (`-C target-cpu=x`, `-Ctarget-cpu=x`,
+/// `--codegen target-cpu=x`, `--codegen=target-cpu=x`).
+pub fn target_cpu(flags: &[String]) -> Option<&str> {
+    flags
+        .iter()
+        .filter_map(|f| {
+            let f = f.strip_prefix("-C").or_else(|| f.strip_prefix("--codegen")).unwrap_or(f);
+            f.trim_start_matches('=').strip_prefix("target-cpu=")
         })
-        .map(|e| e.into_path())
-        .collect();
-    paths.sort();
+        .next_back()
+}
 
-    for path in &paths {
-        let rel = path.strip_prefix(pkg_root).unwrap_or(path);
-        hasher.update(rel.to_string_lossy().as_bytes());
-        hasher.update(b"\0");
-        let contents = std::fs::read(path)
-            .with_context(|| format!("reading {}", path.display()))?;
-        hasher.update(&contents);
-        hasher.update(b"\0");
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    fn flags(list: &[&str]) -> Vec<String> {
+        list.iter().map(|f| f.to_string()).collect()
+    }
+
+    #[test]
+    fn target_cpu_takes_the_last_in_any_spelling() {
+        assert_eq!(target_cpu(&flags(&["-C", "target-cpu=native"])), Some("native"));
+        assert_eq!(target_cpu(&flags(&["-Ctarget-cpu=native"])), Some("native"));
+        assert_eq!(target_cpu(&flags(&["--codegen=target-cpu=native"])), Some("native"));
+        assert_eq!(
+            target_cpu(&flags(&["-Ctarget-cpu=native", "--codegen", "target-cpu=x86-64-v3"])),
+            Some("x86-64-v3")
+        );
+        assert_eq!(target_cpu(&flags(&["-C", "opt-level=3", "--cfg", "x"])), None);
+    }
+
+    const MANIFEST: &str = "[package]\nname = \"flip\"\nversion = \"0.1.0\"\nedition = \"2021\"\n";
+
+    fn write(path: &Path, contents: &str) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    /// A package with a build script, a lib with a doctest and an
+    /// integration test, `manifest_extra` appended to its manifest and
+    /// `config` as its `.cargo/config.toml`. A `cargo-features` line is put
+    /// on top instead.
+    fn write_package(root: &Path, manifest_extra: &str, config: &str) {
+        let manifest = if manifest_extra.starts_with("cargo-features") {
+            format!("{manifest_extra}{MANIFEST}")
+        } else {
+            format!("{MANIFEST}{manifest_extra}")
+        };
+        write(&root.join("Cargo.toml"), &manifest);
+        write(&root.join("build.rs"), "fn main() {}\n");
+        write(&root.join("src/lib.rs"), "/// ```\n/// flip::f();\n/// ```\npub fn f() {}\n");
+        write(&root.join("tests/it.rs"), "#[test]\nfn t() { flip::f() }\n");
+        write(&root.join(".cargo/config.toml"), config);
+    }
+
+    /// Static keys of `cargo test`'s units for the package at `root`, by mode
+    /// and target name.
+    fn unit_keys(root: &Path) -> HashMap<String, CacheKey> {
+        use cargo::GlobalContext;
+        use cargo::core::Workspace;
+        use cargo::core::compiler::{UnitInterner, UserIntent};
+        use cargo::core::shell::Shell;
+        use cargo::ops::CompileOptions;
+
+        let gctx = GlobalContext::new(
+            Shell::from_write(Box::new(std::io::sink())),
+            root.into(),
+            root.join("home"),
+        );
+        let ws = Workspace::new(&root.join("Cargo.toml"), &gctx).unwrap();
+        let opts = [CompileOptions::new(&gctx, UserIntent::Test).unwrap()];
+        let interner = UnitInterner::new();
+        let plan = crate::cargo_interop::Plan::new(&ws, &interner, &opts).unwrap();
+        let ctx = crate::cargo_interop::key_context(&ws, &plan).unwrap();
+        compute_cache_keys(&plan.unit_graph, &plan.roots, &ctx)
+            .unwrap()
+            .static_keys
+            .into_iter()
+            .map(|(unit, key)| (format!("{:?} {}", unit.mode, unit.target.name()), key))
+            .collect()
+    }
+
+    fn keys(manifest_extra: &str, config: &str) -> Vec<CacheKey> {
+        let dir = tempfile::tempdir().unwrap();
+        write_package(dir.path(), manifest_extra, config);
+        let mut keys: Vec<CacheKey> = unit_keys(dir.path()).into_values().collect();
+        keys.sort_by_key(|k| k.0);
+        keys
+    }
+
+    #[test]
+    fn every_output_affecting_setting_changes_the_key() {
+        let base = keys("", "");
+        assert_eq!(keys("", ""), base, "keys must not depend on the checkout");
+        // In the config rather than the manifest, which is keyed as a file.
+        let flips = [
+            "[profile.dev]\nsplit-debuginfo = \"packed\"\n",
+            "[profile.dev]\nrpath = true\n",
+            "[profile.dev]\nincremental = false\n",
+            "[profile.dev.package.flip]\nopt-level = 1\n",
+            "[profile.dev.build-override]\nopt-level = 2\n",
+            "[env]\nFLIP = \"1\"\n",
+            "[target.'cfg(all())']\nlinker = \"cc\"\n",
+            "[build]\nrustc-wrapper = \"/usr/bin/env\"\n",
+            "[build]\nrustdocflags = [\"--cfg\", \"flip\"]\n",
+        ];
+        for config in flips {
+            assert_ne!(keys("", config), base, "{config}");
+        }
+        // Unstable, and opted into in the manifest of both sides.
+        if matches!(cargo::core::features::channel().as_str(), "nightly" | "dev") {
+            let features = "cargo-features = [\"trim-paths\", \"profile-rustflags\"]\n";
+            let base = keys(features, "");
+            let flips = [
+                "[profile.dev]\ntrim-paths = \"all\"\n",
+                "[profile.dev.package.flip]\nrustflags = [\"--cfg\", \"flip\"]\n",
+            ];
+            for config in flips {
+                assert_ne!(keys(features, config), base, "{config}");
+            }
+        }
+        // The runner only runs what was built; test results key on it instead.
+        assert_eq!(keys("", "[target.'cfg(all())']\nrunner = \"valgrind\"\n"), base);
+    }
+
+    #[test]
+    fn a_rustc_wrapper_is_keyed_on_its_contents() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let wrapper = dir.path().join("wrap");
+        let config = format!("[build]\nrustc-wrapper = {:?}\n", wrapper.to_str().unwrap());
+        write(&wrapper, "#!/bin/sh\nexec \"$@\"\n");
+        std::fs::set_permissions(&wrapper, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let before = keys("", &config);
+        write(&wrapper, "#!/bin/sh\n# v2\nexec \"$@\"\n");
+        assert_ne!(keys("", &config), before);
+    }
+
+    #[test]
+    fn sources_are_the_package_files_of_the_target() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        write_package(root, "", "");
+        write(&root.join("nested/Cargo.toml"), "[package]\nname = \"nested\"\nversion = \"0.1.0\"\n");
+        write(&root.join("nested/src/lib.rs"), "");
+        write(&root.join("data/table.txt"), "1");
+        let base = unit_keys(root);
+        let changed = |edit: &dyn Fn()| {
+            edit();
+            let after = unit_keys(root);
+            let mut names: Vec<String> = base
+                .iter()
+                .filter(|(unit, key)| after[*unit] != **key)
+                .map(|(unit, _)| unit.clone())
+                .collect();
+            names.sort();
+            names
+        };
+
+        assert!(changed(&|| write(&root.join("nested/src/lib.rs"), "pub fn g() {}")).is_empty());
+        assert!(changed(&|| write(&root.join(".hidden/x.rs"), "")).is_empty());
+        assert_eq!(changed(&|| write(&root.join("tests/it.rs"), "")), ["Test it"]);
+        let all: Vec<&String> = base.keys().collect();
+        assert_eq!(changed(&|| write(&root.join("data/table.txt"), "2")).len(), all.len());
+    }
+
+    #[test]
+    fn a_main_rs_target_only_hides_a_directory_of_its_own() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        write_package(root, "[[bin]]\nname = \"tool\"\npath = \"main.rs\"\n", "");
+        write(&root.join("main.rs"), "fn main() {}\n");
+        write(&root.join("tests/main.rs"), "#[test]\nfn m() {}\n");
+        write(&root.join("examples/demo/main.rs"), "fn main() {}\n");
+        write(&root.join("examples/demo/util.rs"), "");
+        let changed = |path: &str, contents: &str| {
+            let before = unit_keys(root);
+            write(&root.join(path), contents);
+            let after = unit_keys(root);
+            let mut names: Vec<String> = before
+                .iter()
+                .filter(|(unit, key)| after[*unit] != **key)
+                .map(|(unit, _)| unit.clone())
+                .collect();
+            names.sort();
+            names
+        };
+
+        // `tests/main.rs` shares `tests/` with `tests/it.rs`.
+        assert_eq!(changed("tests/it.rs", "#[test]\nfn t2() {}\n"), ["Test it"]);
+        // A bin at the package root doesn't hide the package from the others.
+        let lib = changed("src/lib.rs", "pub fn f() {}\n");
+        assert!(lib.iter().any(|u| u.ends_with(" flip")), "{lib:?}");
+        assert!(changed("build.rs", "fn main() { }\n").iter().any(|u| u.contains("build-script")));
+        // `examples/demo/` is that example's own.
+        assert_eq!(changed("examples/demo/util.rs", "//").iter().filter(|u| !u.ends_with(" demo")).count(), 0);
     }
-    hasher.update(b"source-end\0");
-    Ok(())
 }
@@ -1,17 +1,28 @@
 mod artifacts;
 mod bench;
+mod build_index;
 mod cache;
 mod cargo_interop;
+mod cutoff;
+mod diagnostics;
+mod gc;
 mod harvest;
 mod hash;
 mod lto_vendored;
+mod messages;
+mod native;
+mod remap;
+mod statcache;
+mod testrun;
+mod toolchain;
 
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use anyhow::Result;
 use cache::CacheBackend;
-use cargo::core::compiler::{CompileMode, Unit, UnitInterner};
+use cargo::core::compiler::{CompileMode, Executor, Unit, UnitInterner};
 use clap::{Parser, Subcommand};
 #[allow(unused_imports)]
 use tracing::{debug, info};
@@ -28,6 +39,8 @@ enum MissCause {
     /// At least one dep missed; this unit is forced-miss because its full_key
     /// depends on dep full_keys.
     Cascade { dep_name: String },
+    /// The bundle matched but disappeared mid-restore (concurrent `gc`).
+    Evicted,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -37,7 +50,7 @@ enum PkgKind { Path, Registry }
 enum InputSource { Rustc, BuildScript }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-enum Category { Rust, Cargo, BuildScript, Cascade }
+enum Category { Rust, Cargo, BuildScript, Cascade, Evicted }
 
 impl Category {
     fn label(self) -> &'static str {
@@ -46,6 +59,7 @@ impl Category {
             Category::Cargo => "cargo",
             Category::BuildScript => "buildscript",
             Category::Cascade => "cascade",
+            Category::Evicted => "evicted",
         }
     }
 }
@@ -71,6 +85,7 @@ fn classify_miss(unit: &Unit, no_manifests: bool, diff: Option<cache::DiffReport
 fn miss_category(cause: &MissCause) -> Category {
     match cause {
         MissCause::Cascade { .. } => Category::Cascade,
+        MissCause::Evicted => Category::Evicted,
         MissCause::NewStaticKey { kind: PkgKind::Path } => Category::Rust,
         MissCause::NewStaticKey { kind: PkgKind::Registry } => Category::Cargo,
         MissCause::DynamicChanged { source: InputSource::Rustc, .. } => Category::Rust,
@@ -84,13 +99,16 @@ fn first_trigger(cause: &MissCause) -> String {
         MissCause::NewStaticKey { kind: PkgKind::Path } => "no prior manifest (path pkg — likely source change)".into(),
         MissCause::NewStaticKey { kind: PkgKind::Registry } => "no prior manifest (registry pkg — likely cargo settings change)".into(),
         MissCause::Cascade { dep_name } => format!("dep {dep_name} missed"),
+        MissCause::Evicted => "cached bundle evicted during restore".into(),
         MissCause::DynamicChanged { diff, .. } => {
+            // Such a script depends on every file of its package, as in cargo.
+            let why = if diff.whole_package { " (build script declares no rerun-if-*)" } else { "" };
             if let Some(p) = diff.changed_paths.first() {
-                format!("path content changed: {}", p.display())
+                format!("path content changed: {}{why}", p.display())
             } else if let Some(p) = diff.appeared_paths.first() {
-                format!("path appeared: {}", p.display())
+                format!("path appeared: {}{why}", p.display())
             } else if let Some(p) = diff.missing_paths.first() {
-                format!("path disappeared: {}", p.display())
+                format!("path disappeared: {}{why}", p.display())
             } else if let Some(e) = diff.changed_envs.first() {
                 format!("env changed: {e}")
             } else {
@@ -116,7 +134,7 @@ fn print_lookup_summary(
     for entry in misses {
         by_cat.entry(miss_category(&entry.1)).or_default().push(entry);
     }
-    for cat in [Category::Rust, Category::Cargo, Category::BuildScript, Category::Cascade] {
+    for cat in [Category::Rust, Category::Cargo, Category::BuildScript, Category::Cascade, Category::Evicted] {
         let entries = by_cat.get(&cat);
         let count = entries.map(|v| v.len()).unwrap_or(0);
         if count == 0 {
@@ -140,7 +158,7 @@ fn print_lookup_summary(
             let cat = miss_category(cause);
             let trig_count = match cause {
                 MissCause::DynamicChanged { diff, .. } => diff.total(),
-                MissCause::Cascade { .. } => 1,
+                MissCause::Cascade { .. } | MissCause::Evicted => 1,
                 MissCause::NewStaticKey { .. } => 0,
             };
             info!(
@@ -211,15 +229,20 @@ struct ZbArgs {
     command: Option<Commands>,
 
     /// Build in release mode
-    #[arg(long, global = true)]
+    #[arg(short, long, global = true, conflicts_with = "profile")]
     release: bool,
 
-    /// Build for the target triple
-    #[arg(long, global = true)]
-    target: Option<String>,
+    /// Build artifacts with the specified profile (can be specified
+    /// multiple times)
+    #[arg(long, global = true, value_name = "PROFILE-NAME")]
+    profile: Vec<String>,
+
+    /// Build for the target triple (can be specified multiple times)
+    #[arg(long, global = true, value_name = "TRIPLE")]
+    target: Vec<String>,
 
     /// Space or comma separated list of features to activate
-    #[arg(long, global = true)]
+    #[arg(short = 'F', long, global = true)]
     features: Vec<String>,
 
     /// Activate all available features
@@ -234,26 +257,162 @@ struct ZbArgs {
     #[arg(short, long, global = true)]
     package: Vec<String>,
 
+    /// Build all packages in the workspace
+    #[arg(long, visible_alias = "all", global = true)]
+    workspace: bool,
+
+    /// Exclude packages from the build (with --workspace)
+    #[arg(long, global = true, value_name = "SPEC")]
+    exclude: Vec<String>,
+
+    /// Build only this package's library
+    #[arg(long, global = true)]
+    lib: bool,
+
+    /// Build only the specified binary
+    #[arg(long, global = true, value_name = "NAME")]
+    bin: Vec<String>,
+
+    /// Build all binaries
+    #[arg(long, global = true)]
+    bins: bool,
+
+    /// Build only the specified example
+    #[arg(long, global = true, value_name = "NAME")]
+    example: Vec<String>,
+
+    /// Build all examples
+    #[arg(long, global = true)]
+    examples: bool,
+
+    /// Build only the specified test target
+    #[arg(long, global = true, value_name = "NAME")]
+    test: Vec<String>,
+
+    /// Build all targets that have `test = true` set
+    #[arg(long, global = true)]
+    tests: bool,
+
+    /// Build only the specified bench target
+    #[arg(long, global = true, value_name = "NAME")]
+    bench: Vec<String>,
+
+    /// Build all targets that have `bench = true` set
+    #[arg(long, global = true)]
+    benches: bool,
+
+    /// Build all targets
+    #[arg(long, global = true)]
+    all_targets: bool,
+
     /// Number of parallel jobs
     #[arg(short, long, global = true)]
     jobs: Option<u32>,
 
+    /// Do not abort the build as soon as there is an error
+    #[arg(long, global = true)]
+    keep_going: bool,
+
+    /// Ignore `rust-version` specification in packages
+    #[arg(long, global = true)]
+    ignore_rust_version: bool,
+
     /// Path to Cargo.toml
     #[arg(long, global = true)]
     manifest_path: Option<PathBuf>,
 
+    /// Directory for all generated artifacts
+    #[arg(long, global = true, value_name = "DIRECTORY")]
+    target_dir: Option<PathBuf>,
+
+    /// Assert that `Cargo.lock` will remain unchanged
+    #[arg(long, global = true)]
+    locked: bool,
+
+    /// Run without accessing the network
+    #[arg(long, global = true)]
+    offline: bool,
+
+    /// Equivalent to specifying both --locked and --offline
+    #[arg(long, global = true)]
+    frozen: bool,
+
+    /// Override a configuration value
+    #[arg(long, global = true, value_name = "KEY=VALUE|PATH")]
+    config: Vec<String>,
+
+    /// Unstable (nightly-only) flags to Cargo
+    #[arg(short = 'Z', global = true, value_name = "FLAG")]
+    unstable_flags: Vec<String>,
+
+    /// Coloring: auto, always, never
+    #[arg(long, global = true, value_name = "WHEN")]
+    color: Option<String>,
+
+    /// Do not print cargo log messages
+    #[arg(short, long, global = true)]
+    quiet: bool,
+
+    /// Copy final artifacts to this directory, as cargo's `--artifact-dir`
+    #[arg(long, global = true, value_name = "PATH")]
+    artifact_dir: Option<PathBuf>,
+
+    /// Error format, as for cargo: human, short, json,
+    /// json-render-diagnostics, json-diagnostic-short,
+    /// json-diagnostic-rendered-ansi
+    #[arg(long, global = true, value_name = "FMT")]
+    message_format: Vec<String>,
+
     /// Cache directory (default: ~/.cache/cargo-zb/)
     #[arg(long)]
     cache_dir: Option<PathBuf>,
 
-    /// Cache backend: "fs" or "lmdb"
+    /// Cache backend: "fs", "lmdb" or "http", or a comma-separated stack
+    /// such as "fs,http" (local tier first, uploads written back)
     #[arg(long, default_value = "fs")]
     cache_backend: String,
 
+    /// Base URL for the http backend (default: $CARGO_ZB_CACHE_URL)
+    #[arg(long)]
+    cache_url: Option<String>,
+
+    /// Header sent with every http cache request, as "Name: value"
+    /// (default: $CARGO_ZB_CACHE_AUTH)
+    #[arg(long)]
+    cache_auth_header: Option<String>,
+
+    /// Connect / stall timeout for the http backend, in seconds
+    #[arg(long, default_value_t = 30)]
+    cache_timeout: u64,
+
+    /// Compress newly stored artifacts: "zstd" or "zstd:<level>" (default: none)
+    #[arg(long, value_parser = str::parse::<cache::codec::Compression>)]
+    compress: Option<cache::codec::Compression>,
+
     /// Disable caching (just run cargo build)
     #[arg(long)]
     no_cache: bool,
 
+    /// Restore uncompressed artifacts as hardlinks to the fs cache's
+    /// read-only blobs (for throwaway target dirs, e.g. CI); they are
+    /// copied if cargo has to run
+    #[arg(long)]
+    hardlink: bool,
+
+    /// Restore a unit cargo rebuilds when the dependency outputs it reads
+    /// match an earlier build's, instead of compiling it
+    #[arg(long)]
+    early_cutoff: bool,
+
+    /// Parallel threads for cache restore
+    #[arg(long, default_value_t = 4)]
+    io_threads: usize,
+
+    /// Submit small-file restores through io_uring (fs backend)
+    #[cfg(feature = "io-uring")]
+    #[arg(long)]
+    io_uring: bool,
+
     /// Verbose output
     #[arg(short, long, action = clap::ArgAction::Count)]
     verbose: u8,
@@ -268,16 +427,69 @@ enum Commands {
         /// Skip sccache comparison
         #[arg(long)]
         no_sccache: bool,
+
+        /// Skip the compressed store/restore pass (uses --compress, or zstd:3)
+        #[arg(long)]
+        no_compress: bool,
     },
 
     ListBenched,
+
+    /// Type-check through the cache, like `cargo check`
+    Check,
+
+    /// Lint through the cache, like `cargo clippy`
+    Clippy {
+        /// Lint options for clippy-driver, e.g. `-D warnings`
+        #[arg(last = true)]
+        args: Vec<String>,
+    },
+
+    /// Build test targets through the cache, then run them like `cargo test`
+    Test {
+        /// Only run tests whose names contain this string
+        testname: Option<String>,
+
+        /// Compile, but don't run tests
+        #[arg(long)]
+        no_run: bool,
+
+        /// Run all tests regardless of failure
+        #[arg(long)]
+        no_fail_fast: bool,
+
+        /// Run tests even when a passing result for the same inputs is cached
+        #[arg(long)]
+        rerun_tests: bool,
+
+        /// Env var the tests read, to key cached results on (repeatable)
+        #[arg(long, value_name = "NAME")]
+        test_env: Vec<String>,
+
+        /// Arguments for the test binaries
+        #[arg(last = true)]
+        args: Vec<String>,
+    },
+
+    /// Evict least-recently-used cache entries and orphaned manifests
+    Gc {
+        /// Evict until the cache is at most this large (e.g. 20G, 512M)
+        #[arg(long, value_parser = gc::parse_size)]
+        max_size: Option<u64>,
+
+        /// Evict entries not used within this long (e.g. 14d, 12h)
+        #[arg(long, value_parser = gc::parse_age)]
+        max_age: Option<std::time::Duration>,
+    },
 }
 
 fn main() -> Result<()> {
-    let Cli { command: CargoSub::Zb(cli) } = Cli::parse();
+    let mut args: Vec<_> = std::env::args_os().collect();
+    let toolchain_override = toolchain::take_override(&mut args);
+    let Cli { command: CargoSub::Zb(mut cli) } = Cli::parse_from(args);
 
     match &cli.command {
-        Some(Commands::Bench { no_sccache }) => {
+        Some(Commands::Bench { no_sccache, no_compress }) => {
             let manifest = cli.manifest_path.clone()
                 .unwrap_or_else(|| PathBuf::from("Cargo.toml"));
             return bench::run_bench(
@@ -285,6 +497,9 @@ fn main() -> Result<()> {
                 cli.release,
                 &cli.cache_backend,
                 !no_sccache,
+                (!no_compress)
+                    .then(|| cli.compress.unwrap_or_else(|| "zstd".parse().unwrap()))
+                    .filter(|c| !c.codec.is_none()),
             );
         }
         Some(Commands::ListBenched) => {
@@ -293,39 +508,6 @@ fn main() -> Result<()> {
         _ => {}
     }
 
-    // When cargo runs as a library, RUSTUP_HOME and RUSTUP_TOOLCHAIN
-    // may be missing — build scripts need them.
-    unsafe {
-        if std::env::var_os("RUSTUP_TOOLCHAIN").is_none() {
-            let output = std::process::Command::new("rustup")
-                .args(["default"])
-                .output();
-            let toolchain = output.ok().and_then(|o| {
-                let s = String::from_utf8(o.stdout).ok()?;
-                Some(s.split_whitespace().next()?.to_string())
-            });
-            std::env::set_var(
-                "RUSTUP_TOOLCHAIN",
-                toolchain.as_deref().unwrap_or("stable"),
-            );
-        }
-        if std::env::var_os("RUSTUP_HOME").is_none() {
-            if let Some(home) = std::env::var_os("HOME") {
-                let rustup_home = std::path::PathBuf::from(home).join(".rustup");
-                if rustup_home.exists() {
-                    std::env::set_var("RUSTUP_HOME", &rustup_home);
-                }
-            }
-        }
-    }
-
-    // cd to manifest dir so cargo's GlobalContext picks up .cargo/config.toml
-    if let Some(manifest) = &cli.manifest_path {
-        if let Some(dir) = manifest.canonicalize().ok().and_then(|p| p.parent().map(|d| d.to_path_buf())) {
-            std::env::set_current_dir(&dir)?;
-        }
-    }
-
     // Default keeps our output to a head summary only. -v / -vv / CARGO_LOG
     // expand into our debug/trace paths and (independently) bump cargo's
     // Shell verbosity so its rustc-invocation lines appear.
@@ -344,165 +526,430 @@ fn main() -> Result<()> {
         .with_target(false)
         .without_time()
         .compact()
+        // stdout carries `--message-format json` messages.
+        .with_writer(std::io::stderr)
         .init();
 
+    if let Some(Commands::Gc { max_size, max_age }) = &cli.command {
+        let cache = open_cache(&cli)?;
+        gc::run_gc(&*cache, *max_size, *max_age)?;
+        return Ok(());
+    }
+
+    // When cargo runs as a library, RUSTUP_HOME and RUSTUP_TOOLCHAIN
+    // may be missing — build scripts need them.
+    toolchain::activate(toolchain_override.as_deref());
+    unsafe {
+        // What `cargo clippy` does: check, with clippy-driver standing in
+        // for rustc on workspace members.
+        if let Some(Commands::Clippy { args }) = &cli.command {
+            std::env::set_var("RUSTC_WORKSPACE_WRAPPER", "clippy-driver");
+            let clippy_args: String = args.iter().map(|a| format!("{a}__CLIPPY_HACKERY__")).collect();
+            std::env::set_var("CLIPPY_ARGS", clippy_args);
+        }
+        if std::env::var_os("RUSTUP_HOME").is_none()
+            && let Some(home) = std::env::var_os("HOME")
+        {
+            let rustup_home = std::path::PathBuf::from(home).join(".rustup");
+            if rustup_home.exists() {
+                std::env::set_var("RUSTUP_HOME", &rustup_home);
+            }
+        }
+    }
+
+    // cd to manifest dir so cargo's GlobalContext picks up .cargo/config.toml;
+    // path flags still mean what they would to cargo run from here.
+    resolve_paths(&mut cli, &std::env::current_dir()?);
+    if let Some(manifest) = &cli.manifest_path
+        && let Some(dir) = manifest.canonicalize().ok().and_then(|p| p.parent().map(|d| d.to_path_buf()))
+    {
+        std::env::set_current_dir(&dir)?;
+    }
+
     if cli.no_cache {
-        info!("caching disabled, running plain cargo build");
+        info!("caching disabled, running plain cargo");
         return run_plain_build(&cli);
     }
 
     run_cached_build(&cli)
 }
 
+/// A var's value for a unit's compilation, as the per-unit lookup resolves
+/// it: the `rustc-env` of its package's build-script run (among `outputs`)
+/// over cargo-zb's environment.
+fn script_env<'a>(
+    graph: &'a cargo::core::compiler::unit_graph::UnitGraph,
+    outputs: &'a HashMap<Unit, harvest::ScriptOutputs>,
+) -> impl Fn(&Unit, &str) -> Option<String> + 'a {
+    move |unit, name| {
+        let script = cargo_interop::own_script_run(graph, unit).and_then(|d| outputs.get(d));
+        match script {
+            Some(outputs) => outputs.lookup(name),
+            None => std::env::var(name).ok(),
+        }
+    }
+}
+
+/// Make the path flags absolute against `cwd`, the directory cargo-zb was
+/// run from. A `--config` value is a file when it names one, as cargo
+/// decides.
+fn resolve_paths(cli: &mut ZbArgs, cwd: &Path) {
+    let paths = [&mut cli.manifest_path, &mut cli.target_dir, &mut cli.artifact_dir, &mut cli.cache_dir];
+    for path in paths.into_iter().flatten() {
+        *path = cwd.join(&*path);
+    }
+    for arg in &mut cli.config {
+        let path = cwd.join(&*arg);
+        if !arg.is_empty() && path.exists() {
+            *arg = path.to_string_lossy().into_owned();
+        }
+    }
+}
+
 fn open_cache(cli: &ZbArgs) -> Result<Box<dyn CacheBackend>> {
-    let dir = match &cli.cache_dir {
-        Some(d) => d.clone(),
-        None => cache::default_cache_dir()?,
-    };
-    let cache: Box<dyn CacheBackend> = match cli.cache_backend.as_str() {
-        "lmdb" => Box::new(cache::lmdb::LmdbCache::open(&dir, None)?),
-        "fs" => Box::new(cache::fs::FsCache::new(&dir)?),
-        other => anyhow::bail!("unknown cache backend: {other} (expected \"fs\" or \"lmdb\")"),
+    let names: Vec<&str> = cli.cache_backend.split(',').map(str::trim).collect();
+    if let [name] = names[..] {
+        let cache = open_backend(cli, name)?;
+        // A shared cache on its own gets the leniency it has as a tier: a
+        // failed lookup is a miss and a failed upload a warning, never a
+        // failed build.
+        return Ok(match name {
+            "http" => Box::new(cache::remote::RemoteCache::new(cache)),
+            _ => cache,
+        });
+    }
+    // Only the first tier reads --cache-dir, so it must be the only local one.
+    for name in &names[1..] {
+        if matches!(*name, "fs" | "lmdb") {
+            anyhow::bail!("--cache-backend {}: {name} can only be the first tier", cli.cache_backend);
+        }
+    }
+    let local = open_backend(cli, names[0])?;
+    let remotes = names[1..]
+        .iter()
+        .map(|name| open_backend(cli, name))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Box::new(cache::tiered::TieredCache::new(local, remotes)?))
+}
+
+fn cache_dir(cli: &ZbArgs) -> Result<PathBuf> {
+    match &cli.cache_dir {
+        Some(d) => Ok(d.clone()),
+        None => cache::default_cache_dir(),
+    }
+}
+
+fn open_backend(cli: &ZbArgs, name: &str) -> Result<Box<dyn CacheBackend>> {
+    let dir = cache_dir(cli)?;
+    let compression = cli.compress.unwrap_or_default();
+    let cache: Box<dyn CacheBackend> = match name {
+        "lmdb" => Box::new(cache::lmdb::LmdbCache::open(&dir, None)?.with_compression(compression)),
+        "fs" => {
+            let fs = cache::fs::FsCache::new(&dir)?
+                .with_compression(compression)
+                .with_hardlinks(cli.hardlink);
+            #[cfg(feature = "io-uring")]
+            let fs = fs.with_io_uring(cli.io_uring);
+            Box::new(fs)
+        }
+        #[cfg(feature = "http")]
+        "http" => {
+            let base_url = cli
+                .cache_url
+                .clone()
+                .or_else(|| std::env::var("CARGO_ZB_CACHE_URL").ok())
+                .ok_or_else(|| anyhow::anyhow!("the http cache backend needs --cache-url or CARGO_ZB_CACHE_URL"))?;
+            let auth_header = cli
+                .cache_auth_header
+                .clone()
+                .or_else(|| std::env::var("CARGO_ZB_CACHE_AUTH").ok());
+            debug!("cache: http at {base_url}");
+            return Ok(Box::new(cache::http::HttpCache::new(cache::http::HttpConfig {
+                base_url,
+                auth_header,
+                timeout: std::time::Duration::from_secs(cli.cache_timeout),
+            })?
+            .with_compression(compression)));
+        }
+        other => anyhow::bail!("unknown cache backend: {other} (expected \"fs\", \"lmdb\" or \"http\")"),
     };
     debug!("cache: {} at {}", cache.name(), dir.display());
     Ok(cache)
 }
 
 fn run_cached_build(cli: &ZbArgs) -> Result<()> {
-    let t_start = std::time::Instant::now();
-    let cache = open_cache(cli)?;
-
-    let gctx = cargo::GlobalContext::default()?;
-    set_cargo_verbosity(&gctx);
+    let cache: Arc<dyn CacheBackend> = open_cache(cli)?.into();
+    statcache::init(&cache_dir(cli)?);
+    let gctx = cargo_context(cli)?;
     let root = cargo_interop::resolve_manifest(cli.manifest_path.as_deref(), &gctx)?;
     let ws = cargo::core::Workspace::new(&root, &gctx)?;
     let compile_opts = build_compile_options(cli, &gctx)?;
 
+    let built = cached_compile(cli, &cache, &ws, &compile_opts)?;
+    save_stat_cache();
+    let Some(Commands::Test { testname, no_run, no_fail_fast, rerun_tests, test_env, args }) = &cli.command else {
+        return cache.flush();
+    };
+    // An all-hit build never ran cargo; its (fresh) compile now lists the
+    // test binaries and doctests.
+    let compilation = match built.compilation {
+        Some(c) => c,
+        None => cargo_interop::execute_build(&ws, &compile_opts[0], &Default::default())?,
+    };
+    let opts = testrun::TestRunOptions {
+        test_args: testname.iter().chain(args).map(String::as_str).collect(),
+        no_run: *no_run,
+        no_fail_fast: *no_fail_fast,
+        rerun: *rerun_tests,
+        test_env,
+    };
+    let result = testrun::run_tests(&ws, &compilation, &built.full_keys, &*cache, &opts);
+    save_stat_cache();
+    cache.flush()?;
+    if let Err(err) = result {
+        cargo::exit_with_error(err, &mut gctx.shell());
+    }
+    Ok(())
+}
+
+/// A stat cache that can't be written only costs the next run some hashing.
+fn save_stat_cache() {
+    if let Err(e) = statcache::save() {
+        tracing::warn!("failed to save the stat cache: {e:#}");
+    }
+}
+
+/// What a cached build leaves for the test runner.
+struct CachedBuild<'gctx> {
+    /// cargo's compilation, if cargo had to run.
+    compilation: Option<cargo::core::compiler::Compilation<'gctx>>,
+    /// Full keys of every unit the build could attest, doctests included.
+    full_keys: HashMap<Unit, hash::CacheKey>,
+}
+
+fn cached_compile<'gctx>(
+    cli: &ZbArgs,
+    shared_cache: &Arc<dyn CacheBackend>,
+    ws: &cargo::core::Workspace<'gctx>,
+    compile_opts: &[cargo::ops::CompileOptions],
+) -> Result<CachedBuild<'gctx>> {
+    let t_start = std::time::Instant::now();
+    let cache = &**shared_cache;
+
     let interner = UnitInterner::new();
-    let bcx = cargo_interop::build_bcx(&ws, &interner, &compile_opts)?;
-    let rustc_version = cargo_interop::rustc_verbose_version(&ws)?;
-    let target_dir = cargo_interop::target_dir(&ws);
-    debug!("unit graph: {} units, {} roots", bcx.unit_graph.len(), bcx.roots.len());
+    let plan = cargo_interop::Plan::new(ws, &interner, compile_opts)?;
+    let target_dir = cargo_interop::target_dir(ws);
+    debug!("unit graph: {} units, {} roots", plan.unit_graph.len(), plan.roots.len());
 
-    let static_keys = hash::compute_cache_keys(&bcx.unit_graph, &bcx.roots, &rustc_version)?;
+    let key_ctx = cargo_interop::key_context(ws, &plan)?;
+    let roots = &key_ctx.paths;
+    let hash::UnitKeys { static_keys, own_keys } = hash::compute_cache_keys(&plan.unit_graph, &plan.roots, &key_ctx)?;
     let t_setup = t_start.elapsed();
 
-    let units: Vec<Unit> = cargo_interop::topo_order(&bcx.unit_graph, &bcx.roots);
+    // Doctest units are never compiled — rustdoc builds them when the tests
+    // run — so there is nothing to look up or store; the lib they link is an
+    // ordinary unit.
+    let units: Vec<Unit> = cargo_interop::topo_order(&plan.unit_graph, &plan.roots)
+        .into_iter()
+        .filter(|u| !u.mode.is_doc_test())
+        .collect();
+
+    // Fast path: if nothing changed since a build of this exact graph, its
+    // index lists every unit's full key and one bulk restore is all it takes.
+    let build_key = build_index::build_key(&units, &static_keys);
+    let mut hits: HashMap<Unit, hash::CacheKey> = HashMap::new();
+    let mut misses: Vec<(Unit, MissCause)> = Vec::new();
+    // The manifest each hit was found by, for the next build index.
+    let mut hit_inputs: HashMap<Unit, cache::DynamicInputs> = HashMap::new();
+    let indexed = match build_index::lookup(cache, roots, &build_key, &units, &static_keys)? {
+        Some(keys) if build_index::restore(cache, &target_dir, roots, cli.io_threads, &keys)? => {
+            debug!("build index hit: {} units", keys.len());
+            hits = keys;
+            true
+        }
+        _ => false,
+    };
 
     // Phase 1: per-unit lookup in topo order. A unit can hit only if all its
     // deps hit (we need their full_keys to derive ours). For each unit, try
     // every recorded dynamic-inputs manifest under its static_key; pick the
     // first whose (content_hash + dep_full_keys) points to a stored bundle.
-    debug!("looking up {} units in cache...", units.len());
-    let mut hits: HashMap<Unit, hash::CacheKey> = HashMap::new();
-    let mut misses: Vec<(Unit, MissCause)> = Vec::new();
-
-    for unit in &units {
-        let static_key = static_keys.get(unit).expect("static key for every unit");
-
-        let dep_units: Vec<&Unit> = bcx
-            .unit_graph
-            .get(unit)
-            .map(|deps| deps.iter().map(|d| &d.unit).collect())
-            .unwrap_or_default();
-        let missing_dep: Option<&Unit> = dep_units
-            .iter()
-            .find(|d| !hits.contains_key(*d))
-            .copied();
-
-        // Always evaluate own state first — even if a dep is missing — so
-        // we report the unit's own root cause instead of hiding it behind a
-        // cascade (e.g. on a fresh cold build, every unit's static_key is
-        // genuinely new; that's more useful info than "dep X missed").
-        let manifests = cache.list_dynamic_inputs(static_key.as_bytes())?;
-        let mut best_diff: Option<cache::DiffReport> = None;
-        for inputs in &manifests {
-            if let Ok(d) = inputs.diff_current(|n| std::env::var(n).ok()) {
-                let total = d.total();
-                let curr_total = best_diff.as_ref().map(|x| x.total()).unwrap_or(usize::MAX);
-                if total < curr_total {
-                    best_diff = Some(d);
-                }
+    if !indexed {
+        debug!("looking up {} units in cache...", units.len());
+
+        // Restores run on `--io-threads` workers while the lookup continues;
+        // consumers only need a dep's full_key, not its files on disk.
+        let mut script_outputs: HashMap<Unit, harvest::ScriptOutputs> = HashMap::new();
+        std::thread::scope(|scope| -> Result<()> {
+            let mut restorer = artifacts::Restorer::start(scope, cache, &target_dir, roots, cli.io_threads);
+            let mut restoring: Vec<Unit> = Vec::new();
+
+            // Every unit's recorded manifests, up front, so the paths they
+            // declare are hashed in parallel before the walk.
+            let mut manifests_of: HashMap<&Unit, Vec<cache::DynamicInputs>> = HashMap::new();
+            for unit in &units {
+                manifests_of.insert(unit, cache.list_dynamic_inputs(static_keys[unit].as_bytes())?);
             }
-        }
-        // For non-RunCustomBuild units, "env changed" entries from rustc's
-        // dep-info may reflect env vars that build scripts set via
-        // `cargo:rustc-env=` — those are absent from the process env at
-        // zb-invocation time, so `diff_current` flags them even when our
-        // content_hash (which also uses process env) is stable. When a dep
-        // missed, that's the actual root cause; suppress these false-flag
-        // diff entries to avoid mis-classifying the unit as "rust".
-        let diff_meaningful = best_diff.as_ref().map(|d| {
-            !d.changed_paths.is_empty()
-                || !d.appeared_paths.is_empty()
-                || !d.missing_paths.is_empty()
-                // Only count an env diff as meaningful if its current value
-                // is set (suggests a real env change at zb-time). Diffs where
-                // current is None but stored was Some(...) are typically
-                // build-script-injected envs that never appear in our env.
-                || d.changed_envs.iter().any(|n| std::env::var(n).is_ok())
-        }).unwrap_or(false);
-
-        let own_would_miss = manifests.is_empty() || diff_meaningful;
-
-        if let Some(dep) = missing_dep {
-            // Dep missed. If our own state would have missed too (real diff
-            // or no manifest), report own cause; otherwise it's a cascade.
-            let cause = if own_would_miss {
-                classify_miss(unit, manifests.is_empty(), best_diff)
-            } else {
-                MissCause::Cascade {
-                    dep_name: format!("{} ({})", dep.pkg.name(), dep.target.name()),
+            let declared: Vec<PathBuf> = manifests_of
+                .values()
+                .flatten()
+                .flat_map(|m| m.paths.iter().map(|p| roots.resolve(&p.path)))
+                .collect::<std::collections::HashSet<_>>()
+                .into_iter()
+                .collect();
+            cache::prehash(&declared);
+
+            for unit in &units {
+                let static_key = static_keys.get(unit).expect("static key for every unit");
+
+                let dep_units: Vec<&Unit> = plan
+                    .unit_graph
+                    .get(unit)
+                    .map(|deps| deps.iter().map(|d| &d.unit).collect())
+                    .unwrap_or_default();
+                let missing_dep: Option<&Unit> = dep_units
+                    .iter()
+                    .find(|d| !hits.contains_key(*d))
+                    .copied();
+                let own_script = cargo_interop::own_script_run(&plan.unit_graph, unit);
+                let script = own_script.and_then(|d| script_outputs.get(d));
+                let env_lookup = |n: &str| match script {
+                    Some(outputs) => outputs.lookup(n),
+                    None => std::env::var(n).ok(),
+                };
+
+                // Always evaluate own state first — even if a dep is missing — so
+                // we report the unit's own root cause instead of hiding it behind a
+                // cascade (e.g. on a fresh cold build, every unit's static_key is
+                // genuinely new; that's more useful info than "dep X missed").
+                let manifests = manifests_of.remove(unit).unwrap_or_default();
+                let mut best_diff: Option<cache::DiffReport> = None;
+                for inputs in &manifests {
+                    if let Ok(d) = inputs.diff_current(roots, env_lookup) {
+                        let total = d.total();
+                        let curr_total = best_diff.as_ref().map(|x| x.total()).unwrap_or(usize::MAX);
+                        if total < curr_total {
+                            best_diff = Some(d);
+                        }
+                    }
                 }
-            };
-            misses.push((unit.clone(), cause));
-            continue;
-        }
-
-        let dep_full_keys: Vec<hash::CacheKey> = dep_units
-            .iter()
-            .map(|d| *hits.get(*d).expect("checked above"))
-            .collect();
-
-        let mut hit = false;
-        for inputs in &manifests {
-            let content = match inputs.content_hash(|n| std::env::var(n).ok()) {
-                Ok(c) => c,
-                Err(e) => {
-                    debug!("dynamic content hash failed for {}: {e}", unit.pkg.name());
+                // Until the package's build script hits, its `rustc-env` vars
+                // can't be told apart from real env changes; its miss is the
+                // cause then.
+                let script_pending = script.is_none() && own_script.is_some();
+                let diff_meaningful = best_diff.as_ref().is_some_and(|d| {
+                    !d.changed_paths.is_empty()
+                        || !d.appeared_paths.is_empty()
+                        || !d.missing_paths.is_empty()
+                        || (!d.changed_envs.is_empty() && !script_pending)
+                });
+
+                let own_would_miss = manifests.is_empty() || diff_meaningful;
+
+                if let Some(dep) = missing_dep {
+                    // Dep missed. If our own state would have missed too (real diff
+                    // or no manifest), report own cause; otherwise it's a cascade.
+                    let cause = if own_would_miss {
+                        classify_miss(unit, manifests.is_empty(), best_diff)
+                    } else {
+                        MissCause::Cascade {
+                            dep_name: format!("{} ({})", dep.pkg.name(), dep.target.name()),
+                        }
+                    };
+                    misses.push((unit.clone(), cause));
                     continue;
                 }
-            };
-            let full = hash::combine_full_key(static_key, &content, &dep_full_keys);
-            if cache.contains_unit(full.as_bytes())? {
-                let restored = artifacts::restore_unit(&*cache, full.as_bytes(), &target_dir)?;
-                debug!(
-                    "restored {} files for {} ({})",
-                    restored,
-                    unit.pkg.name(),
-                    unit.target.name()
-                );
-                hits.insert(unit.clone(), full);
-                hit = true;
-                break;
+
+                let mut dep_full_keys: Vec<hash::CacheKey> = dep_units
+                    .iter()
+                    .map(|d| *hits.get(*d).expect("checked above"))
+                    .collect();
+                dep_full_keys.extend(script.map(|outputs| outputs.key(roots)));
+
+                let mut hit = false;
+                for inputs in &manifests {
+                    let content = match inputs.content_hash(roots, env_lookup) {
+                        Ok(c) => c,
+                        Err(e) => {
+                            debug!("dynamic content hash failed for {}: {e}", unit.pkg.name());
+                            continue;
+                        }
+                    };
+                    let full = hash::combine_full_key(static_key, &content, &dep_full_keys);
+                    if let Some(id) = restorer.submit(full.as_bytes())? {
+                        debug_assert_eq!(id, restoring.len());
+                        debug!("restoring {} ({})", unit.pkg.name(), unit.target.name());
+                        restoring.push(unit.clone());
+                        hits.insert(unit.clone(), full);
+                        hit_inputs.insert(unit.clone(), inputs.clone());
+                        if unit.mode.is_run_custom_build() {
+                            let outputs = harvest::ScriptOutputs::from_cache(cache, roots, full.as_bytes())?;
+                            script_outputs.insert(unit.clone(), outputs);
+                        }
+                        hit = true;
+                        break;
+                    }
+                }
+                if !hit {
+                    let cause = classify_miss(unit, manifests.is_empty(), best_diff);
+                    misses.push((unit.clone(), cause));
+                }
             }
-        }
-        if !hit {
-            let cause = classify_miss(unit, manifests.is_empty(), best_diff);
-            misses.push((unit.clone(), cause));
+
+            let report = restorer.finish()?;
+            debug!("restored {} files for {} units", report.files, restoring.len() - report.failed.len());
+            for id in report.failed {
+                let unit = &restoring[id];
+                hits.remove(unit);
+                hit_inputs.remove(unit);
+                misses.push((unit.clone(), MissCause::Evicted));
+            }
+            Ok(())
+        })?;
+        if misses.is_empty() {
+            let unit_env = script_env(&plan.unit_graph, &script_outputs);
+            build_index::store(cache, roots, &build_key, &units, &static_keys, &hits, &hit_inputs, unit_env)?;
         }
     }
 
     let t_lookup = t_start.elapsed() - t_setup;
     print_lookup_summary(&hits, &misses);
 
+    // Hardlinked restores are only for a build cargo doesn't touch. cargo
+    // runs to rebuild the misses, and in test mode even after a full hit.
+    if cli.hardlink && (!misses.is_empty() || matches!(cli.command, Some(Commands::Test { .. }))) {
+        let copied = artifacts::unshare_restored(&target_dir)?;
+        debug!("copied {copied} hardlinked files before running cargo");
+    }
+
     if misses.is_empty() {
         debug!(
             "all units restored from cache (setup={:.2}s lookup={:.2}s)",
             t_setup.as_secs_f64(),
             t_lookup.as_secs_f64(),
         );
-        return Ok(());
+        // cargo never runs, so nothing else would uplift these units'
+        // outputs, show the warnings they were built with or report them
+        // to `--message-format json` consumers. In test mode the follow-up
+        // cargo compile does all of that itself.
+        if !matches!(cli.command, Some(Commands::Test { .. })) {
+            let mut runners = plan.runners()?;
+            let build_config = &compile_opts[0].build_config;
+            let mut shell = ws.gctx().shell();
+            for unit in &units {
+                let runner = runners.get(unit);
+                artifacts::uplift_unit(runner, unit)?;
+                diagnostics::replay(&mut shell, runner, unit, build_config.message_format)?;
+                if build_config.emit_json() {
+                    messages::emit_restored(&mut shell, runner, unit)?;
+                }
+            }
+            if build_config.emit_json() {
+                messages::emit_finished(&mut shell);
+            }
+        }
+        let full_keys = with_doctest_keys(&plan.unit_graph, &static_keys, hits);
+        return Ok(CachedBuild { compilation: None, full_keys });
     }
 
     // Phase 2: run cargo build. cargo's incremental will treat the restored
@@ -510,8 +957,30 @@ fn run_cached_build(cli: &ZbArgs) -> Result<()> {
     // match, so it should only recompile units whose dynamic inputs we couldn't
     // attest. cargo prints its own status lines (`Compiling X`, `Finished`,
     // any warnings/errors) to stderr — we don't add a banner before it.
+    // Each profile is its own cargo build; fully restored ones are cheap
+    // no-ops that still uplift their outputs and report them.
     let t_build = std::time::Instant::now();
-    cargo_interop::execute_build(&ws, &compile_opts)?;
+    let diagnostics = Arc::new(diagnostics::Diagnostics::default());
+    let cutoffs = cli
+        .early_cutoff
+        .then(|| cutoff::Cutoffs::new(&plan, &own_keys, roots, &target_dir).map(Arc::new))
+        .transpose()?;
+    let cutoff_exec = cutoffs.as_ref().map(|cutoffs| {
+        Arc::new(cutoff::CutoffExecutor::new(cutoffs.clone(), shared_cache.clone(), cli.io_threads, diagnostics.clone()))
+    });
+    let exec: Arc<dyn Executor> = match &cutoff_exec {
+        Some(exec) => exec.clone(),
+        None => Arc::new(diagnostics::CapturingExecutor(diagnostics.clone())),
+    };
+    let mut compilation = None;
+    for opts in compile_opts {
+        compilation = Some(cargo_interop::execute_build_with(ws, opts, &exec)?);
+    }
+    if let Some(exec) = &cutoff_exec
+        && exec.restored() > 0
+    {
+        info!("cargo-zb: early cutoff restored {} units", exec.restored());
+    }
     let build_secs = t_build.elapsed().as_secs_f64();
 
     // Phase 3: harvest dynamic inputs + per-unit artifacts in topo order so
@@ -519,18 +988,27 @@ fn run_cached_build(cli: &ZbArgs) -> Result<()> {
     // units that hit cache (we need to track their full_key for consumers).
     let t_harvest = std::time::Instant::now();
     debug!("harvesting per-unit cache entries...");
-    {
-        let runner = cargo_interop::prepared_runner(&bcx)?;
+    let full_keys = {
+        let mut runners = plan.runners()?;
         let mut stored = 0usize;
         let mut skipped = 0usize;
         let mut full_keys: HashMap<Unit, hash::CacheKey> = hits.clone();
+        let mut bundled = hit_inputs;
 
         for unit in &units {
             if full_keys.contains_key(unit) {
                 continue; // already known from Phase 1 hit
             }
+            // The build or a restore left the package's build-script output
+            // in `target/`.
+            let script = match cargo_interop::own_script_run(&plan.unit_graph, unit) {
+                Some(run) => Some(harvest::ScriptOutputs::read(runners.get(run), run)?),
+                None => None,
+            };
+            let runner = runners.get(unit);
+            diagnostics::write_for_unit(runner, &diagnostics, unit)?;
             let static_key = static_keys.get(unit).expect("static key");
-            let inputs = match harvest::harvest_unit(&runner, unit)? {
+            let mut inputs = match harvest::harvest_unit(runner, roots, unit)? {
                 Some(i) => i,
                 None => {
                     skipped += 1;
@@ -538,7 +1016,7 @@ fn run_cached_build(cli: &ZbArgs) -> Result<()> {
                 }
             };
 
-            let dep_full_keys: Vec<hash::CacheKey> = bcx
+            let mut dep_full_keys: Vec<hash::CacheKey> = plan
                 .unit_graph
                 .get(unit)
                 .map(|deps| {
@@ -550,7 +1028,7 @@ fn run_cached_build(cli: &ZbArgs) -> Result<()> {
 
             // If we don't have full_keys for all this unit's deps, don't try
             // to cache it (we'd compute a different key on lookup).
-            let dep_count = bcx.unit_graph.get(unit).map(|d| d.len()).unwrap_or(0);
+            let dep_count = plan.unit_graph.get(unit).map(|d| d.len()).unwrap_or(0);
             if dep_full_keys.len() != dep_count {
                 debug!(
                     "incomplete dep full_keys for {} ({}); not caching",
@@ -561,34 +1039,65 @@ fn run_cached_build(cli: &ZbArgs) -> Result<()> {
                 continue;
             }
 
-            let content = inputs.content_hash(|n| std::env::var(n).ok())?;
+            dep_full_keys.extend(script.as_ref().map(|outputs| outputs.key(roots)));
+            let content = inputs.content_hash(roots, |n| match &script {
+                Some(outputs) => outputs.lookup(n),
+                None => std::env::var(n).ok(),
+            })?;
             let full = hash::combine_full_key(static_key, &content, &dep_full_keys);
             full_keys.insert(unit.clone(), full);
+            inputs.unit_keys.push(*full.as_bytes());
+            // The same manifest under the unit's own key finds the bundle by
+            // what its dependencies built instead (see `cutoff`).
+            let cutoff_inputs = match &cutoffs {
+                Some(cutoffs) => cutoffs
+                    .key(&diagnostics::unit_id(runner, unit), &content)?
+                    .map(|key| cache::DynamicInputs {
+                        cutoffs: vec![cache::Cutoff { key: *key.as_bytes(), unit_key: *full.as_bytes() }],
+                        ..inputs.clone()
+                    }),
+                None => None,
+            };
 
             if cache.contains_unit(full.as_bytes())? {
                 cache.put_dynamic_inputs(static_key.as_bytes(), &inputs)?;
+                if let Some(indexed) = &cutoff_inputs {
+                    cache.put_dynamic_inputs(own_keys[unit].as_bytes(), indexed)?;
+                }
+                bundled.insert(unit.clone(), inputs);
                 continue;
             }
 
-            let unit_artifacts = artifacts::collect_unit_artifacts(&runner, unit);
+            let unit_artifacts = artifacts::collect_unit_artifacts(runner, unit);
             if unit_artifacts.files.is_empty() {
                 debug!("no artifacts for {} ({})", unit.pkg.name(), unit.target.name());
                 continue;
             }
 
             cache.put_dynamic_inputs(static_key.as_bytes(), &inputs)?;
-            let count = artifacts::store_unit(&*cache, full.as_bytes(), &unit_artifacts, &target_dir)?;
+            if let Some(indexed) = &cutoff_inputs {
+                cache.put_dynamic_inputs(own_keys[unit].as_bytes(), indexed)?;
+            }
+            let count = artifacts::store_unit(cache, full.as_bytes(), &unit_artifacts, &target_dir, roots)?;
             debug!(
                 "stored {} files for {} ({})",
                 count,
                 unit.pkg.name(),
                 unit.target.name()
             );
+            bundled.insert(unit.clone(), inputs);
             stored += 1;
         }
 
         debug!("stored {} unit bundles ({} skipped)", stored, skipped);
-    }
+        let mut script_outputs = HashMap::new();
+        for unit in units.iter().filter(|u| u.mode.is_run_custom_build()) {
+            script_outputs.insert(unit.clone(), harvest::ScriptOutputs::read(runners.get(unit), unit)?);
+        }
+        let unit_env = script_env(&plan.unit_graph, &script_outputs);
+        build_index::store(cache, roots, &build_key, &units, &static_keys, &full_keys, &bundled, unit_env)?;
+        full_keys
+    };
     let harvest_secs = t_harvest.elapsed().as_secs_f64();
 
     debug!(
@@ -599,52 +1108,131 @@ fn run_cached_build(cli: &ZbArgs) -> Result<()> {
         harvest_secs,
         t_start.elapsed().as_secs_f64(),
     );
-    Ok(())
+    let full_keys = with_doctest_keys(&plan.unit_graph, &static_keys, full_keys);
+    Ok(CachedBuild { compilation, full_keys })
+}
+
+/// Add keys for doctest units, which are never built: a package's doctests
+/// are as fresh as their static key (the lib's sources) and their deps.
+fn with_doctest_keys(
+    unit_graph: &cargo::core::compiler::unit_graph::UnitGraph,
+    static_keys: &HashMap<Unit, hash::CacheKey>,
+    mut full_keys: HashMap<Unit, hash::CacheKey>,
+) -> HashMap<Unit, hash::CacheKey> {
+    for (unit, deps) in unit_graph.iter() {
+        if !unit.mode.is_doc_test() {
+            continue;
+        }
+        let dep_keys: Option<Vec<hash::CacheKey>> =
+            deps.iter().map(|d| full_keys.get(&d.unit).copied()).collect();
+        if let Some(dep_keys) = dep_keys {
+            let full = hash::combine_full_key(&static_keys[unit], &[0; 32], &dep_keys);
+            full_keys.insert(unit.clone(), full);
+        }
+    }
+    full_keys
 }
 
 fn run_plain_build(cli: &ZbArgs) -> Result<()> {
-    let gctx = cargo::GlobalContext::default()?;
-    set_cargo_verbosity(&gctx);
+    let gctx = cargo_context(cli)?;
     let root = cargo_interop::resolve_manifest(cli.manifest_path.as_deref(), &gctx)?;
     let ws = cargo::core::Workspace::new(&root, &gctx)?;
-    let compile_opts = build_compile_options(cli, &gctx)?;
-    cargo_interop::execute_build(&ws, &compile_opts)
+    let mut compile_opts = build_compile_options(cli, &gctx)?;
+    if let Some(Commands::Test { testname, no_run, no_fail_fast, args, .. }) = &cli.command {
+        let test_args: Vec<&str> = testname.iter().chain(args).map(String::as_str).collect();
+        let opts = cargo::ops::TestOptions {
+            compile_opts: compile_opts.remove(0),
+            no_run: *no_run,
+            no_fail_fast: *no_fail_fast,
+        };
+        if let Err(err) = cargo::ops::run_tests(&ws, &opts, &test_args) {
+            cargo::exit_with_error(err, &mut gctx.shell());
+        }
+        return Ok(());
+    }
+    for opts in &compile_opts {
+        cargo_interop::execute_build(&ws, opts, &Default::default())?;
+    }
+    Ok(())
 }
 
-/// Cargo's `Shell::new()` defaults to `Verbose`, which causes every rustc
-/// invocation to be echoed. The cargo CLI explicitly downgrades this in
-/// `configure()` to match user expectations. We do the same: Normal by default,
-/// Verbose only if `CARGO_LOG` is set in the environment.
-fn set_cargo_verbosity(gctx: &cargo::GlobalContext) {
-    use cargo::core::shell::Verbosity;
-    let verbosity = if std::env::var_os("CARGO_LOG").is_some() {
-        Verbosity::Verbose
-    } else {
-        Verbosity::Normal
-    };
-    gctx.shell().set_verbosity(verbosity);
+/// A `GlobalContext` configured from the cargo flags, as the cargo CLI's
+/// `configure()` does. Cargo's `Shell::new()` defaults to `Verbose`, which
+/// echoes every rustc invocation; like cargo we use Normal by default
+/// (Verbose only if `CARGO_LOG` is set in the environment).
+fn cargo_context(cli: &ZbArgs) -> Result<cargo::GlobalContext> {
+    let mut gctx = cargo::GlobalContext::default()?;
+    let verbose = u32::from(std::env::var_os("CARGO_LOG").is_some());
+    gctx.configure(
+        verbose,
+        cli.quiet,
+        cli.color.as_deref(),
+        cli.frozen,
+        cli.locked,
+        cli.offline,
+        &cli.target_dir,
+        &cli.unstable_flags,
+        &cli.config,
+    )?;
+    Ok(gctx)
 }
 
+/// Compile options for each requested profile, in the order given;
+/// `--profile` may repeat to build several in one run.
 fn build_compile_options(
     cli: &ZbArgs,
     gctx: &cargo::GlobalContext,
+) -> Result<Vec<cargo::ops::CompileOptions>> {
+    let mut profiles: Vec<Option<&str>> = Vec::new();
+    if cli.release {
+        profiles.push(Some("release"));
+    }
+    for profile in &cli.profile {
+        if profile == "doc" {
+            anyhow::bail!("profile `doc` is reserved and not allowed to be explicitly specified");
+        }
+        if !profiles.contains(&Some(profile)) {
+            profiles.push(Some(profile));
+        }
+    }
+    if profiles.is_empty() {
+        profiles.push(None);
+    }
+    if profiles.len() > 1 && matches!(cli.command, Some(Commands::Test { .. })) {
+        anyhow::bail!("`cargo zb test` takes a single --profile");
+    }
+    profiles
+        .into_iter()
+        .map(|profile| profile_compile_options(cli, gctx, profile))
+        .collect()
+}
+
+fn profile_compile_options(
+    cli: &ZbArgs,
+    gctx: &cargo::GlobalContext,
+    profile: Option<&str>,
 ) -> Result<cargo::ops::CompileOptions> {
-    use cargo::core::compiler::{CompileKind, CompileTarget, UserIntent};
+    use cargo::core::compiler::{CompileKind, UserIntent};
+    use cargo::util::interning::InternedString;
 
-    let mut opts = cargo::ops::CompileOptions::new(gctx, UserIntent::Build)?;
+    let intent = match cli.command {
+        Some(Commands::Test { .. }) => UserIntent::Test,
+        Some(Commands::Check | Commands::Clippy { .. }) => UserIntent::Check { test: false },
+        _ => UserIntent::Build,
+    };
+    let mut opts = cargo::ops::CompileOptions::new(gctx, intent)?;
 
     if let Some(j) = cli.jobs {
         opts.build_config.jobs = j;
     }
+    opts.build_config.message_format = messages::parse_format(&cli.message_format)?;
+    opts.build_config.export_dir = cli.artifact_dir.clone();
 
-    if let Some(ref target) = cli.target {
-        let compile_target = CompileTarget::new(target)?;
-        opts.build_config.requested_kinds = vec![CompileKind::Target(compile_target)];
-    }
+    opts.build_config.requested_kinds = CompileKind::from_requested_targets(gctx, &cli.target)?;
+    opts.build_config.keep_going = cli.keep_going;
 
-    if cli.release {
-        opts.build_config.requested_profile =
-            cargo::util::interning::InternedString::new("release");
+    if let Some(profile) = profile {
+        opts.build_config.requested_profile = InternedString::new(profile);
     }
 
     opts.cli_features = cargo::core::resolver::features::CliFeatures::from_command_line(
@@ -653,13 +1241,156 @@ fn build_compile_options(
         !cli.no_default_features,
     )?;
 
-    if !cli.package.is_empty() {
-        opts.spec = cargo::ops::Packages::Packages(
-            cli.package.clone(),
-        );
-    } else {
-        opts.spec = cargo::ops::Packages::Default;
+    opts.spec = cargo::ops::Packages::from_flags(
+        cli.workspace,
+        cli.exclude.clone(),
+        cli.package.clone(),
+    )?;
+    opts.filter = cargo::ops::CompileFilter::from_raw_arguments(
+        cli.lib,
+        cli.bin.clone(),
+        cli.bins,
+        cli.test.clone(),
+        cli.tests,
+        cli.example.clone(),
+        cli.examples,
+        cli.bench.clone(),
+        cli.benches,
+        cli.all_targets,
+    );
+    if cli.ignore_rust_version {
+        opts.honor_rust_version = Some(false);
     }
 
     Ok(opts)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_flags_resolve_against_the_invocation_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let work = dir.path().join("work");
+        std::fs::create_dir_all(&work).unwrap();
+        std::fs::write(work.join("zb.toml"), "[alias]\nzbtest = \"build\"\n").unwrap();
+        let Cli { command: CargoSub::Zb(mut cli) } = Cli::parse_from([
+            "cargo",
+            "zb",
+            "--manifest-path",
+            "../project/Cargo.toml",
+            "--target-dir",
+            "out",
+            "--config",
+            "zb.toml",
+            "--config",
+            "build.jobs=2",
+            "--artifact-dir",
+            "dist",
+            "--cache-dir",
+            "cache",
+        ]);
+        resolve_paths(&mut cli, &work);
+        assert_eq!(cli.manifest_path, Some(work.join("../project/Cargo.toml")));
+        assert_eq!(cli.artifact_dir, Some(work.join("dist")));
+        assert_eq!(cli.cache_dir, Some(work.join("cache")));
+        assert_eq!(cli.config, [work.join("zb.toml").to_string_lossy().as_ref(), "build.jobs=2"]);
+
+        // Whatever directory cargo's context is created in.
+        let gctx = cargo_context(&cli).unwrap();
+        let target_dir = gctx.target_dir().unwrap().unwrap();
+        assert_eq!(target_dir.as_path_unlocked(), work.join("out"));
+        let alias: Option<String> = gctx.get("alias.zbtest").unwrap();
+        assert_eq!(alias.as_deref(), Some("build"));
+    }
+
+    /// What cargo prints, shared with the test.
+    #[derive(Clone, Default)]
+    struct Captured(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn early_cutoff_restores_a_rebuilt_unit_whose_dependencies_came_out_the_same() {
+        let dir = tempfile::tempdir().unwrap();
+        // The cache and cargo home stay out of the package's file listing.
+        let root = dir.path().join("pkg");
+        let write = |rel: &str, contents: &str| {
+            let path = root.join(rel);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        };
+        write("Cargo.toml", "[package]\nname = \"cut\"\nversion = \"0.1.0\"\nedition = \"2021\"\n");
+        // Reruns whenever flavor.txt changes, emitting the same output. The
+        // file is outside the package, so only the run's dynamic inputs see it.
+        write(
+            "build.rs",
+            "fn main() {\n    println!(\"cargo:rerun-if-changed=../flavor.txt\");\n    \
+             let _ = std::fs::read_to_string(\"../flavor.txt\");\n}\n",
+        );
+        write("../flavor.txt", "one");
+        write("src/lib.rs", "pub fn f() {\n    let unused = 1;\n}\n");
+        let Cli { command: CargoSub::Zb(cli) } = Cli::parse_from([
+            "cargo".as_ref(),
+            "zb".as_ref(),
+            "--early-cutoff".as_ref(),
+            "--cache-dir".as_ref(),
+            dir.path().join("cache").as_os_str(),
+        ]);
+        let cache: Arc<dyn CacheBackend> = open_cache(&cli).unwrap().into();
+        let build = |exec: Option<&Arc<dyn Executor>>| {
+            let out = Captured::default();
+            let gctx = cargo::GlobalContext::new(
+                cargo::core::Shell::from_write(Box::new(out.clone())),
+                root.clone(),
+                dir.path().join("home"),
+            );
+            let ws = cargo::core::Workspace::new(&root.join("Cargo.toml"), &gctx).unwrap();
+            let opts = build_compile_options(&cli, &gctx).unwrap();
+            match exec {
+                // The first build stores the lib's cutoff manifest.
+                None => drop(cached_compile(&cli, &cache, &ws, &opts).unwrap()),
+                Some(exec) => drop(cargo_interop::execute_build_with(&ws, &opts[0], exec).unwrap()),
+            }
+            String::from_utf8(out.0.lock().unwrap().clone()).unwrap()
+        };
+        assert!(build(None).contains("unused variable"));
+
+        // The script reruns, so cargo rebuilds the lib; the executor restores it.
+        write("../flavor.txt", "two");
+        let gctx = cargo::GlobalContext::new(
+            cargo::core::Shell::from_write(Box::new(std::io::sink())),
+            root.clone(),
+            dir.path().join("home"),
+        );
+        let ws = cargo::core::Workspace::new(&root.join("Cargo.toml"), &gctx).unwrap();
+        let opts = build_compile_options(&cli, &gctx).unwrap();
+        let interner = UnitInterner::new();
+        let plan = cargo_interop::Plan::new(&ws, &interner, &opts).unwrap();
+        let key_ctx = cargo_interop::key_context(&ws, &plan).unwrap();
+        let keys = hash::compute_cache_keys(&plan.unit_graph, &plan.roots, &key_ctx).unwrap();
+        let target_dir = cargo_interop::target_dir(&ws);
+        let cutoffs = cutoff::Cutoffs::new(&plan, &keys.own_keys, &key_ctx.paths, &target_dir).unwrap();
+        let cutoff_exec = Arc::new(cutoff::CutoffExecutor::new(
+            Arc::new(cutoffs),
+            cache.clone(),
+            cli.io_threads,
+            Arc::new(diagnostics::Diagnostics::default()),
+        ));
+        let exec: Arc<dyn Executor> = cutoff_exec.clone();
+        let printed = build(Some(&exec));
+        assert_eq!(cutoff_exec.restored(), 1, "{printed}");
+        // Its warning comes from the bundle, as if rustc had printed it.
+        assert!(printed.contains("unused variable"), "{printed}");
+    }
+}
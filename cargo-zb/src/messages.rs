@@ -0,0 +1,207 @@
+//! cargo's `--message-format json` messages for builds cargo didn't run.
+//!
+//! Whenever cargo executes, it reports every unit itself, fresh or not. When
+//! every unit hits, cargo-zb stands in for it: each restored unit gets the
+//! `compiler-artifact` (or `build-script-executed`) message cargo prints for
+//! a fresh unit, built from the same planned outputs and the restored
+//! build-script `output` file, followed by `build-finished`.
+
+use std::path::PathBuf;
+
+use anyhow::{Result, bail};
+use cargo::core::compiler::{BuildOutput, BuildRunner, FileFlavor, MessageFormat, Unit};
+
+use cargo::core::shell::Shell;
+use cargo::util::machine_message::{
+    Artifact, ArtifactDebuginfo, ArtifactProfile, BuildFinished, BuildScript, Message,
+};
+use cargo_util_schemas::manifest::TomlDebugInfo;
+
+/// Parse `--message-format` values the way cargo does: comma-separated,
+/// with the `json-*` modifiers implying `json`.
+pub fn parse_format(values: &[String]) -> Result<MessageFormat> {
+    const CONFLICT: &str = "cannot specify two kinds of `message-format` arguments";
+    let default_json = MessageFormat::Json {
+        short: false,
+        ansi: false,
+        render_diagnostics: false,
+    };
+    let mut format = None;
+    for fmt in values.iter().flat_map(|v| v.split(',')) {
+        let fmt = fmt.to_ascii_lowercase();
+        let base = match fmt.as_str() {
+            "json" => Some(default_json),
+            "human" => Some(MessageFormat::Human),
+            "short" => Some(MessageFormat::Short),
+            "json-render-diagnostics"
+            | "json-diagnostic-short"
+            | "json-diagnostic-rendered-ansi" => None,
+            s => bail!("invalid message format specifier: `{s}`"),
+        };
+        if let Some(base) = base {
+            if format.is_some() {
+                bail!(CONFLICT);
+            }
+            format = Some(base);
+            continue;
+        }
+        let MessageFormat::Json {
+            short,
+            ansi,
+            render_diagnostics,
+        } = format.get_or_insert(default_json)
+        else {
+            bail!(CONFLICT);
+        };
+        match fmt.as_str() {
+            "json-render-diagnostics" => *render_diagnostics = true,
+            "json-diagnostic-short" => *short = true,
+            _ => *ansi = true,
+        }
+    }
+    Ok(format.unwrap_or(MessageFormat::Human))
+}
+
+/// Print the message cargo would have printed for `unit` had it been fresh.
+pub fn emit_restored(
+    shell: &mut Shell,
+    runner: &mut BuildRunner<'_, '_>,
+    unit: &Unit,
+) -> Result<()> {
+    let msg = if unit.mode.is_run_custom_build() {
+        build_script_executed(runner, unit)?
+    } else {
+        compiler_artifact(runner, unit)?
+    };
+    drop(writeln!(shell.out(), "{msg}"));
+    Ok(())
+}
+
+pub fn emit_finished(shell: &mut Shell) {
+    let msg = BuildFinished { success: true }.to_json_string();
+    drop(writeln!(shell.out(), "{msg}"));
+}
+
+fn compiler_artifact(runner: &mut BuildRunner<'_, '_>, unit: &Unit) -> Result<String> {
+    // As cargo reports them: the uplifted copy where there is one, else the
+    // file in deps/, skipping outputs this build didn't produce.
+    let destinations: Vec<(PathBuf, bool)> = runner
+        .outputs(unit)?
+        .iter()
+        .filter(|o| o.path.exists())
+        .map(|o| {
+            let path = match &o.hardlink {
+                Some(dst) if dst.exists() => dst.clone(),
+                _ => o.path.clone(),
+            };
+            (path, o.flavor == FileFlavor::Normal)
+        })
+        .collect();
+    let executable = match runner.get_executable(unit)? {
+        Some(_) => destinations
+            .iter()
+            .find(|(_, normal)| *normal)
+            .map(|(path, _)| path.clone()),
+        None => None,
+    };
+    let filenames = destinations.into_iter().map(|(path, _)| path).collect();
+    let profile = &unit.profile;
+    let debuginfo = match profile.debuginfo.into_inner() {
+        TomlDebugInfo::None => ArtifactDebuginfo::Int(0),
+        TomlDebugInfo::Limited => ArtifactDebuginfo::Int(1),
+        TomlDebugInfo::Full => ArtifactDebuginfo::Int(2),
+        TomlDebugInfo::LineDirectivesOnly => ArtifactDebuginfo::Named("line-directives-only"),
+        TomlDebugInfo::LineTablesOnly => ArtifactDebuginfo::Named("line-tables-only"),
+    };
+    Ok(Artifact {
+        package_id: unit.pkg.package_id().to_spec(),
+        manifest_path: unit.pkg.manifest_path().to_path_buf(),
+        target: &unit.target,
+        profile: ArtifactProfile {
+            opt_level: profile.opt_level.as_str(),
+            debuginfo: Some(debuginfo),
+            debug_assertions: profile.debug_assertions,
+            overflow_checks: profile.overflow_checks,
+            test: unit.mode.is_any_test(),
+        },
+        features: unit.features.iter().map(|f| f.to_string()).collect(),
+        filenames,
+        executable,
+        fresh: true,
+    }
+    .to_json_string())
+}
+
+fn build_script_executed(runner: &BuildRunner<'_, '_>, unit: &Unit) -> Result<String> {
+    let files = runner.files();
+    let run_dir = files.build_script_run_dir(unit);
+    let out_dir = files.build_script_out_dir(unit);
+    // `output` names OUT_DIR as it was when the script ran; cargo rewrites
+    // that prefix to the current one, as it does for fresh scripts.
+    let out_dir_when_generated = std::fs::read(run_dir.join("root-output"))
+        .ok()
+        .and_then(|b| cargo_util::paths::bytes2path(&b).ok())
+        .unwrap_or_else(|| out_dir.clone());
+    let output = BuildOutput::parse_file(
+        &run_dir.join("output"),
+        unit.pkg.library().map(|t| t.crate_name()),
+        &unit.pkg.to_string(),
+        &out_dir_when_generated,
+        &out_dir,
+        runner.bcx.gctx.nightly_features_allowed,
+        unit.pkg.targets(),
+        &unit.pkg.rust_version().cloned(),
+    )?;
+    let linked_paths: Vec<String> = output
+        .library_paths
+        .iter()
+        .map(|l| l.as_ref().display().to_string())
+        .collect();
+    Ok(BuildScript {
+        package_id: unit.pkg.package_id().to_spec(),
+        linked_libs: &output.library_links,
+        linked_paths: &linked_paths,
+        cfgs: &output.cfgs,
+        env: &output.env,
+        out_dir: &out_dir,
+    }
+    .to_json_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(values: &[&str]) -> Result<MessageFormat> {
+        parse_format(&values.iter().map(|v| v.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn parse_format_matches_cargo() {
+        assert_eq!(parse(&[]).unwrap(), MessageFormat::Human);
+        assert_eq!(parse(&["short"]).unwrap(), MessageFormat::Short);
+        assert_eq!(
+            parse(&["json-render-diagnostics"]).unwrap(),
+            MessageFormat::Json {
+                short: false,
+                ansi: false,
+                render_diagnostics: true
+            }
+        );
+        assert_eq!(
+            parse(&[
+                "json,json-diagnostic-rendered-ansi",
+                "json-diagnostic-short"
+            ])
+            .unwrap(),
+            MessageFormat::Json {
+                short: true,
+                ansi: true,
+                render_diagnostics: false
+            }
+        );
+        assert!(parse(&["json", "human"]).is_err());
+        assert!(parse(&["short,json-render-diagnostics"]).is_err());
+        assert!(parse(&["xml"]).is_err());
+    }
+}
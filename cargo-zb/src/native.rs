@@ -0,0 +1,271 @@
+//! Native inputs of build scripts: the C/C++ toolchain and system libraries.
+//!
+//! A build script that compiles C (`cc`, `cmake`) or probes for a system
+//! library (`pkg-config`) reads `CC`, `CFLAGS` and friends, runs whatever
+//! compiler they name and has rustc link libraries it found on the system.
+//! None of that shows up in `rerun-if-*` unless the script says so. Scripts
+//! that do any of it leave `rustc-link-lib` / `rustc-link-search` lines in
+//! their `output`; for those, [`build_script_inputs`] adds to the unit's
+//! dynamic inputs:
+//!
+//! - the toolchain env vars in every spelling the `cc` crate reads them;
+//! - the C and C++ compiler binaries. Their contents stand in for their
+//!   version, without running them on every lookup;
+//! - each linked library as the linker would resolve it: the link-search
+//!   dirs, then the compiler's own library dirs. Libraries the build itself
+//!   produced (under the target dir) are outputs, not inputs.
+
+use std::path::{Path, PathBuf};
+
+/// Variables the `cc` crate and `pkg-config` consult.
+const TOOLCHAIN_VARS: &[&str] = &[
+    "CC",
+    "CXX",
+    "AR",
+    "CFLAGS",
+    "CXXFLAGS",
+    "ARFLAGS",
+    "CRATE_CC_NO_DEFAULTS",
+    "PKG_CONFIG",
+    "PKG_CONFIG_PATH",
+    "PKG_CONFIG_LIBDIR",
+    "PKG_CONFIG_SYSROOT_DIR",
+    "PKG_CONFIG_ALL_STATIC",
+];
+
+/// Wrappers that put the real compiler in their first argument.
+const COMPILER_WRAPPERS: &[&str] = &["ccache", "sccache", "distcc"];
+
+/// What a build script asked rustc to link.
+#[derive(Debug, Default, PartialEq)]
+struct LinkDirectives {
+    search: Vec<PathBuf>,
+    /// `(kind, name)`; kind is `""` when the script gave none.
+    libs: Vec<(String, String)>,
+}
+
+/// Paths and env var names a build script's native toolchain use adds to its
+/// dynamic inputs, given its `output` and the triple it built for.
+pub fn build_script_inputs(
+    output: &str,
+    target: &str,
+    target_dir: &Path,
+    env_lookup: impl Fn(&str) -> Option<String>,
+) -> (Vec<PathBuf>, Vec<String>) {
+    let directives = parse_link_directives(output);
+    if directives.libs.is_empty() && directives.search.is_empty() {
+        return (Vec::new(), Vec::new());
+    }
+    let envs = toolchain_env_names(target);
+    let mut paths: Vec<PathBuf> = ["CC", "CXX"]
+        .iter()
+        .filter_map(|var| compiler(var, target, &env_lookup))
+        .collect();
+    let mut dirs = directives.search.clone();
+    if let Some(cc) = paths.first() {
+        dirs.extend(compiler_lib_dirs(cc));
+    }
+    for (kind, name) in &directives.libs {
+        match resolve_lib(&dirs, kind, name) {
+            Some(lib) if !lib.starts_with(target_dir) => paths.push(lib),
+            Some(_) => {}
+            None => tracing::debug!("{kind}={name}: not found in {dirs:?}"),
+        }
+    }
+    (paths, envs)
+}
+
+fn parse_link_directives(output: &str) -> LinkDirectives {
+    let mut out = LinkDirectives::default();
+    for line in output.lines() {
+        let Some(body) = line
+            .strip_prefix("cargo::")
+            .or_else(|| line.strip_prefix("cargo:"))
+        else {
+            continue;
+        };
+        if let Some(value) = body.strip_prefix("rustc-link-search=") {
+            let (kind, path) = value.split_once('=').unwrap_or(("all", value));
+            if kind != "framework" {
+                out.search.push(PathBuf::from(path));
+            }
+        } else if let Some(value) = body.strip_prefix("rustc-link-lib=") {
+            // `[KIND[:MODIFIERS]=]NAME[:RENAME]`
+            let (kind, name) = value.split_once('=').unwrap_or(("", value));
+            let kind = kind.split(':').next().unwrap_or_default();
+            let name = name.split(':').next().unwrap_or_default();
+            if kind != "framework" && !name.is_empty() {
+                out.libs.push((kind.to_string(), name.to_string()));
+            }
+        }
+    }
+    out
+}
+
+/// `CC`, `CC_x86_64-unknown-linux-gnu`, `CC_x86_64_unknown_linux_gnu`,
+/// `HOST_CC`, `TARGET_CC` and so on for every toolchain var.
+fn toolchain_env_names(target: &str) -> Vec<String> {
+    let underscored = target.replace('-', "_");
+    let mut names = Vec::new();
+    for var in TOOLCHAIN_VARS {
+        names.push(var.to_string());
+        names.push(format!("{var}_{target}"));
+        names.push(format!("{var}_{underscored}"));
+        names.push(format!("HOST_{var}"));
+        names.push(format!("TARGET_{var}"));
+    }
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// The compiler binary `var` (`CC` or `CXX`) selects for `target`, looked up
+/// in the order the `cc` crate uses.
+fn compiler(
+    var: &str,
+    target: &str,
+    env_lookup: &impl Fn(&str) -> Option<String>,
+) -> Option<PathBuf> {
+    let underscored = target.replace('-', "_");
+    let configured = [
+        format!("{var}_{target}"),
+        format!("{var}_{underscored}"),
+        format!("TARGET_{var}"),
+        var.to_string(),
+    ]
+    .iter()
+    .find_map(|name| env_lookup(name).filter(|v| !v.trim().is_empty()));
+    let command = configured.unwrap_or_else(|| if var == "CC" { "cc" } else { "c++" }.to_string());
+    let mut words = command.split_whitespace();
+    let mut program = words.next()?;
+    let is_wrapper = |p: &str| {
+        let name = Path::new(p).file_name().and_then(|n| n.to_str());
+        name.is_some_and(|n| COMPILER_WRAPPERS.contains(&n))
+    };
+    if is_wrapper(program) {
+        program = words.next()?;
+    }
+    find_program(program)
+}
+
+/// `program` as the shell would find it: as given if it names a path, else
+/// the first match on `PATH`.
+pub(crate) fn find_program(program: &str) -> Option<PathBuf> {
+    if program.contains('/') {
+        return Some(PathBuf::from(program));
+    }
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(program))
+        .find(|candidate| candidate.is_file())
+}
+
+/// The directories the compiler's linker searches by default
+/// (`-print-search-dirs`).
+fn compiler_lib_dirs(compiler: &Path) -> Vec<PathBuf> {
+    let output = std::process::Command::new(compiler)
+        .arg("-print-search-dirs")
+        .output()
+        .ok()
+        .filter(|o| o.status.success());
+    let Some(output) = output else {
+        return Vec::new();
+    };
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    stdout
+        .lines()
+        .find_map(|l| l.strip_prefix("libraries: ="))
+        .map(|dirs| std::env::split_paths(dirs).collect())
+        .unwrap_or_default()
+}
+
+/// The file `-l<kind>=<name>` links: the first match in `dirs`, shared
+/// before static unless the kind says `static`.
+fn resolve_lib(dirs: &[PathBuf], kind: &str, name: &str) -> Option<PathBuf> {
+    let candidates: Vec<String> = match kind {
+        "static" => vec![format!("lib{name}.a"), format!("{name}.lib")],
+        "dylib" => vec![format!("lib{name}.so"), format!("lib{name}.dylib")],
+        _ => vec![
+            format!("lib{name}.so"),
+            format!("lib{name}.dylib"),
+            format!("lib{name}.a"),
+        ],
+    };
+    dirs.iter().find_map(|dir| {
+        candidates
+            .iter()
+            .map(|file| dir.join(file))
+            .find(|path| path.exists())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn link_directives_in_every_spelling() {
+        let output = "cargo:rustc-link-search=native=/opt/ssl/lib\n\
+                      cargo::rustc-link-search=/usr/local/lib\n\
+                      cargo:rustc-link-search=framework=/Library/Frameworks\n\
+                      cargo:rustc-link-lib=ssl\n\
+                      cargo:rustc-link-lib=static:+whole-archive=foo\n\
+                      cargo:rustc-link-lib=dylib=z:zlib\n\
+                      cargo:rustc-link-lib=framework=Security\n\
+                      cargo:rerun-if-changed=build.rs\n";
+        assert_eq!(
+            parse_link_directives(output),
+            LinkDirectives {
+                search: vec!["/opt/ssl/lib".into(), "/usr/local/lib".into()],
+                libs: vec![
+                    ("".into(), "ssl".into()),
+                    ("static".into(), "foo".into()),
+                    ("dylib".into(), "z".into()),
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn libraries_resolve_like_the_linker() {
+        let dir = tempfile::tempdir().unwrap();
+        let (first, second, target) = (
+            dir.path().join("a"),
+            dir.path().join("b"),
+            dir.path().join("target"),
+        );
+        for d in [&first, &second, &target] {
+            std::fs::create_dir_all(d).unwrap();
+        }
+        std::fs::write(first.join("libfoo.a"), "").unwrap();
+        std::fs::write(second.join("libfoo.so"), "").unwrap();
+        std::fs::write(target.join("libbuilt.a"), "").unwrap();
+        let dirs = [first.clone(), second.clone()];
+        assert_eq!(resolve_lib(&dirs, "", "foo"), Some(first.join("libfoo.a")));
+        assert_eq!(
+            resolve_lib(&dirs, "dylib", "foo"),
+            Some(second.join("libfoo.so"))
+        );
+        assert_eq!(resolve_lib(&dirs, "", "missing"), None);
+
+        let output = format!(
+            "cargo:rustc-link-search=native={}\ncargo:rustc-link-search={}\n\
+             cargo:rustc-link-lib=static=built\ncargo:rustc-link-lib=dylib=foo\n",
+            target.display(),
+            second.display(),
+        );
+        let (paths, envs) =
+            build_script_inputs(&output, "x86_64-unknown-linux-gnu", &target, |_| {
+                Some("/nonexistent/cc".into())
+            });
+        assert_eq!(paths[0], Path::new("/nonexistent/cc"));
+        assert_eq!(paths.last(), Some(&second.join("libfoo.so")));
+        assert!(!paths.contains(&target.join("libbuilt.a")));
+        assert!(envs.contains(&"CFLAGS_x86_64_unknown_linux_gnu".to_string()));
+        assert!(envs.contains(&"TARGET_CC".to_string()));
+
+        let (paths, envs) =
+            build_script_inputs("cargo:rerun-if-changed=x\n", "x", &target, |_| None);
+        assert!(paths.is_empty() && envs.is_empty());
+    }
+}
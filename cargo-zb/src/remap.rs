@@ -0,0 +1,204 @@
+//! Checkout-independent paths, so one cache serves every clone of a project.
+//!
+//! Keys and stored manifests would otherwise embed where the build happened:
+//! dynamic inputs are absolute paths, path packages' source ids name their
+//! directory, and `--remap-path-prefix` or `-L` rustflags spell out the
+//! checkout. [`PathRoots`] replaces the workspace root, `CARGO_HOME` and the
+//! target dir with placeholders wherever a path goes into a key or a stored
+//! manifest, and turns placeholders back into this build's roots on use.
+//!
+//! A few bundled files name paths too: rustc's dep-info, build-script
+//! `output` / `root-output` and cargo's fingerprint JSON. They are stored
+//! with placeholders and rewritten on restore ([`is_path_bearing`]).
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use cargo::core::Workspace;
+
+/// The roots a build's paths are relative to, paired with their placeholders.
+#[derive(Debug, Clone)]
+pub struct PathRoots {
+    /// Longest root first, so a target dir inside the workspace (or a
+    /// `CARGO_HOME` inside it, as some CI setups do) wins over the workspace.
+    roots: Vec<(&'static str, String)>,
+}
+
+impl PathRoots {
+    pub fn new(ws: &Workspace<'_>) -> Self {
+        Self::from_roots(
+            ws.root(),
+            ws.gctx().home().as_path_unlocked(),
+            &crate::cargo_interop::target_dir(ws),
+        )
+    }
+
+    pub fn from_roots(workspace: &Path, cargo_home: &Path, target_dir: &Path) -> Self {
+        let mut roots: Vec<(&'static str, String)> = [
+            ("${ZB_WORKSPACE}", workspace),
+            ("${ZB_CARGO_HOME}", cargo_home),
+            ("${ZB_TARGET}", target_dir),
+        ]
+        .into_iter()
+        .map(|(placeholder, root)| {
+            let root = root.to_string_lossy();
+            (placeholder, root.trim_end_matches('/').to_string())
+        })
+        .filter(|(_, root)| !root.is_empty())
+        .collect();
+        roots.sort_by_key(|(_, root)| std::cmp::Reverse(root.len()));
+        Self { roots }
+    }
+
+    /// `path` with its root, if any, replaced by a placeholder.
+    pub fn normalize(&self, path: &Path) -> PathBuf {
+        for (placeholder, root) in &self.roots {
+            if let Ok(rest) = path.strip_prefix(root) {
+                return Path::new(placeholder).join(rest);
+            }
+        }
+        path.to_path_buf()
+    }
+
+    /// Inverse of [`normalize`](Self::normalize).
+    pub fn resolve(&self, path: &Path) -> PathBuf {
+        for (placeholder, root) in &self.roots {
+            if let Ok(rest) = path.strip_prefix(placeholder) {
+                return Path::new(root).join(rest);
+            }
+        }
+        path.to_path_buf()
+    }
+
+    /// Replace every root occurring in `text` (a flag, an env value, a file)
+    /// as a whole path prefix: `/src/proj` matches in `/src/proj/lib.rs` and
+    /// `--remap-path-prefix=/src/proj=.`, but not in `/src/project`.
+    pub fn normalize_str(&self, text: &str) -> String {
+        let mut out = text.to_string();
+        for (placeholder, root) in &self.roots {
+            out = replace_prefix(&out, root, placeholder);
+        }
+        out
+    }
+
+    /// Inverse of [`normalize_str`](Self::normalize_str).
+    pub fn resolve_str(&self, text: &str) -> String {
+        let mut out = text.to_string();
+        for (placeholder, root) in &self.roots {
+            out = out.replace(placeholder, root);
+        }
+        out
+    }
+
+    /// Rewrite a restored path-bearing file for this build's roots. The file
+    /// is replaced rather than written in place: it may be a hard link into
+    /// the cache (`--hardlink`).
+    pub fn resolve_file(&self, path: &Path) -> Result<()> {
+        let Ok(text) = std::fs::read_to_string(path) else {
+            return Ok(()); // not text; nothing we stored normalized
+        };
+        if !text.contains("${ZB_") {
+            return Ok(());
+        }
+        let resolved = self.resolve_str(&text);
+        std::fs::remove_file(path).with_context(|| format!("replacing {}", path.display()))?;
+        std::fs::write(path, resolved).with_context(|| format!("writing {}", path.display()))
+    }
+}
+
+/// Whether a bundled file (by its path relative to the target dir) names
+/// paths of the build that produced it.
+pub fn is_path_bearing(rel_path: &str) -> bool {
+    let path = Path::new(rel_path);
+    // `deps/<file>`, `.fingerprint/<unit>/<file>`, `build/<unit>/<file>`
+    let ancestor = |n: usize| path.ancestors().nth(n).and_then(|a| a.file_name());
+    match path.file_name().and_then(|n| n.to_str()) {
+        Some(name) if name.ends_with(".d") => ancestor(1) == Some("deps".as_ref()),
+        Some(name) if name.ends_with(".json") => ancestor(2) == Some(".fingerprint".as_ref()),
+        Some("output" | "root-output") => ancestor(2) == Some("build".as_ref()),
+        _ => false,
+    }
+}
+
+fn replace_prefix(text: &str, root: &str, placeholder: &str) -> String {
+    let is_path_char = |c: char| c.is_alphanumeric() || "-_./".contains(c);
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(at) = rest.find(root) {
+        let (before, after) = (&rest[..at], &rest[at + root.len()..]);
+        let starts = before
+            .chars()
+            .next_back()
+            .or_else(|| out.chars().next_back())
+            .is_none_or(|c| !is_path_char(c));
+        let ends = after
+            .chars()
+            .next()
+            .is_none_or(|c| c == '/' || !is_path_char(c));
+        out.push_str(before);
+        out.push_str(if starts && ends { placeholder } else { root });
+        rest = after;
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roots() -> PathRoots {
+        PathRoots::from_roots(
+            Path::new("/home/alice/proj"),
+            Path::new("/home/alice/.cargo"),
+            Path::new("/home/alice/proj/target"),
+        )
+    }
+
+    #[test]
+    fn paths_round_trip_through_the_longest_root() {
+        let roots = roots();
+        let cases = [
+            ("/home/alice/proj/src/lib.rs", "${ZB_WORKSPACE}/src/lib.rs"),
+            (
+                "/home/alice/proj/target/debug/build/x/out/a.rs",
+                "${ZB_TARGET}/debug/build/x/out/a.rs",
+            ),
+            (
+                "/home/alice/.cargo/registry/src/x/lib.rs",
+                "${ZB_CARGO_HOME}/registry/src/x/lib.rs",
+            ),
+            ("/usr/include/stdio.h", "/usr/include/stdio.h"),
+        ];
+        for (path, normalized) in cases {
+            assert_eq!(roots.normalize(Path::new(path)), Path::new(normalized));
+            assert_eq!(roots.resolve(Path::new(normalized)), Path::new(path));
+        }
+    }
+
+    #[test]
+    fn text_is_rewritten_at_path_boundaries_only() {
+        let roots = roots();
+        let text = "--remap-path-prefix=/home/alice/proj=. /home/alice/project /x/home/alice/proj/a \
+                    /home/alice/proj/target/debug/deps/a.d: /home/alice/proj/src/lib.rs";
+        let normalized = roots.normalize_str(text);
+        assert_eq!(
+            normalized,
+            "--remap-path-prefix=${ZB_WORKSPACE}=. /home/alice/project /x/home/alice/proj/a \
+             ${ZB_TARGET}/debug/deps/a.d: ${ZB_WORKSPACE}/src/lib.rs"
+        );
+        assert_eq!(roots.resolve_str(&normalized), text);
+    }
+
+    #[test]
+    fn path_bearing_files() {
+        assert!(is_path_bearing("debug/deps/foo-0123.d"));
+        assert!(is_path_bearing("debug/.fingerprint/foo-0123/lib-foo.json"));
+        assert!(is_path_bearing("debug/build/foo-0123/output"));
+        assert!(is_path_bearing("debug/build/foo-0123/root-output"));
+        assert!(!is_path_bearing("debug/build/foo-0123/out/bindings.d"));
+        assert!(!is_path_bearing("debug/build/foo-0123/out/output"));
+        assert!(!is_path_bearing("debug/deps/libfoo-0123.rlib"));
+        assert!(!is_path_bearing("debug/.fingerprint/foo-0123/dep-lib-foo"));
+    }
+}
@@ -0,0 +1,298 @@
+//! Persistent stat cache: a file's content hash, reused while its stat is
+//! unchanged.
+//!
+//! Keys read every path package's sources, and lookups, harvest and the
+//! build index re-read every dynamic input, including whole directories
+//! named by `rerun-if-changed`. The cache maps a path to the blake3 of its
+//! contents together with the `(dev, inode, size, mtime, ctime)` it had when
+//! hashed. A file whose stat still matches isn't read again, in this run or
+//! (via `<cache dir>/stat-cache`) the next.
+//!
+//! As in git's racy-index handling, a hash is only remembered when the file's
+//! mtime and ctime are a safe distance in the past. Otherwise an edit landing
+//! in the same timestamp tick as our read, without changing the size, would
+//! keep the old hash.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+
+const FILE_NAME: &str = "stat-cache";
+
+/// How recent a change must be for a hash not to be remembered: well above
+/// the timestamp granularity of common filesystems.
+const RACY_WINDOW: Duration = Duration::from_secs(2);
+
+/// Entries unused for this long are dropped on save.
+const MAX_UNUSED_DAYS: u64 = 30;
+
+static GLOBAL: OnceLock<StatCache> = OnceLock::new();
+
+/// Load the persistent cache from `cache_dir`. Without this, [`global`] is an
+/// in-memory cache for the run.
+pub fn init(cache_dir: &Path) {
+    let cache = StatCache::load(cache_dir.join(FILE_NAME));
+    if GLOBAL.set(cache).is_err() {
+        tracing::debug!("stat cache already initialized");
+    }
+}
+
+pub fn global() -> &'static StatCache {
+    GLOBAL.get_or_init(|| StatCache::new(None, RACY_WINDOW))
+}
+
+/// Write the global cache back, if anything changed.
+pub fn save() -> Result<()> {
+    match GLOBAL.get() {
+        Some(cache) => cache.save(),
+        None => Ok(()),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Stat {
+    dev: u64,
+    ino: u64,
+    size: u64,
+    mtime_ns: i64,
+    ctime_ns: i64,
+}
+
+impl Stat {
+    fn of(meta: &std::fs::Metadata) -> Self {
+        Self {
+            dev: meta.dev(),
+            ino: meta.ino(),
+            size: meta.size(),
+            mtime_ns: meta.mtime() * 1_000_000_000 + meta.mtime_nsec(),
+            ctime_ns: meta.ctime() * 1_000_000_000 + meta.ctime_nsec(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    stat: Stat,
+    hash: [u8; 32],
+    /// Day (since the epoch) the entry was last used.
+    used: u64,
+}
+
+pub struct StatCache {
+    file: Option<PathBuf>,
+    racy_window: Duration,
+    entries: Mutex<HashMap<PathBuf, Entry>>,
+    dirty: AtomicBool,
+}
+
+impl StatCache {
+    fn new(file: Option<PathBuf>, racy_window: Duration) -> Self {
+        Self {
+            file,
+            racy_window,
+            entries: Mutex::new(HashMap::new()),
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    /// The cache stored at `file`; empty if it is missing or unreadable.
+    fn load(file: PathBuf) -> Self {
+        let entries = match std::fs::read_to_string(&file) {
+            Ok(text) => parse(&text),
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    tracing::debug!("ignoring stat cache {}: {e}", file.display());
+                }
+                HashMap::new()
+            }
+        };
+        let cache = Self::new(Some(file), RACY_WINDOW);
+        *cache.entries.lock().unwrap() = entries;
+        cache
+    }
+
+    /// blake3 of the regular file at `path`, whose metadata is `meta`.
+    pub fn file_hash(&self, path: &Path, meta: &std::fs::Metadata) -> Result<[u8; 32]> {
+        let stat = Stat::of(meta);
+        let today = today();
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(path)
+            && entry.stat == stat
+        {
+            if entry.used != today {
+                entry.used = today;
+                self.dirty.store(true, Ordering::Relaxed);
+            }
+            return Ok(entry.hash);
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as i64;
+        let contents =
+            std::fs::read(path).with_context(|| format!("hashing {}", path.display()))?;
+        let hash = *blake3::hash(&contents).as_bytes();
+        let settled = now - self.racy_window.as_nanos() as i64;
+        if stat.mtime_ns < settled && stat.ctime_ns < settled {
+            let entry = Entry {
+                stat,
+                hash,
+                used: today,
+            };
+            self.entries
+                .lock()
+                .unwrap()
+                .insert(path.to_path_buf(), entry);
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        Ok(hash)
+    }
+
+    fn save(&self) -> Result<()> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let text = serialize(&self.entries.lock().unwrap(), today());
+        let dir = file.parent().unwrap_or(Path::new("."));
+        std::fs::create_dir_all(dir)?;
+        let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+        tmp.write_all(text.as_bytes())?;
+        tmp.persist(file)
+            .with_context(|| format!("writing {}", file.display()))?;
+        Ok(())
+    }
+}
+
+fn today() -> u64 {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    secs / 86400
+}
+
+/// `<hash> <dev> <ino> <size> <mtime_ns> <ctime_ns> <used> <path>` per line.
+fn serialize(entries: &HashMap<PathBuf, Entry>, today: u64) -> String {
+    let mut out = String::new();
+    for (path, e) in entries {
+        let Some(path) = path.to_str().filter(|p| !p.contains('\n')) else {
+            continue;
+        };
+        if today.saturating_sub(e.used) > MAX_UNUSED_DAYS {
+            continue;
+        }
+        let s = &e.stat;
+        out.push_str(&format!(
+            "{} {} {} {} {} {} {} {path}\n",
+            crate::cache::hex(&e.hash),
+            s.dev,
+            s.ino,
+            s.size,
+            s.mtime_ns,
+            s.ctime_ns,
+            e.used
+        ));
+    }
+    out
+}
+
+fn parse(text: &str) -> HashMap<PathBuf, Entry> {
+    let parse_line = |line: &str| -> Option<(PathBuf, Entry)> {
+        let mut fields = line.splitn(8, ' ');
+        let hash = crate::cache::unhex(fields.next()?)?;
+        let mut num = || fields.next()?.parse::<i64>().ok();
+        let (dev, ino, size) = (num()? as u64, num()? as u64, num()? as u64);
+        let (mtime_ns, ctime_ns, used) = (num()?, num()?, num()? as u64);
+        let path = PathBuf::from(fields.next()?);
+        let stat = Stat {
+            dev,
+            ino,
+            size,
+            mtime_ns,
+            ctime_ns,
+        };
+        Some((path, Entry { stat, hash, used }))
+    };
+    text.lines().filter_map(parse_line).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_are_reused_while_the_stat_matches() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.rs");
+        std::fs::write(&path, "one").unwrap();
+        let meta = || std::fs::metadata(&path).unwrap();
+
+        // Just written: too fresh to remember.
+        let strict = StatCache::new(None, RACY_WINDOW);
+        assert_eq!(
+            strict.file_hash(&path, &meta()).unwrap(),
+            *blake3::hash(b"one").as_bytes()
+        );
+        assert!(strict.entries.lock().unwrap().is_empty());
+
+        let cache = StatCache::new(Some(dir.path().join(FILE_NAME)), Duration::ZERO);
+        let first = cache.file_hash(&path, &meta()).unwrap();
+        // A same-stat entry is trusted without reading the file...
+        cache.entries.lock().unwrap().get_mut(&path).unwrap().hash = [7; 32];
+        assert_eq!(cache.file_hash(&path, &meta()).unwrap(), [7; 32]);
+        // ...and survives a save and load.
+        cache.save().unwrap();
+        let loaded = StatCache::load(dir.path().join(FILE_NAME));
+        assert_eq!(
+            loaded.entries.lock().unwrap()[&path].stat,
+            Stat::of(&meta())
+        );
+
+        // Any change to the file's stat means reading it again.
+        std::fs::write(&path, "two!").unwrap();
+        let second = cache.file_hash(&path, &meta()).unwrap();
+        assert_eq!(second, *blake3::hash(b"two!").as_bytes());
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn stale_and_malformed_lines_are_dropped() {
+        let stat = Stat {
+            dev: 1,
+            ino: 2,
+            size: 3,
+            mtime_ns: 4,
+            ctime_ns: 5,
+        };
+        let entries = HashMap::from([
+            (
+                PathBuf::from("/src/a b.rs"),
+                Entry {
+                    stat,
+                    hash: [1; 32],
+                    used: 100,
+                },
+            ),
+            (
+                PathBuf::from("/src/old.rs"),
+                Entry {
+                    stat,
+                    hash: [2; 32],
+                    used: 10,
+                },
+            ),
+        ]);
+        let text = serialize(&entries, 100) + "garbage line\n";
+        let parsed = parse(&text);
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[Path::new("/src/a b.rs")].hash, [1; 32]);
+    }
+}
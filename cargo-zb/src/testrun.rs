@@ -0,0 +1,440 @@
+//! Test execution for `cargo zb test`, with per-binary result caching.
+//!
+//! Mirrors `cargo test`'s runner (`ops::cargo_test`, private to cargo) over
+//! the `Compilation` of the cached build, except that every test binary and
+//! every package's doctests run under a result key:
+//!
+//! - the unit's full key (binary contents, transitively),
+//! - the test args,
+//! - the runtime inputs, as a `DynamicInputs`: the package's non-`.rs` files
+//!   (fixtures a test might open) and the env the harness sees — what cargo
+//!   sets for the process, libtest's `RUST_TEST_*`, and any `--test-env`.
+//!
+//! A stored result replays its stdout/stderr instead of running. Only passing
+//! runs are stored, so a flaky failure is retried on the next run rather than
+//! replayed.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::path::Path;
+
+use anyhow::Result;
+use cargo::CliResult;
+use cargo::core::compiler::{Compilation, CompileKind, CompileMode, Doctest, Unit, UnitOutput};
+use cargo::core::profiles::{PanicStrategy, Profile};
+use cargo::core::shell::ColorChoice;
+use cargo::core::{PackageId, Target, TargetKind, Workspace};
+use cargo::util::errors::CliError;
+use cargo::util::interning::InternedString;
+use cargo_util::{ProcessBuilder, ProcessError};
+use serde::{Deserialize, Serialize};
+
+use crate::cache::{CacheBackend, DynEnv, DynPath, DynamicInputs};
+use crate::hash::{self, CacheKey};
+use crate::remap::PathRoots;
+
+/// Env vars every test run is keyed on, on top of the ones cargo sets.
+const HARNESS_ENV: &[&str] = &[
+    "RUST_BACKTRACE",
+    "RUST_LIB_BACKTRACE",
+    "RUST_LOG",
+    "RUST_MIN_STACK",
+];
+
+/// The single artifact of a stored test result.
+const RECORD: &str = "test-result.json";
+
+#[derive(Serialize, Deserialize)]
+struct TestRecord {
+    stdout: String,
+    stderr: String,
+}
+
+pub struct TestRunOptions<'a> {
+    pub test_args: Vec<&'a str>,
+    pub no_run: bool,
+    pub no_fail_fast: bool,
+    /// Run every test even if a result is stored (results are still stored).
+    pub rerun: bool,
+    /// Extra env vars whose values key the results.
+    pub test_env: &'a [String],
+}
+
+/// Run (or replay) the tests in `compilation`. `full_keys` holds the full key
+/// of every unit the cached build could attest, doctest units included;
+/// tests of other units run uncached.
+pub fn run_tests(
+    ws: &Workspace<'_>,
+    compilation: &Compilation<'_>,
+    full_keys: &HashMap<Unit, CacheKey>,
+    cache: &dyn CacheBackend,
+    opts: &TestRunOptions<'_>,
+) -> CliResult {
+    let gctx = ws.gctx();
+    let mut tests: Vec<&UnitOutput> = compilation.tests.iter().collect();
+    tests.sort();
+
+    if opts.no_run {
+        for output in tests {
+            gctx.shell().status("Executable", exe_display(ws, output))?;
+        }
+        return Ok(());
+    }
+
+    let full_keys: HashMap<UnitId, CacheKey> =
+        full_keys.iter().map(|(u, k)| (unit_id(u), *k)).collect();
+    let mut runner = Runner {
+        ws,
+        cache,
+        opts,
+        failed: Vec::new(),
+        inputs: HashMap::new(),
+        roots: PathRoots::new(ws),
+    };
+
+    for output in tests {
+        let UnitOutput {
+            unit,
+            path,
+            script_metas,
+        } = output;
+        let mut cmd =
+            compilation.target_process(path, unit.kind, &unit.pkg, script_metas.as_ref())?;
+        cmd.args(&opts.test_args);
+        runner.run(
+            unit,
+            full_keys.get(&unit_id(unit)),
+            &exe_display(ws, output),
+            &cmd,
+        )?;
+    }
+
+    for doctest in &compilation.to_doc_test {
+        let cmd = doctest_process(ws, compilation, doctest, &opts.test_args)?;
+        let label = format!("doc-tests {}", doctest.unit.target.name());
+        runner.run(
+            &doctest.unit,
+            full_keys.get(&unit_id(&doctest.unit)),
+            &label,
+            &cmd,
+        )?;
+    }
+
+    match runner.failed.len() {
+        0 => Ok(()),
+        n => {
+            let list: Vec<String> = runner.failed.iter().map(|f| format!("    {f}")).collect();
+            let noun = if n == 1 { "target" } else { "targets" };
+            Err(CliError::new(
+                anyhow::anyhow!("{n} {noun} failed:\n{}", list.join("\n")),
+                101,
+            ))
+        }
+    }
+}
+
+/// A unit's identity across unit graphs. `compilation` comes from cargo's own
+/// compile, whose units are interned separately from ours: `Unit` compares by
+/// pointer, and its `dep_hash` is a hash of dep `Unit` pointers.
+type UnitId = (
+    PackageId,
+    Target,
+    Profile,
+    CompileKind,
+    CompileMode,
+    Vec<InternedString>,
+);
+
+fn unit_id(unit: &Unit) -> UnitId {
+    (
+        unit.pkg.package_id(),
+        unit.target.clone(),
+        unit.profile.clone(),
+        unit.kind,
+        unit.mode,
+        unit.features.clone(),
+    )
+}
+
+struct Runner<'a, 'gctx> {
+    ws: &'a Workspace<'gctx>,
+    cache: &'a dyn CacheBackend,
+    opts: &'a TestRunOptions<'a>,
+    /// Labels of failed runs, for the `--no-fail-fast` summary.
+    failed: Vec<String>,
+    /// Per-package runtime file inputs, listed once per run.
+    inputs: HashMap<std::path::PathBuf, Vec<DynPath>>,
+    roots: PathRoots,
+}
+
+impl Runner<'_, '_> {
+    fn run(
+        &mut self,
+        unit: &Unit,
+        full_key: Option<&CacheKey>,
+        label: &str,
+        cmd: &ProcessBuilder,
+    ) -> CliResult {
+        let gctx = self.ws.gctx();
+        let key = match full_key {
+            Some(full_key) => Some(self.result_key(unit, full_key, cmd)?),
+            None => None,
+        };
+
+        if let Some(key) = &key
+            && !self.opts.rerun
+            && let Some(data) = self.cache.get_artifact(key.as_bytes(), RECORD)?
+        {
+            let record: TestRecord = serde_json::from_slice(&data).map_err(anyhow::Error::from)?;
+            gctx.shell().status("Cached", label)?;
+            print!("{}", record.stdout);
+            eprint!("{}", record.stderr);
+            self.cache.touch_units(&[*key.as_bytes()])?;
+            return Ok(());
+        }
+
+        gctx.shell().status("Running", label)?;
+        gctx.shell().verbose(|shell| shell.status("Running", cmd))?;
+        let mut record = TestRecord {
+            stdout: String::new(),
+            stderr: String::new(),
+        };
+        let result = cmd.exec_with_streaming(
+            &mut |line| {
+                println!("{line}");
+                record.stdout.push_str(line);
+                record.stdout.push('\n');
+                Ok(())
+            },
+            &mut |line| {
+                eprintln!("{line}");
+                record.stderr.push_str(line);
+                record.stderr.push('\n');
+                Ok(())
+            },
+            false,
+        );
+
+        match result {
+            Ok(_) => {
+                if let Some(key) = &key {
+                    self.cache.put_artifact(
+                        key.as_bytes(),
+                        RECORD,
+                        &serde_json::to_vec(&record).map_err(anyhow::Error::from)?,
+                    )?;
+                    self.cache
+                        .finalize_unit(key.as_bytes(), &[RECORD.to_string()])?;
+                }
+                Ok(())
+            }
+            Err(e) => {
+                // libtest exits 101 on ordinary failures; anything else (a
+                // crash, a signal) is worth showing the process error for.
+                let code = e.downcast_ref::<ProcessError>().and_then(|p| p.code);
+                let msg = format!("test failed: {label}");
+                let err = if code == Some(101) {
+                    anyhow::anyhow!(msg)
+                } else {
+                    e.context(msg)
+                };
+                cargo::display_error(&err, &mut gctx.shell());
+                if !self.opts.no_fail_fast {
+                    return Err(CliError::code(code.unwrap_or(101)));
+                }
+                self.failed.push(label.to_string());
+                Ok(())
+            }
+        }
+    }
+
+    fn result_key(
+        &mut self,
+        unit: &Unit,
+        full_key: &CacheKey,
+        cmd: &ProcessBuilder,
+    ) -> Result<CacheKey> {
+        let root = unit.pkg.root();
+        if !self.inputs.contains_key(root) {
+            let files = runtime_files(root, &crate::cargo_interop::target_dir(self.ws))
+                .into_iter()
+                .map(|p| DynPath {
+                    path: self.roots.normalize(&p.path),
+                    ..p
+                })
+                .collect();
+            self.inputs.insert(root.to_path_buf(), files);
+        }
+        let set_by_cargo = cmd.get_envs();
+        let mut names: Vec<String> = set_by_cargo.keys().cloned().collect();
+        names.extend(HARNESS_ENV.iter().map(|n| n.to_string()));
+        names.extend(
+            std::env::vars_os()
+                .filter_map(|(n, _)| n.into_string().ok())
+                .filter(|n| n.starts_with("RUST_TEST_")),
+        );
+        names.extend(self.opts.test_env.iter().cloned());
+        names.sort();
+        names.dedup();
+
+        let inputs = DynamicInputs {
+            paths: self.inputs[root].clone(),
+            envs: names
+                .into_iter()
+                .map(|name| DynEnv {
+                    name,
+                    stored_value: None,
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let content = inputs.content_hash(&self.roots, |name| match set_by_cargo.get(name) {
+            Some(value) => value.as_ref().map(|v| v.to_string_lossy().into_owned()),
+            None => std::env::var(name).ok(),
+        })?;
+        // The program is the test binary, or the `runner` wrapping it.
+        let command: Vec<String> = std::iter::once(cmd.get_program())
+            .chain(cmd.get_args())
+            .map(|a| self.roots.normalize_str(&a.to_string_lossy()))
+            .collect();
+        Ok(hash::test_result_key(full_key, &command, &content))
+    }
+}
+
+/// Files under a package a test might read at runtime: everything but Rust
+/// sources (already in the unit key), build output (`target_dir`, or any
+/// `target/`), dot-directories and nested packages, which are their own.
+fn runtime_files(pkg_root: &Path, target_dir: &Path) -> Vec<DynPath> {
+    walkdir::WalkDir::new(pkg_root)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|e| {
+            e.depth() == 0
+                || !(e.file_type().is_dir()
+                    && (e.file_name() == "target"
+                        || e.file_name().to_string_lossy().starts_with('.')
+                        || e.path() == target_dir
+                        || e.path().join("Cargo.toml").is_file()))
+        })
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file() && e.path().extension().is_none_or(|ext| ext != "rs"))
+        .map(|e| DynPath {
+            path: e.into_path(),
+            stored_hash: [0; 32],
+        })
+        .collect()
+}
+
+/// `cargo test`'s description of a test binary, e.g.
+/// `unittests src/lib.rs (target/debug/deps/foo-0123abcd)`.
+fn exe_display(ws: &Workspace<'_>, output: &UnitOutput) -> String {
+    let UnitOutput { unit, path, .. } = output;
+    let src = unit.target.src_path().path().unwrap_or(path);
+    let src = src.strip_prefix(unit.pkg.root()).unwrap_or(src).display();
+    let exe = path.strip_prefix(ws.gctx().cwd()).unwrap_or(path).display();
+    match unit.target.kind() {
+        TargetKind::Test | TargetKind::Bench => format!("{src} ({exe})"),
+        _ => format!("unittests {src} ({exe})"),
+    }
+}
+
+/// The rustdoc invocation `cargo test` uses for one package's doctests.
+fn doctest_process(
+    ws: &Workspace<'_>,
+    compilation: &Compilation<'_>,
+    doctest: &Doctest,
+    test_args: &[&str],
+) -> Result<ProcessBuilder> {
+    let Doctest {
+        unit,
+        args,
+        unstable_opts,
+        linker,
+        script_metas,
+        env,
+    } = doctest;
+    let mut p = compilation.rustdoc_process(unit, script_metas.as_ref())?;
+    for (var, value) in env {
+        p.env(var, value);
+    }
+    let color = match ws.gctx().shell().color_choice() {
+        ColorChoice::Always => "always",
+        ColorChoice::Never => "never",
+        ColorChoice::CargoAuto => "auto",
+    };
+    p.arg("--color").arg(color);
+    p.arg("--crate-name").arg(unit.target.crate_name());
+    p.arg("--test");
+    cargo::util::add_path_args(ws, unit, &mut p);
+    p.arg("--test-run-directory").arg(unit.pkg.root());
+    if let CompileKind::Target(target) = unit.kind {
+        p.arg("--target").arg(target.rustc_target());
+    }
+    if let Some((runtool, runtool_args)) = compilation.target_runner(unit.kind) {
+        p.arg("--test-runtool").arg(runtool);
+        for arg in runtool_args {
+            p.arg("--test-runtool-arg").arg(arg);
+        }
+    }
+    if let Some(linker) = linker {
+        let mut joined = OsString::from("linker=");
+        joined.push(linker);
+        p.arg("-C").arg(joined);
+    }
+    if unit.profile.panic != PanicStrategy::Unwind {
+        p.arg("-C").arg(format!("panic={}", unit.profile.panic));
+    }
+    for dir in [
+        &compilation.deps_output[&unit.kind],
+        &compilation.deps_output[&CompileKind::Host],
+    ] {
+        let mut arg = OsString::from("dependency=");
+        arg.push(dir);
+        p.arg("-L").arg(arg);
+    }
+    for dir in &compilation.native_dirs {
+        p.arg("-L").arg(dir);
+    }
+    for arg in test_args {
+        p.arg("--test-args").arg(arg);
+    }
+    p.args(unit.pkg.manifest().lint_rustflags());
+    p.args(args);
+    if *unstable_opts {
+        p.arg("-Zunstable-options");
+    }
+    Ok(p)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runtime_files_skip_sources_build_output_and_nested_packages() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        for rel in [
+            "Cargo.toml",
+            "src/lib.rs",
+            "tests/data/case.json",
+            "target/debug/foo",
+            "out/debug/foo",
+            ".git/HEAD",
+            "crates/inner/Cargo.toml",
+            "crates/inner/data.txt",
+        ] {
+            let path = root.join(rel);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, rel).unwrap();
+        }
+        let files: Vec<_> = runtime_files(root, &root.join("out"))
+            .into_iter()
+            .map(|p| p.path.strip_prefix(root).unwrap().to_path_buf())
+            .collect();
+        assert_eq!(
+            files,
+            [Path::new("Cargo.toml"), Path::new("tests/data/case.json")]
+        );
+    }
+}
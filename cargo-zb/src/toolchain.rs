@@ -0,0 +1,139 @@
+//! The Rust toolchain a build runs with.
+//!
+//! cargo-zb links cargo as a library, so no rustup proxy picks the toolchain
+//! for it: cargo simply runs `rustc` from `PATH`, i.e. the proxy, which
+//! resolves the toolchain again for every invocation. [`activate`] settles it
+//! once, as rustup would for `cargo` run in the same directory: a
+//! `+toolchain` argument, then `RUSTUP_TOOLCHAIN`, a directory override, a
+//! `rust-toolchain(.toml)` file and finally the default. It exports the
+//! result as `RUSTUP_TOOLCHAIN`, so every rustc, rustdoc and build script of
+//! the build agrees. [`identity`] describes the resulting rustc for the
+//! cache keys.
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use cargo::util::Rustc;
+
+/// Remove a `+toolchain` argument, as the rustup proxy takes it, from the
+/// command line: before the `zb` subcommand (`cargo-zb +nightly zb`) or
+/// right after it (`cargo zb +nightly`).
+pub fn take_override(args: &mut Vec<OsString>) -> Option<String> {
+    let pos = (1..args.len().min(3)).find(|&i| {
+        args[i]
+            .to_str()
+            .is_some_and(|a| a.len() > 1 && a.starts_with('+'))
+    })?;
+    let arg = args.remove(pos);
+    Some(arg.to_string_lossy()[1..].to_string())
+}
+
+/// Resolve the toolchain and export it as `RUSTUP_TOOLCHAIN`. Without rustup
+/// this leaves the environment alone: cargo then uses whatever `rustc` is on
+/// `PATH`.
+pub fn activate(cli_override: Option<&str>) {
+    // SAFETY: called from `main` before any other thread exists.
+    unsafe {
+        if let Some(name) = cli_override {
+            std::env::set_var("RUSTUP_TOOLCHAIN", name);
+        }
+        // rustup resolves overrides and toolchain files relative to the
+        // current directory, which `main` hasn't changed yet.
+        match active_toolchain() {
+            Some(name) => std::env::set_var("RUSTUP_TOOLCHAIN", name),
+            None => tracing::debug!("rustup not available; using rustc from PATH"),
+        }
+    }
+}
+
+/// The compiler a build runs, for cache keys: `rustc -vV` plus what its
+/// sysroot holds of the compiler, `bin/rustc` and `lib/librustc_driver-*`.
+/// The version tells apart releases; the contents separate toolchains that
+/// report the same version, e.g. a custom-linked one and the release it was
+/// built from. Neither depends on where the toolchain lives or what it is
+/// called, so the same compiler picked as `stable` or `1.80.0`, or installed
+/// under another home, shares cache entries.
+pub fn identity(rustc: &Rustc) -> Result<String> {
+    let output = std::process::Command::new(&rustc.path)
+        .args(["--print", "sysroot"])
+        .output()
+        .with_context(|| format!("running {} --print sysroot", rustc.path.display()))?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "{} --print sysroot failed: {}",
+            rustc.path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let sysroot = String::from_utf8(output.stdout).context("rustc printed a non-UTF-8 sysroot")?;
+    let sysroot = Path::new(sysroot.trim());
+    let lib = sysroot.join("lib");
+    let mut files = vec![sysroot.join("bin").join("rustc")];
+    let mut drivers: Vec<PathBuf> = std::fs::read_dir(&lib)
+        .with_context(|| format!("reading {}", lib.display()))?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| {
+            p.file_name()
+                .is_some_and(|n| n.to_string_lossy().starts_with("librustc_driver-"))
+        })
+        .collect();
+    drivers.sort();
+    files.extend(drivers);
+    let mut hasher = blake3::Hasher::new();
+    for file in &files {
+        hasher.update(file.strip_prefix(sysroot).unwrap_or(file).to_string_lossy().as_bytes());
+        hasher.update(b"\0");
+        // Hashed through the stat cache: the driver is large.
+        hasher.update(&crate::cache::hash_path_current(file)?);
+    }
+    Ok(format!("{}sysroot: {}\n", rustc.verbose_version, hasher.finalize().to_hex()))
+}
+
+/// `rustup show active-toolchain`'s full toolchain name, e.g.
+/// `nightly-x86_64-unknown-linux-gnu` for `+nightly`.
+fn active_toolchain() -> Option<String> {
+    let output = std::process::Command::new("rustup")
+        .args(["show", "active-toolchain"])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())?;
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    Some(stdout.split_whitespace().next()?.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<OsString> {
+        list.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn take_override_finds_plus_toolchain_around_the_subcommand() {
+        let mut before = args(&["cargo-zb", "+nightly", "zb", "--release"]);
+        assert_eq!(take_override(&mut before).as_deref(), Some("nightly"));
+        assert_eq!(before, args(&["cargo-zb", "zb", "--release"]));
+
+        let mut after = args(&["cargo-zb", "zb", "+1.80.0", "test"]);
+        assert_eq!(take_override(&mut after).as_deref(), Some("1.80.0"));
+        assert_eq!(after, args(&["cargo-zb", "zb", "test"]));
+
+        let mut none = args(&["cargo-zb", "zb", "test", "+foo"]);
+        assert_eq!(take_override(&mut none), None);
+        assert_eq!(none.len(), 4);
+    }
+
+    #[test]
+    fn identity_is_the_version_and_compiler_not_its_location() {
+        let gctx = cargo::GlobalContext::default().unwrap();
+        let rustc = gctx.load_global_rustc(None).unwrap();
+        let id = identity(&rustc).unwrap();
+        assert!(id.starts_with(&rustc.verbose_version), "{id}");
+        let home = std::env::var("HOME").unwrap();
+        assert!(!id.contains(&home) && !id.contains("toolchains"), "{id}");
+        assert_eq!(identity(&rustc).unwrap(), id);
+    }
+}
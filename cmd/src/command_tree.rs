@@ -123,10 +123,8 @@ impl CommandTree {
                 if i >= argv.len() {
                     break ""
                 }
-                if argv0 == "sh" || argv0 == "bash" {
-                    if argv[i] == "-c" {
-                        break "";
-                    }
+                if (argv0 == "sh" || argv0 == "bash") && argv[i] == "-c" {
+                    break "";
                 }
                 if !argv[i].starts_with('-') {
                     break argv[i].split_whitespace().next().unwrap_or("")
@@ -68,11 +68,11 @@ impl TimeCommand {
                 deadline = Some(Instant::now() + Duration::from_secs(3));
             }
 
-            if let Some(deadline) = deadline {
-                if Instant::now() >= deadline {
-                    error!("Timeout reached after command exit, some processes may still be running");
-                    break;
-                }
+            if let Some(deadline) = deadline
+                && Instant::now() >= deadline
+            {
+                error!("Timeout reached after command exit, some processes may still be running");
+                break;
             }
             let (wait_result, rusage) = unsafe {
                 let mut status: libc::c_int = 0;